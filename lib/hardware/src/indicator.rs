@@ -0,0 +1,487 @@
+//! Status indicator, showing the state of the device on an RGB LED.
+//!
+//! The indicator follows the WiFi link, from boot through to connected, unless the application shows a status of its
+//! own. Provisioning, firmware updates & fatal errors of the kernel take precedence over both. It runs as a kernel
+//! service, rendering the colour & pattern of each status from a `Theme`.
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use inu_os::connectivity::Connectivity;
+use inu_os::crash;
+use inu_os::error::OsError;
+use inu_os::hal::{Clock, Platform, ThreadOptions};
+use inu_os::health;
+use inu_os::kernel::{Kernel, KernelState};
+use inu_os::supervisor::ServiceSpec;
+use inu_os::types::{LinkState, WifiState};
+
+use crate::ws2812::RGB8;
+
+const LOG_TGT: &str = "inu.indicator";
+
+/// Interval between rendered frames.
+const FRAME: Duration = Duration::from_millis(20);
+
+const OPTIONS: ThreadOptions = ThreadOptions {
+    priority: 2,
+    core: None,
+    stack_size: 3072,
+};
+
+/// State shown by the indicator.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Boot,
+    Connecting,
+    AcquiringIp,
+    Connected,
+    Provisioning,
+    Ota,
+    Error,
+}
+
+impl Status {
+    /// The status of the WiFi link. Until the link first comes up or fails, the device is booting.
+    pub fn of_wifi(state: &WifiState) -> Self {
+        match state.link {
+            LinkState::Disconnected if state.last_disconnect.is_none() => Status::Boot,
+            LinkState::Disconnected | LinkState::Connecting => Status::Connecting,
+            LinkState::AcquiringIp => Status::AcquiringIp,
+            LinkState::Connected(_) => Status::Connected,
+        }
+    }
+
+    /// The status of the kernel, unless it is running normally.
+    pub fn of_kernel(state: KernelState) -> Option<Self> {
+        match state {
+            KernelState::Running => None,
+            KernelState::Provisioning => Some(Status::Provisioning),
+            KernelState::Updating => Some(Status::Ota),
+            KernelState::Halted => Some(Status::Error),
+        }
+    }
+}
+
+/// How the brightness of the LED varies over time.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pattern {
+    Solid,
+    Blink {
+        on: Duration,
+        off: Duration,
+    },
+    /// Fade in & out again over the period.
+    Breathe {
+        period: Duration,
+    },
+}
+
+impl Pattern {
+    /// Brightness, 0 to 255, the given time after the pattern started.
+    pub fn level(&self, elapsed: Duration) -> u8 {
+        match *self {
+            Pattern::Solid => u8::MAX,
+            Pattern::Blink { on, off } => {
+                let period = (on + off).as_nanos();
+                match period == 0 || elapsed.as_nanos() % period < on.as_nanos() {
+                    true => u8::MAX,
+                    false => 0,
+                }
+            }
+            Pattern::Breathe { period } => {
+                let half = period.as_nanos() / 2;
+                if half == 0 {
+                    return u8::MAX;
+                }
+                let t = elapsed.as_nanos() % (half * 2);
+                let rising = if t < half { t } else { half * 2 - t };
+                (rising * u8::MAX as u128 / half) as u8
+            }
+        }
+    }
+}
+
+/// The colour & pattern of a status.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Appearance {
+    pub color: RGB8,
+    pub pattern: Pattern,
+}
+
+impl Appearance {
+    pub fn solid(color: RGB8) -> Self {
+        Self {
+            color,
+            pattern: Pattern::Solid,
+        }
+    }
+
+    pub fn blink(color: RGB8, on: Duration, off: Duration) -> Self {
+        Self {
+            color,
+            pattern: Pattern::Blink { on, off },
+        }
+    }
+
+    pub fn breathe(color: RGB8, period: Duration) -> Self {
+        Self {
+            color,
+            pattern: Pattern::Breathe { period },
+        }
+    }
+
+    /// Colour the given time after the status was first shown.
+    pub fn color_at(&self, elapsed: Duration) -> RGB8 {
+        let level = self.pattern.level(elapsed) as u16;
+        let scale = |c: u8| ((c as u16 * level + 127) / 255) as u8;
+        RGB8::new(
+            scale(self.color.r),
+            scale(self.color.g),
+            scale(self.color.b),
+        )
+    }
+}
+
+/// Appearance of every status.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Theme {
+    pub boot: Appearance,
+    pub connecting: Appearance,
+    pub acquiring_ip: Appearance,
+    pub connected: Appearance,
+    pub provisioning: Appearance,
+    pub ota: Appearance,
+    pub error: Appearance,
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        let ms = Duration::from_millis;
+
+        Self {
+            boot: Appearance::solid(RGB8::new(255, 255, 255)),
+            connecting: Appearance::blink(RGB8::new(0, 0, 255), ms(250), ms(250)),
+            acquiring_ip: Appearance::blink(RGB8::new(0, 255, 255), ms(100), ms(400)),
+            connected: Appearance::solid(RGB8::new(0, 255, 0)),
+            provisioning: Appearance::breathe(RGB8::new(255, 0, 255), ms(3000)),
+            ota: Appearance::blink(RGB8::new(255, 128, 0), ms(100), ms(100)),
+            error: Appearance::blink(RGB8::new(255, 0, 0), ms(500), ms(500)),
+        }
+    }
+}
+
+impl Theme {
+    /// Replace the appearance of a status.
+    pub fn with(mut self, status: Status, appearance: Appearance) -> Self {
+        *self.appearance_mut(status) = appearance;
+        self
+    }
+
+    pub fn appearance(&self, status: Status) -> Appearance {
+        match status {
+            Status::Boot => self.boot,
+            Status::Connecting => self.connecting,
+            Status::AcquiringIp => self.acquiring_ip,
+            Status::Connected => self.connected,
+            Status::Provisioning => self.provisioning,
+            Status::Ota => self.ota,
+            Status::Error => self.error,
+        }
+    }
+
+    fn appearance_mut(&mut self, status: Status) -> &mut Appearance {
+        match status {
+            Status::Boot => &mut self.boot,
+            Status::Connecting => &mut self.connecting,
+            Status::AcquiringIp => &mut self.acquiring_ip,
+            Status::Connected => &mut self.connected,
+            Status::Provisioning => &mut self.provisioning,
+            Status::Ota => &mut self.ota,
+            Status::Error => &mut self.error,
+        }
+    }
+}
+
+/// An LED able to show a single colour.
+pub trait StatusLed: Send {
+    fn show(&mut self, color: RGB8) -> Result<(), OsError>;
+}
+
+#[cfg(feature = "esp32s3")]
+impl StatusLed for crate::ws2812::Ws2812<'static> {
+    /// Show the colour on every pixel of the strip.
+    fn show(&mut self, color: RGB8) -> Result<(), OsError> {
+        self.fill(color);
+        crate::ws2812::Ws2812::show(self)
+    }
+}
+
+struct Shared {
+    theme: Theme,
+    /// Status shown by the application, in place of the WiFi status.
+    status: Option<Status>,
+    /// Link followed for the WiFi status, once the kernel has started.
+    connectivity: Option<Arc<Connectivity>>,
+}
+
+/// Handle to the indicator service.
+#[derive(Clone)]
+pub struct Indicator {
+    shared: Arc<Mutex<Shared>>,
+}
+
+impl Indicator {
+    /// Start the indicator service on its own kernel thread, showing the state of the device on the LED.
+    pub fn start(
+        kernel: &Kernel,
+        led: impl StatusLed + 'static,
+        theme: Theme,
+    ) -> Result<Self, OsError> {
+        let indicator = Self::new(theme);
+        indicator.attach(kernel);
+        let mut renderer = Renderer::new(led, indicator.clone(), kernel.platform().clock());
+
+        kernel.add_service(
+            ServiceSpec::new("led", OPTIONS),
+            Box::new(move || renderer.run()),
+        )?;
+
+        Ok(indicator)
+    }
+
+    /// Start the indicator on an unsupervised thread before the kernel boots, so that it shows the device booting or
+    /// being provisioned. Once the kernel has started, `attach` it to follow the WiFi link.
+    pub fn start_early(
+        platform: &dyn Platform,
+        led: impl StatusLed + 'static,
+        theme: Theme,
+    ) -> Result<Self, OsError> {
+        let indicator = Self::new(theme);
+        let mut renderer = Renderer::new(led, indicator.clone(), platform.clock());
+
+        platform.spawn(
+            OPTIONS,
+            Box::new(move || {
+                if let Err(e) = renderer.frames() {
+                    log::error!(target: LOG_TGT, "Indicator stopped: {}", crash::describe(&e));
+                }
+            }),
+        )?;
+
+        Ok(indicator)
+    }
+
+    fn new(theme: Theme) -> Self {
+        Self {
+            shared: Arc::new(Mutex::new(Shared {
+                theme,
+                status: None,
+                connectivity: None,
+            })),
+        }
+    }
+
+    /// Follow the WiFi link of the kernel.
+    pub fn attach(&self, kernel: &Kernel) {
+        self.shared.lock().unwrap().connectivity = Some(kernel.connectivity());
+    }
+
+    /// Show a status in place of the WiFi status, until cleared.
+    pub fn set(&self, status: Status) {
+        log::debug!(target: LOG_TGT, "Showing {:?}", status);
+        self.shared.lock().unwrap().status = Some(status);
+    }
+
+    /// Return to showing the WiFi status.
+    pub fn clear(&self) {
+        self.shared.lock().unwrap().status = None;
+    }
+
+    /// Status shown by the application, if any.
+    pub fn status(&self) -> Option<Status> {
+        self.shared.lock().unwrap().status
+    }
+
+    pub fn set_theme(&self, theme: Theme) {
+        self.shared.lock().unwrap().theme = theme;
+    }
+}
+
+/// Renders the indicator's status to the LED.
+struct Renderer<L> {
+    led: L,
+    indicator: Indicator,
+    kernel_state: fn() -> KernelState,
+    clock: Arc<dyn Clock>,
+    /// Status being shown & when it was first shown.
+    showing: Option<(Status, Duration)>,
+    /// Colour last sent to the LED.
+    color: Option<RGB8>,
+}
+
+impl<L: StatusLed> Renderer<L> {
+    fn new(led: L, indicator: Indicator, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            led,
+            indicator,
+            kernel_state: Kernel::state,
+            clock,
+            showing: None,
+            color: None,
+        }
+    }
+
+    fn run(&mut self) -> Result<(), OsError> {
+        health::watch()?;
+        // The LED may have been left on by a previous run
+        self.color = None;
+        self.frames()
+    }
+
+    fn frames(&mut self) -> Result<(), OsError> {
+        loop {
+            self.render()?;
+            health::sleep(FRAME);
+        }
+    }
+
+    /// Update the LED to the current frame, if its colour has changed.
+    fn render(&mut self) -> Result<(), OsError> {
+        let now = self.clock.now();
+        let (status, appearance) = {
+            let shared = self.indicator.shared.lock().unwrap();
+            let status = Status::of_kernel((self.kernel_state)())
+                .or(shared.status)
+                .unwrap_or_else(|| match &shared.connectivity {
+                    Some(c) => Status::of_wifi(&c.state()),
+                    None => Status::Boot,
+                });
+            (status, shared.theme.appearance(status))
+        };
+
+        // Patterns restart when the status changes, so a blink always begins lit
+        let since = match self.showing {
+            Some((s, since)) if s == status => since,
+            _ => {
+                self.showing = Some((status, now));
+                now
+            }
+        };
+
+        let color = appearance.color_at(now.saturating_sub(since));
+        if self.color != Some(color) {
+            self.led.show(color)?;
+            self.color = Some(color);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "host"))]
+mod tests {
+    use super::*;
+    use inu_os::hal::host::ManualClock;
+    use inu_os::types::DisconnectReason;
+
+    struct Recorder(Arc<Mutex<Vec<RGB8>>>);
+
+    impl StatusLed for Recorder {
+        fn show(&mut self, color: RGB8) -> Result<(), OsError> {
+            self.0.lock().unwrap().push(color);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn patterns_vary_brightness() {
+        let ms = Duration::from_millis;
+        let blink = Pattern::Blink {
+            on: ms(100),
+            off: ms(300),
+        };
+        let levels: Vec<_> = [0, 99, 100, 399, 400].map(|t| blink.level(ms(t))).into();
+        assert_eq!(levels, [255, 255, 0, 0, 255]);
+
+        let breathe = Pattern::Breathe { period: ms(1000) };
+        let levels: Vec<_> = [0, 250, 500, 750, 1000]
+            .map(|t| breathe.level(ms(t)))
+            .into();
+        assert_eq!(levels, [0, 127, 255, 127, 0]);
+
+        let appearance = Appearance::breathe(RGB8::new(255, 0, 128), ms(1000));
+        assert_eq!(appearance.color_at(ms(250)), RGB8::new(127, 0, 64));
+    }
+
+    #[test]
+    fn wifi_status_is_shown_unless_overridden() {
+        let connectivity = Arc::new(Connectivity::new());
+        let clock = Arc::new(ManualClock::new());
+        let shown = Arc::new(Mutex::new(vec![]));
+        let indicator = Indicator::new(Theme::default());
+        let mut renderer = Renderer::new(Recorder(shown.clone()), indicator.clone(), clock.clone());
+        renderer.kernel_state = || KernelState::Running;
+        let theme = Theme::default();
+        let (blue, red) = (theme.connecting.color, theme.error.color);
+
+        // Solid colours are only sent once
+        renderer.render().unwrap();
+        renderer.render().unwrap();
+        assert_eq!(*shown.lock().unwrap(), [theme.boot.color]);
+
+        indicator.shared.lock().unwrap().connectivity = Some(connectivity.clone());
+
+        // Blinking restarts lit when the status changes
+        connectivity.update(|s| s.link = LinkState::Connecting);
+        renderer.render().unwrap();
+        clock.advance(Duration::from_millis(300));
+        renderer.render().unwrap();
+        assert_eq!(shown.lock().unwrap()[1..], [blue, RGB8::default()]);
+
+        indicator.set(Status::Error);
+        renderer.render().unwrap();
+        assert_eq!(shown.lock().unwrap().last(), Some(&red));
+
+        indicator.clear();
+        connectivity.update(|s| {
+            s.link = LinkState::Disconnected;
+            s.last_disconnect = Some(DisconnectReason::JoinFailed);
+        });
+        renderer.render().unwrap();
+        assert_eq!(shown.lock().unwrap().last(), Some(&blue));
+    }
+
+    #[test]
+    fn kernel_state_takes_precedence() {
+        static STATE: Mutex<KernelState> = Mutex::new(KernelState::Provisioning);
+
+        let clock = Arc::new(ManualClock::new());
+        let shown = Arc::new(Mutex::new(vec![]));
+        let indicator = Indicator::new(Theme::default());
+        let mut renderer = Renderer::new(Recorder(shown.clone()), indicator.clone(), clock.clone());
+        renderer.kernel_state = || *STATE.lock().unwrap();
+        let theme = Theme::default();
+
+        // Provisioning happens before the kernel, so without a link to follow
+        renderer.render().unwrap();
+        clock.advance(Duration::from_millis(1500));
+        renderer.render().unwrap();
+        assert_eq!(
+            shown.lock().unwrap().last(),
+            Some(&theme.provisioning.color)
+        );
+
+        indicator.set(Status::Connected);
+        *STATE.lock().unwrap() = KernelState::Updating;
+        renderer.render().unwrap();
+        assert_eq!(shown.lock().unwrap().last(), Some(&theme.ota.color));
+
+        *STATE.lock().unwrap() = KernelState::Halted;
+        renderer.render().unwrap();
+        assert_eq!(shown.lock().unwrap().last(), Some(&theme.error.color));
+
+        *STATE.lock().unwrap() = KernelState::Running;
+        renderer.render().unwrap();
+        assert_eq!(shown.lock().unwrap().last(), Some(&theme.connected.color));
+    }
+}
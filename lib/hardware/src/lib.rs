@@ -1,2 +1,3 @@
-//pub mod ws2812;
+pub mod indicator;
 pub mod switch;
+pub mod ws2812;
@@ -1,23 +1,51 @@
 //! Switch module for handling input from a button, NPN sensor, etc.
 
 use core::cell::{Cell, RefCell};
-use esp_idf_svc::hal::gpio::Level;
+use inu_os::crash;
+use inu_os::error::{OsError, PinError};
+use inu_os::hal::clock::SystemClock;
+use inu_os::hal::gpio::Level;
+use inu_os::hal::{Clock, ThreadOptions, WakeCause, WakeLevel, WakeSource};
+use inu_os::health;
+use inu_os::inu::InuService;
+use inu_os::kernel::Kernel;
 use inu_os::pin_mgr::GpioInput;
-use std::time::{Duration, SystemTime};
+use inu_os::supervisor::ServiceSpec;
+use std::sync::Arc;
+use std::time::Duration;
 
-/// Function signature for a callback executed when the switch state changes. The argument is the new state.
-pub type OnToggle = fn(Level) -> ();
+const LOG_TGT: &str = "inu.switch";
 
-/// A switch that can be polled for state changes.
+/// A change in the state of a switch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SwitchEvent {
+    /// GPIO pin of the switch, identifying it.
+    pub id: u8,
+    pub level: Level,
+    pub previous: Level,
+    /// How long the switch was held in the previous state.
+    pub held: Duration,
+}
+
+/// Callback executed when the switch state changes. It may capture state, and runs on the switch's task if listening.
+pub type OnToggle = Box<dyn FnMut(SwitchEvent) + Send>;
+
+/// A switch that can be polled for state changes, or left to a task of its own woken by the pin's interrupt.
 ///
-/// Using the polling is preferable to using an interrupt or manual testing as it enables the use of DelayOptions.
-/// These are important to filtering out electrical interference.
+/// Either is preferable to reading the pin directly, as they apply DelayOptions. These are important to filtering out
+/// electrical interference.
 pub struct InuSwitch<'s> {
     input: GpioInput<'s>,
     state: Cell<Level>,
-    toggle_cb: Option<OnToggle>,
+    toggle_cb: RefCell<Option<OnToggle>>,
+    trigger: Option<(InuService, u16)>,
     delay_ops: DelayOptions,
-    timer: RefCell<Option<SystemTime>>,
+    clock: Arc<dyn Clock>,
+    timer: RefCell<Option<Duration>>,
+    /// When the state last changed.
+    since: Cell<Duration>,
+    /// The switch woke the device, so was pressed before it could be polled.
+    woke: Cell<bool>,
 }
 
 impl<'s> InuSwitch<'s> {
@@ -29,18 +57,32 @@ impl<'s> InuSwitch<'s> {
     /// Switch has a default transition delay of 50ms if not specified.
     pub fn new(input: GpioInput<'s>) -> Self {
         let state = input.get_level();
+        let clock: Arc<dyn Clock> = Arc::new(SystemClock::new());
 
         Self {
             input,
             state: Cell::new(state),
-            toggle_cb: None,
+            toggle_cb: RefCell::new(None),
+            trigger: None,
             delay_ops: DelayOptions::default(),
-            timer: RefCell::new(Some(SystemTime::now())),
+            timer: RefCell::new(Some(clock.now())),
+            since: Cell::new(clock.now()),
+            clock,
+            woke: Cell::new(false),
         }
     }
 
-    pub fn with_callback(mut self, cb: OnToggle) -> Self {
-        self.toggle_cb = Some(cb);
+    pub fn with_callback<F>(mut self, cb: F) -> Self
+    where
+        F: FnMut(SwitchEvent) + Send + 'static,
+    {
+        self.set_callback(cb);
+        self
+    }
+
+    /// Publish an Inu trigger with the given code each time the switch becomes active (high).
+    pub fn with_trigger(mut self, inu: InuService, code: u16) -> Self {
+        self.trigger = Some((inu, code));
         self
     }
 
@@ -49,8 +91,39 @@ impl<'s> InuSwitch<'s> {
         self
     }
 
-    pub fn set_callback(&mut self, c: OnToggle) {
-        self.toggle_cb = Some(c);
+    /// Use the given clock for transition delays, typically the kernel's platform clock.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        *self.timer.get_mut() = Some(clock.now());
+        self.since.set(clock.now());
+        self.clock = clock;
+        self
+    }
+
+    /// Acknowledge the press that woke the device, if it was this switch. The switch is activated on the first poll,
+    /// even if it has since been released.
+    pub fn with_wake_cause(self, cause: Option<&WakeCause>) -> Self {
+        if let Some(WakeCause::Gpio(pins)) = cause {
+            if pins.contains(&self.input.pin()) {
+                self.state.set(Level::Low);
+                self.woke.set(true);
+            }
+        }
+        self
+    }
+
+    /// Wake the device from deep sleep when the switch becomes active (high). The switch must be on an RTC GPIO.
+    pub fn wake_source(&self) -> WakeSource {
+        WakeSource::Gpio {
+            pins: vec![self.input.pin()],
+            level: WakeLevel::AnyHigh,
+        }
+    }
+
+    pub fn set_callback<F>(&mut self, c: F)
+    where
+        F: FnMut(SwitchEvent) + Send + 'static,
+    {
+        *self.toggle_cb.get_mut() = Some(Box::new(c));
     }
 
     pub fn set_delay_options(&mut self, delay_options: DelayOptions) {
@@ -67,6 +140,10 @@ impl<'s> InuSwitch<'s> {
 
     /// Poll the switch state and call the callback if the state has changed.
     pub fn poll(&self) {
+        if self.woke.take() {
+            self.toggle(Level::High);
+        }
+
         let is_active = self.is_active();
         let mut timer = self.timer.borrow_mut();
 
@@ -82,32 +159,106 @@ impl<'s> InuSwitch<'s> {
             Some(min_transition_time) => {
                 if let Some(ref t) = *timer {
                     // Timer is running, check if it's time to switch
-                    if t.elapsed().unwrap() >= min_transition_time {
+                    if self.clock.now().saturating_sub(*t) >= min_transition_time {
                         *timer = None;
-                        self.state.set(is_active);
-
-                        if let Some(cb) = self.toggle_cb {
-                            cb(self.state.get());
-                        }
+                        self.toggle(is_active);
                     }
                 } else {
                     // Start the timer, but take no action yet
-                    *timer = Some(SystemTime::now());
+                    *timer = Some(self.clock.now());
                 }
             }
 
             // If no delay is set, switch immediately.
-            None => {
-                self.state.set(is_active);
+            None => self.toggle(is_active),
+        }
+    }
 
-                if let Some(cb) = self.toggle_cb {
-                    cb(self.state.get());
-                }
+    /// Poll the switch, then block until it next needs polling: when its level changes or, while a transition is
+    /// pending, when the transition delay has passed.
+    fn wait(&mut self) -> Result<(), PinError> {
+        self.poll();
+        let state = self.state.get();
+
+        // A level differing from the state after polling is waiting out the delay, so watch for it bouncing back
+        let pending = self.is_active() != state;
+        let timeout = match (*self.timer.borrow(), self.delay_ops.min_transition_time) {
+            (Some(started), Some(delay)) if pending => {
+                (started + delay).saturating_sub(self.clock.now())
+            }
+            _ => health::FEED_INTERVAL,
+        };
+
+        let target = if pending { state } else { !state };
+        let reached = self.input.wait_for_level(target, timeout)?;
+
+        // Without a delay every transition counts, including a pulse that ended before it could be read
+        if reached
+            && !pending
+            && self.delay_ops.min_transition_time.is_none()
+            && self.is_active() == state
+        {
+            self.toggle(!state);
+            self.toggle(state);
+        }
+        Ok(())
+    }
+
+    fn run(&mut self) -> Result<(), OsError> {
+        if let Err(e) = health::watch() {
+            log::warn!(target: LOG_TGT, "Switch task unwatched: {}", crash::describe(&e));
+        }
+
+        loop {
+            self.wait()?;
+            health::feed();
+        }
+    }
+
+    /// Acknowledge a state change, notifying the callback & publishing the trigger.
+    fn toggle(&self, level: Level) {
+        let now = self.clock.now();
+        let event = SwitchEvent {
+            id: self.input.pin(),
+            level,
+            previous: self.state.replace(level),
+            held: now.saturating_sub(self.since.replace(now)),
+        };
+
+        if let Some(cb) = self.toggle_cb.borrow_mut().as_mut() {
+            cb(event);
+        }
+
+        if let (Some((inu, code)), Level::High) = (&self.trigger, level) {
+            if let Err(e) = inu.trigger(*code) {
+                log::warn!(target: LOG_TGT, "Failed to publish trigger {}: {:?}", code, e);
             }
         }
     }
 }
 
+impl InuSwitch<'static> {
+    /// Watch the switch from its own kernel task, woken by the pin's interrupt, rather than calling `poll` from the
+    /// main loop. The switch is moved to the task, where the callback runs.
+    ///
+    /// DelayOptions apply as when polled. The interrupt wakes the CPU from light sleep, so switches keep working with
+    /// the `light_sleep` setting on.
+    pub fn listen(mut self, kernel: &Kernel) -> Result<(), OsError> {
+        let name = format!("switch{}", self.input.pin());
+        kernel.add_service(
+            ServiceSpec::new(
+                &name,
+                ThreadOptions {
+                    priority: 6,
+                    core: None,
+                    stack_size: 3072,
+                },
+            ),
+            Box::new(move || self.run()),
+        )
+    }
+}
+
 pub struct DelayOptions {
     /// The time that must lapse before acknowledging a state change.
     pub min_transition_time: Option<Duration>,
@@ -134,3 +285,204 @@ impl Default for DelayOptions {
         }
     }
 }
+
+#[cfg(all(test, feature = "host"))]
+mod tests {
+    use super::*;
+    use inu_os::hal::gpio::Pull;
+    use inu_os::hal::host::{ManualClock, SimGpio};
+    use inu_os::hal::Gpio;
+    use inu_os::inu::Identity;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static TOGGLES: AtomicUsize = AtomicUsize::new(0);
+    static WAKES: AtomicUsize = AtomicUsize::new(0);
+    static PRESSES: AtomicUsize = AtomicUsize::new(0);
+    static PULSES: AtomicUsize = AtomicUsize::new(0);
+
+    fn wait_for(count: &AtomicUsize, n: usize) -> bool {
+        let start = std::time::Instant::now();
+        while start.elapsed() < Duration::from_secs(5) {
+            if count.load(Ordering::SeqCst) == n {
+                return true;
+            }
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        false
+    }
+
+    #[test]
+    fn transitions_are_debounced() {
+        let gpio = SimGpio::new();
+        let clock = Arc::new(ManualClock::new());
+        let sw = InuSwitch::new(gpio.input(9, Pull::Down).unwrap())
+            .with_callback(|_| {
+                TOGGLES.fetch_add(1, Ordering::SeqCst);
+            })
+            .with_delay(DelayOptions::tnx_ms(10))
+            .with_clock(clock.clone());
+
+        // A short pulse is filtered out
+        gpio.drive(9, Level::High);
+        sw.poll();
+        clock.advance(Duration::from_millis(5));
+        gpio.drive(9, Level::Low);
+        sw.poll();
+        assert_eq!(TOGGLES.load(Ordering::SeqCst), 0);
+
+        // A held state is acknowledged once the delay has passed
+        gpio.drive(9, Level::High);
+        sw.poll();
+        clock.advance(Duration::from_millis(10));
+        sw.poll();
+        sw.poll();
+        assert_eq!(TOGGLES.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn callbacks_capture_state_and_see_the_event() {
+        let gpio = SimGpio::new();
+        let clock = Arc::new(ManualClock::new());
+        let events = Arc::new(std::sync::Mutex::new(vec![]));
+        let seen = events.clone();
+        let sw = InuSwitch::new(gpio.input(7, Pull::Down).unwrap())
+            .with_callback(move |e| seen.lock().unwrap().push(e))
+            .with_delay(DelayOptions::none())
+            .with_clock(clock.clone());
+
+        clock.advance(Duration::from_secs(3));
+        gpio.drive(7, Level::High);
+        sw.poll();
+        clock.advance(Duration::from_millis(250));
+        gpio.drive(7, Level::Low);
+        sw.poll();
+
+        assert_eq!(
+            *events.lock().unwrap(),
+            [
+                SwitchEvent {
+                    id: 7,
+                    level: Level::High,
+                    previous: Level::Low,
+                    held: Duration::from_secs(3),
+                },
+                SwitchEvent {
+                    id: 7,
+                    level: Level::Low,
+                    previous: Level::High,
+                    held: Duration::from_millis(250),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn activation_publishes_trigger() {
+        let gpio = SimGpio::new();
+        let clock = Arc::new(ManualClock::new());
+        let inu = InuService::new(
+            Identity {
+                device_id: "inu.button".into(),
+                edition: "Ferric".into(),
+                build: 1,
+            },
+            clock.clone(),
+        );
+
+        let triggers = Arc::new(AtomicUsize::new(0));
+        let count = triggers.clone();
+        inu.on_trigger(4, move |t| {
+            assert_eq!(t.device_id, "inu.button");
+            count.fetch_add(1, Ordering::SeqCst);
+        });
+
+        let sw = InuSwitch::new(gpio.input(9, Pull::Down).unwrap())
+            .with_trigger(inu, 4)
+            .with_delay(DelayOptions::none())
+            .with_clock(clock);
+
+        // Only activation fires, releasing the switch does not
+        for level in [Level::High, Level::Low, Level::High] {
+            gpio.drive(9, level);
+            sw.poll();
+        }
+        assert_eq!(triggers.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn press_that_woke_the_device_is_acknowledged() {
+        let gpio = SimGpio::new();
+        let sw = InuSwitch::new(gpio.input(3, Pull::Down).unwrap())
+            .with_callback(|e| {
+                if e.level == Level::High {
+                    WAKES.fetch_add(1, Ordering::SeqCst);
+                }
+            })
+            .with_delay(DelayOptions::none())
+            .with_wake_cause(Some(&WakeCause::Gpio(vec![3])));
+        assert_eq!(
+            sw.wake_source(),
+            WakeSource::Gpio {
+                pins: vec![3],
+                level: WakeLevel::AnyHigh
+            }
+        );
+
+        // Released by the time the device has booted, the press still counts once
+        sw.poll();
+        sw.poll();
+        assert_eq!(WAKES.load(Ordering::SeqCst), 1);
+
+        let other = InuSwitch::new(gpio.input(4, Pull::Down).unwrap())
+            .with_wake_cause(Some(&WakeCause::Gpio(vec![3])));
+        assert!(!other.woke.get());
+    }
+
+    #[test]
+    fn interrupt_mode_applies_delay_options() {
+        let gpio = SimGpio::new();
+        let mut sw = InuSwitch::new(gpio.input(5, Pull::Down).unwrap())
+            .with_callback(|e| {
+                if e.level == Level::High {
+                    PRESSES.fetch_add(1, Ordering::SeqCst);
+                }
+            })
+            .with_delay(DelayOptions::tnx_ms(20));
+        std::thread::spawn(move || loop {
+            sw.wait().unwrap();
+        });
+        std::thread::sleep(Duration::from_millis(20));
+
+        // Released before the delay passed, so filtered out
+        gpio.drive(5, Level::High);
+        std::thread::sleep(Duration::from_millis(2));
+        gpio.drive(5, Level::Low);
+        std::thread::sleep(Duration::from_millis(50));
+        assert_eq!(PRESSES.load(Ordering::SeqCst), 0);
+
+        // Held, so acknowledged without anything polling
+        gpio.drive(5, Level::High);
+        assert!(wait_for(&PRESSES, 1));
+    }
+
+    #[test]
+    fn interrupt_mode_catches_short_pulses() {
+        let gpio = SimGpio::new();
+        let mut sw = InuSwitch::new(gpio.input(6, Pull::Down).unwrap())
+            .with_callback(|_| {
+                PULSES.fetch_add(1, Ordering::SeqCst);
+            })
+            .with_delay(DelayOptions::none());
+        std::thread::spawn(move || loop {
+            sw.wait().unwrap();
+        });
+        std::thread::sleep(Duration::from_millis(20));
+
+        // Both edges are acknowledged, whether or not the task ran before the pulse ended
+        gpio.drive(6, Level::High);
+        gpio.drive(6, Level::Low);
+        assert!(wait_for(&PULSES, 2));
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(PULSES.load(Ordering::SeqCst), 2);
+    }
+}
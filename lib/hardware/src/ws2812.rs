@@ -1,52 +1,261 @@
-use core::time::Duration;
-use esp_idf_svc::hal::{
-    gpio::OutputPin,
-    peripheral::Peripheral,
-    rmt::{config::TransmitConfig, FixedLengthSignal, PinState, Pulse, RmtChannel, TxRmtDriver},
-};
+//! WS2812 addressable RGB LED driver.
+//!
+//! Pixel colours are corrected & ordered by an `Encoder`, then each bit of the result is sent as a high & low pulse
+//! by the RMT peripheral. The encoding is independent of the hardware, so can be tested on the host.
+
+use std::sync::OnceLock;
 
-/*
-use inu_os::error;
 pub use rgb::RGB8;
 
+#[cfg(feature = "esp32s3")]
+pub use driver::Ws2812;
+
+/// Gamma of the LEDs' perceived brightness curve.
+const GAMMA: f32 = 2.8;
+
+/// Order the LEDs expect the colour bytes of a pixel in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ColorOrder {
+    /// Green, red, blue; most WS2812 & WS2812B LEDs.
+    #[default]
+    Grb,
+    Rgb,
+}
+
+/// Duration of the pulses encoding a bit, in nanoseconds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Timing {
+    pub t0h: u32,
+    pub t0l: u32,
+    pub t1h: u32,
+    pub t1l: u32,
+}
+
+/// WS2812B timing.
+pub const WS2812_TIMING: Timing = Timing {
+    t0h: 350,
+    t0l: 800,
+    t1h: 700,
+    t1l: 600,
+};
+
+/// Converts pixel colours to the bytes sent to the LEDs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Encoder {
+    pub order: ColorOrder,
+    /// Scale applied to every channel, 255 being full brightness.
+    pub brightness: u8,
+    /// Correct for the non-linear brightness of the LEDs.
+    pub gamma: bool,
+}
 
-pub struct WS2812RMT<'a> {
-    tx_rtm_driver: TxRmtDriver<'a>,
+impl Default for Encoder {
+    fn default() -> Self {
+        Self {
+            order: ColorOrder::default(),
+            brightness: u8::MAX,
+            gamma: true,
+        }
+    }
 }
 
-impl<'d> WS2812RMT<'d> {
-    // Rust ESP Board gpio2,  ESP32-C3-DevKitC-02 gpio8
-    pub fn new(
-        led: impl Peripheral<P = impl OutputPin> + 'd,
-        channel: impl Peripheral<P = impl RmtChannel> + 'd,
-    ) -> Result<Self, error::InuError> {
-        let config = TransmitConfig::new().clock_divider(2);
-        let tx = TxRmtDriver::new(channel, led, &config)?;
-        Ok(Self { tx_rtm_driver: tx })
+impl Encoder {
+    /// The bytes of a pixel, in the order sent.
+    pub fn pixel(&self, color: RGB8) -> [u8; 3] {
+        let channel = |c: u8| {
+            let c = if self.gamma { gamma(c) } else { c };
+            ((c as u16 * self.brightness as u16 + 127) / 255) as u8
+        };
+        let (r, g, b) = (channel(color.r), channel(color.g), channel(color.b));
+
+        match self.order {
+            ColorOrder::Grb => [g, r, b],
+            ColorOrder::Rgb => [r, g, b],
+        }
     }
 
-    pub fn set_pixel(&mut self, rgb: RGB8) -> Result<()> {
-        let color: u32 = ((rgb.g as u32) << 16) | ((rgb.r as u32) << 8) | rgb.b as u32;
-        let ticks_hz = self.tx_rtm_driver.counter_clock()?;
-        let t0h = Pulse::new_with_duration(ticks_hz, PinState::High, &ns(350))?;
-        let t0l = Pulse::new_with_duration(ticks_hz, PinState::Low, &ns(800))?;
-        let t1h = Pulse::new_with_duration(ticks_hz, PinState::High, &ns(700))?;
-        let t1l = Pulse::new_with_duration(ticks_hz, PinState::Low, &ns(600))?;
-        let mut signal = FixedLengthSignal::<24>::new();
-        for i in (0..24).rev() {
-            let p = 2_u32.pow(i);
-            let bit = p & color != 0;
-            let (high_pulse, low_pulse) = if bit { (t1h, t1l) } else { (t0h, t0l) };
-            signal.set(23 - i as usize, &(high_pulse, low_pulse))?;
-        }
-        self.tx_rtm_driver.start_blocking(&signal)?;
-
-        Ok(())
+    /// The bytes of a strip of pixels, first pixel first.
+    pub fn encode(&self, pixels: &[RGB8]) -> Vec<u8> {
+        pixels.iter().flat_map(|p| self.pixel(*p)).collect()
     }
 }
 
-fn ns(nanos: u64) -> Duration {
-    Duration::from_nanos(nanos)
+/// Gamma correct a channel value.
+pub fn gamma(c: u8) -> u8 {
+    static TABLE: OnceLock<[u8; 256]> = OnceLock::new();
+
+    TABLE.get_or_init(|| {
+        let mut table = [0; 256];
+        for (i, v) in table.iter_mut().enumerate() {
+            *v = ((i as f32 / 255.0).powf(GAMMA) * 255.0 + 0.5) as u8;
+        }
+        table
+    })[c as usize]
+}
+
+/// The pulses sending the data, as high & low durations in ticks of the given clock, most significant bit first.
+pub fn pulses(data: &[u8], timing: &Timing, ticks_hz: u32) -> Vec<(u16, u16)> {
+    let ticks = |ns: u32| ((ns as u64 * ticks_hz as u64 + 500_000_000) / 1_000_000_000) as u16;
+    let zero = (ticks(timing.t0h), ticks(timing.t0l));
+    let one = (ticks(timing.t1h), ticks(timing.t1l));
+
+    data.iter()
+        .flat_map(|byte| (0..8).rev().map(move |bit| byte & 1 << bit != 0))
+        .map(|bit| if bit { one } else { zero })
+        .collect()
 }
 
- */
\ No newline at end of file
+#[cfg(feature = "esp32s3")]
+mod driver {
+    use esp_idf_svc::hal::peripheral::Peripheral;
+    use esp_idf_svc::hal::rmt::config::TransmitConfig;
+    use esp_idf_svc::hal::rmt::{
+        PinState, Pulse, PulseTicks, RmtChannel, TxRmtDriver, VariableLengthSignal,
+    };
+    use inu_os::error::OsError;
+    use inu_os::pin_mgr::PinManager;
+
+    use super::{pulses, ColorOrder, Encoder, RGB8, WS2812_TIMING};
+
+    /// A strip of WS2812 LEDs, driven by an RMT channel.
+    ///
+    /// Colours are set on the pixel buffer, then sent to the strip with `show`.
+    pub struct Ws2812<'d> {
+        tx: TxRmtDriver<'d>,
+        encoder: Encoder,
+        pixels: Vec<RGB8>,
+    }
+
+    impl<'d> Ws2812<'d> {
+        /// Drive a strip of `len` pixels on the given pin, claimed from the pin manager.
+        pub fn new(
+            pin_mgr: &PinManager,
+            pin: u8,
+            channel: impl Peripheral<P = impl RmtChannel> + 'd,
+            len: usize,
+        ) -> Result<Self, OsError> {
+            let config = TransmitConfig::new().clock_divider(2);
+            let tx = TxRmtDriver::new(channel, pin_mgr.get_pin(pin)?, &config)?;
+
+            Ok(Self {
+                tx,
+                encoder: Encoder::default(),
+                pixels: vec![RGB8::default(); len],
+            })
+        }
+
+        pub fn with_order(mut self, order: ColorOrder) -> Self {
+            self.encoder.order = order;
+            self
+        }
+
+        pub fn with_brightness(mut self, brightness: u8) -> Self {
+            self.encoder.brightness = brightness;
+            self
+        }
+
+        pub fn with_gamma(mut self, gamma: bool) -> Self {
+            self.encoder.gamma = gamma;
+            self
+        }
+
+        pub fn set_brightness(&mut self, brightness: u8) {
+            self.encoder.brightness = brightness;
+        }
+
+        pub fn len(&self) -> usize {
+            self.pixels.len()
+        }
+
+        pub fn is_empty(&self) -> bool {
+            self.pixels.is_empty()
+        }
+
+        /// The pixel buffer, shown on the next call to `show`.
+        pub fn pixels_mut(&mut self) -> &mut [RGB8] {
+            &mut self.pixels
+        }
+
+        pub fn set_pixel(&mut self, index: usize, color: RGB8) -> Result<(), OsError> {
+            let len = self.pixels.len();
+            let pixel = self.pixels.get_mut(index).ok_or_else(|| {
+                OsError::Generic(format!("Pixel {} is beyond the strip of {}", index, len))
+            })?;
+            *pixel = color;
+            Ok(())
+        }
+
+        pub fn fill(&mut self, color: RGB8) {
+            self.pixels.fill(color);
+        }
+
+        pub fn clear(&mut self) {
+            self.fill(RGB8::default());
+        }
+
+        /// Send the pixel buffer to the strip, blocking until sent.
+        pub fn show(&mut self) -> Result<(), OsError> {
+            let data = self.encoder.encode(&self.pixels);
+            let ticks_hz = self.tx.counter_clock()?;
+
+            let mut signal = VariableLengthSignal::with_capacity(data.len() * 16);
+            for (high, low) in pulses(&data, &WS2812_TIMING, ticks_hz.into()) {
+                signal.push(&[
+                    Pulse::new(PinState::High, PulseTicks::new(high)?),
+                    Pulse::new(PinState::Low, PulseTicks::new(low)?),
+                ])?;
+            }
+
+            self.tx.start_blocking(&signal)?;
+            Ok(())
+        }
+    }
+}
+
+#[cfg(all(test, feature = "host"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pixels_are_corrected_and_ordered() {
+        let color = RGB8::new(255, 128, 0);
+
+        let raw = Encoder {
+            gamma: false,
+            ..Encoder::default()
+        };
+        assert_eq!(raw.pixel(color), [128, 255, 0]);
+        assert_eq!(
+            Encoder {
+                order: ColorOrder::Rgb,
+                ..raw
+            }
+            .pixel(color),
+            [255, 128, 0]
+        );
+        assert_eq!(
+            Encoder {
+                brightness: 64,
+                ..raw
+            }
+            .pixel(color),
+            [32, 64, 0]
+        );
+
+        assert_eq!((gamma(0), gamma(128), gamma(255)), (0, 37, 255));
+        assert_eq!(Encoder::default().pixel(color), [37, 255, 0]);
+        assert_eq!(
+            raw.encode(&[color, RGB8::new(1, 2, 3)]),
+            [128, 255, 0, 2, 1, 3]
+        );
+    }
+
+    #[test]
+    fn bits_are_sent_most_significant_first() {
+        // 40 MHz, the 80 MHz APB clock divided by 2
+        let pulses = pulses(&[0b1010_0000], &WS2812_TIMING, 40_000_000);
+        let (zero, one) = ((14, 32), (28, 24));
+
+        assert_eq!(pulses, [one, zero, one, zero, zero, zero, zero, zero]);
+    }
+}
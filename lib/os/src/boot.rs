@@ -0,0 +1,165 @@
+//! Boot failure handling.
+//!
+//! Every boot increments a counter in flash, which the kernel clears once it has started. The counter therefore holds
+//! the number of consecutive boots that failed to start the kernel, letting the `FailurePolicy` back off or fall back
+//! to safe mode when the device is stuck in a restart loop.
+
+use std::time::Duration;
+
+use crate::error::FlashError;
+use crate::flash::{Flash, Readable, Recovery, Writable};
+use crate::hal::Platform;
+
+const BOOT_PARTITION: &str = "cfg";
+const BOOT_NAMESPACE: &str = "boot";
+const KEY_FAILURES: &str = "failures";
+
+/// What the kernel does when it fails to start.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FailurePolicy {
+    /// Log the error & halt, so that the device can be inspected over serial.
+    Halt,
+    /// Restart after a delay, doubling with each consecutive failed boot from `min_delay` up to `max_delay`.
+    Restart {
+        min_delay: Duration,
+        max_delay: Duration,
+        /// Boot into safe mode once this many consecutive boots have failed.
+        safe_mode_after: Option<u32>,
+    },
+    /// Restart into safe mode, hosting the provisioning portal so that the settings can be corrected.
+    SafeMode,
+}
+
+impl Default for FailurePolicy {
+    fn default() -> Self {
+        FailurePolicy::Restart {
+            min_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(300),
+            safe_mode_after: Some(5),
+        }
+    }
+}
+
+/// Action taken after a failed boot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Recover {
+    Halt,
+    /// Restart after the given delay.
+    Restart(Duration),
+}
+
+impl FailurePolicy {
+    /// Whether to skip the normal boot & enter safe mode, given the number of consecutive failed boots before this
+    /// one.
+    pub fn safe_mode(&self, failures: u32) -> bool {
+        match self {
+            FailurePolicy::Halt => false,
+            FailurePolicy::Restart {
+                safe_mode_after, ..
+            } => safe_mode_after.is_some_and(|n| failures >= n),
+            FailurePolicy::SafeMode => failures > 0,
+        }
+    }
+
+    /// How to recover after a boot failed, given the number of consecutive failed boots including this one.
+    pub fn recover(&self, failures: u32) -> Recover {
+        match *self {
+            FailurePolicy::Halt => Recover::Halt,
+            FailurePolicy::Restart {
+                min_delay,
+                max_delay,
+                ..
+            } => {
+                let doublings = failures.saturating_sub(1).min(31);
+                Recover::Restart(min_delay.saturating_mul(1 << doublings).min(max_delay))
+            }
+            FailurePolicy::SafeMode => Recover::Restart(Duration::ZERO),
+        }
+    }
+}
+
+/// Count of consecutive failed boots, persisted in flash.
+pub struct BootCounter {
+    flash: Flash,
+}
+
+impl BootCounter {
+    pub fn new(platform: &dyn Platform) -> Result<Self, FlashError> {
+        Ok(Self::with_flash(Flash::new(
+            platform,
+            BOOT_PARTITION,
+            BOOT_NAMESPACE,
+        )?))
+    }
+
+    /// A damaged counter reads as zero, rather than blocking the boot.
+    pub fn with_flash(flash: Flash) -> Self {
+        BootCounter {
+            flash: flash.with_recovery(|_, _| Recovery::UseDefault),
+        }
+    }
+
+    /// Number of consecutive boots that have failed.
+    pub fn failures(&self) -> Result<u32, FlashError> {
+        match self.flash.read(KEY_FAILURES) {
+            Ok(n) => Ok(n),
+            Err(FlashError::NotFound) => Ok(0),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Record the start of a boot, returning the number of failed boots before it. The boot counts as failed until
+    /// `clear` is called.
+    pub fn begin(&mut self) -> Result<u32, FlashError> {
+        let failures = self.failures()?;
+        self.flash.write(KEY_FAILURES, failures.saturating_add(1))?;
+        Ok(failures)
+    }
+
+    /// The boot succeeded, reset the count.
+    pub fn clear(&mut self) -> Result<(), FlashError> {
+        self.flash.write(KEY_FAILURES, 0u32)
+    }
+}
+
+#[cfg(all(test, feature = "host"))]
+mod tests {
+    use super::*;
+    use crate::hal::host::MemoryStorage;
+
+    #[test]
+    fn counter_tracks_consecutive_failed_boots() {
+        let store = MemoryStorage::new();
+        let counter = || BootCounter::with_flash(Flash::with_storage(Box::new(store.clone())));
+
+        assert_eq!(counter().begin().unwrap(), 0);
+        assert_eq!(counter().begin().unwrap(), 1);
+        assert_eq!(counter().failures().unwrap(), 2);
+
+        let mut c = counter();
+        c.clear().unwrap();
+        assert_eq!(c.begin().unwrap(), 0);
+    }
+
+    #[test]
+    fn restart_policy_backs_off_then_enters_safe_mode() {
+        let policy = FailurePolicy::Restart {
+            min_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(10),
+            safe_mode_after: Some(3),
+        };
+
+        let delays: Vec<_> = (1..=5).map(|n| policy.recover(n)).collect();
+        assert_eq!(
+            delays,
+            [1, 2, 4, 8, 10].map(|s| Recover::Restart(Duration::from_secs(s)))
+        );
+        assert!(!policy.safe_mode(2));
+        assert!(policy.safe_mode(3));
+
+        assert!(FailurePolicy::SafeMode.safe_mode(1));
+        assert!(!FailurePolicy::SafeMode.safe_mode(0));
+        assert_eq!(FailurePolicy::Halt.recover(9), Recover::Halt);
+        assert!(!FailurePolicy::Halt.safe_mode(9));
+    }
+}
@@ -0,0 +1,171 @@
+//! Connectivity state, shared between the networking task & the rest of the device.
+//!
+//! Services that depend on the network subscribe to link transitions rather than polling `Kernel::is_online`, either
+//! with a channel or a callback.
+
+use std::mem;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Mutex;
+
+use crate::types::{LinkState, WifiState};
+
+/// A transition of the WiFi link from one `LinkState` to another.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ConnectivityEvent {
+    pub previous: LinkState,
+    /// State after the transition, including link statistics at that moment.
+    pub state: WifiState,
+}
+
+impl ConnectivityEvent {
+    /// The device has just come online.
+    pub fn is_online(&self) -> bool {
+        self.state.is_connected() && !matches!(self.previous, LinkState::Connected(_))
+    }
+
+    /// The device has just gone offline.
+    pub fn is_offline(&self) -> bool {
+        !self.state.is_connected() && matches!(self.previous, LinkState::Connected(_))
+    }
+}
+
+type Callback = Box<dyn FnMut(&ConnectivityEvent) + Send>;
+
+enum Subscriber {
+    Channel(Sender<ConnectivityEvent>),
+    Callback(Callback),
+}
+
+#[derive(Default)]
+pub struct Connectivity {
+    state: Mutex<WifiState>,
+    subscribers: Mutex<Vec<Subscriber>>,
+}
+
+impl Connectivity {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn state(&self) -> WifiState {
+        *self.state.lock().unwrap()
+    }
+
+    pub fn is_online(&self) -> bool {
+        self.state().is_connected()
+    }
+
+    /// Receive an event for every link transition. Dropping the receiver ends the subscription.
+    pub fn subscribe(&self) -> Receiver<ConnectivityEvent> {
+        let (tx, rx) = mpsc::channel();
+        self.subscribers
+            .lock()
+            .unwrap()
+            .push(Subscriber::Channel(tx));
+        rx
+    }
+
+    /// Call `f` on every link transition.
+    ///
+    /// Callbacks run on the networking task, so should return quickly. Hand anything slow to another thread.
+    pub fn on_change<F>(&self, f: F)
+    where
+        F: FnMut(&ConnectivityEvent) + Send + 'static,
+    {
+        self.subscribers
+            .lock()
+            .unwrap()
+            .push(Subscriber::Callback(Box::new(f)));
+    }
+
+    /// Modify the state, notifying subscribers if the link state changed.
+    pub fn update(&self, f: impl FnOnce(&mut WifiState)) {
+        let event = {
+            let mut state = self.state.lock().unwrap();
+            let previous = state.link;
+            f(&mut state);
+
+            if state.link == previous {
+                return;
+            }
+            ConnectivityEvent {
+                previous,
+                state: *state,
+            }
+        };
+
+        // Subscribers are taken out while notified, so a callback may subscribe without deadlocking
+        let mut subscribers = mem::take(&mut *self.subscribers.lock().unwrap());
+        subscribers.retain_mut(|s| match s {
+            Subscriber::Channel(tx) => tx.send(event).is_ok(),
+            Subscriber::Callback(f) => {
+                f(&event);
+                true
+            }
+        });
+
+        let mut current = self.subscribers.lock().unwrap();
+        subscribers.append(&mut current);
+        *current = subscribers;
+    }
+}
+
+#[cfg(all(test, feature = "host"))]
+mod tests {
+    use super::*;
+    use crate::hal::wifi::IpInfo;
+    use embedded_svc::ipv4::{Mask, Subnet};
+    use std::net::Ipv4Addr;
+    use std::sync::Arc;
+
+    #[test]
+    fn subscribers_see_link_transitions_only() {
+        let connectivity = Arc::new(Connectivity::new());
+        let rx = connectivity.subscribe();
+        let dropped = connectivity.subscribe();
+        drop(dropped);
+
+        let seen = Arc::new(Mutex::new(vec![]));
+        let log = seen.clone();
+        let c = connectivity.clone();
+        connectivity.on_change(move |e| {
+            log.lock().unwrap().push(e.previous);
+            // Subscribing from a callback must not deadlock
+            let _ = c.subscribe();
+        });
+
+        connectivity.update(|s| s.link = LinkState::Connecting);
+        connectivity.update(|s| s.link = LinkState::Connecting);
+        connectivity.update(|s| s.rssi = Some(-40));
+
+        let ip = IpInfo {
+            ip: Ipv4Addr::new(10, 0, 0, 2),
+            subnet: Subnet {
+                gateway: Ipv4Addr::new(10, 0, 0, 1),
+                mask: Mask(24),
+            },
+            dns: None,
+            secondary_dns: None,
+        };
+        connectivity.update(|s| s.link = LinkState::Connected(ip));
+        connectivity.update(|s| s.link = LinkState::Disconnected);
+
+        let events: Vec<_> = rx.try_iter().collect();
+        assert_eq!(events.len(), 3);
+        assert!(!events[0].is_online());
+        assert!(events[1].is_online());
+        assert_eq!(events[1].state.rssi, Some(-40));
+        assert!(events[2].is_offline());
+
+        assert_eq!(
+            *seen.lock().unwrap(),
+            vec![
+                LinkState::Disconnected,
+                LinkState::Connecting,
+                LinkState::Connected(ip)
+            ]
+        );
+        // Dropped receivers are pruned on the next event, leaving only the latest made by the callback
+        assert_eq!(connectivity.subscribers.lock().unwrap().len(), 3);
+    }
+}
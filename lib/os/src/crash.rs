@@ -0,0 +1,291 @@
+//! Crash & reset log.
+//!
+//! Whatever takes the device down, a panic or a fatal `OsError`, is written to flash as the pending crash before the
+//! reset. On the next boot the pending crash is paired with the reset reason reported by the hardware & appended to a
+//! small ring buffer of recent boots, so the cause of a reboot in the field can be read back or reported over the
+//! network.
+
+use std::any::Any;
+use std::error::Error;
+use std::panic;
+use std::sync::{Arc, Mutex, Once};
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::{FlashError, OsError};
+use crate::flash::{Flash, Json, Readable, Recovery, Writable};
+use crate::hal::{Platform, ResetReason};
+use crate::inu::protocol::{fit, CrashReport};
+
+const LOG_TGT: &str = "inu.crash";
+
+const CRASH_PARTITION: &str = "cfg";
+const CRASH_NAMESPACE: &str = "crash";
+const KEY_LOG: &str = "log";
+const KEY_PENDING: &str = "pending";
+
+/// Number of boots kept in the log.
+pub const CRASH_LOG_LEN: usize = 8;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PanicRecord {
+    pub message: String,
+    /// Source file, line & column of the panic.
+    pub location: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ErrorRecord {
+    /// Stable error code, see `OsError::code`.
+    pub code: u16,
+    /// The underlying ESP-IDF error code, if any.
+    pub esp_code: Option<i32>,
+    /// The error & its sources.
+    pub message: String,
+}
+
+/// What happened before the device went down, recorded ahead of the reset.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Pending {
+    panic: Option<PanicRecord>,
+    error: Option<ErrorRecord>,
+}
+
+/// A boot of the device & the cause of the reset before it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CrashRecord {
+    pub reset_reason: ResetReason,
+    pub panic: Option<PanicRecord>,
+    pub error: Option<ErrorRecord>,
+}
+
+impl CrashRecord {
+    /// Whether the reset was unexpected, rather than a power cycle or a requested restart.
+    pub fn is_crash(&self) -> bool {
+        self.panic.is_some()
+            || self.error.is_some()
+            || matches!(
+                self.reset_reason,
+                ResetReason::Panic | ResetReason::Watchdog | ResetReason::Brownout
+            )
+    }
+
+    /// Summary of the crash for the Inu network.
+    pub fn report(&self, device_id: &str) -> CrashReport {
+        let detail = match (&self.panic, &self.error) {
+            (Some(p), _) => match &p.location {
+                Some(location) => format!("{} at {}", p.message, location),
+                None => p.message.clone(),
+            },
+            (None, Some(e)) => e.message.clone(),
+            (None, None) => String::new(),
+        };
+
+        CrashReport {
+            device_id: device_id.to_string(),
+            reset_reason: self.reset_reason,
+            error_code: self.error.as_ref().map_or(0, |e| e.code),
+            detail: fit(detail),
+        }
+    }
+}
+
+/// Ring buffer of recent boots, persisted in flash.
+pub struct CrashLog {
+    flash: Mutex<Flash>,
+}
+
+impl CrashLog {
+    pub fn new(platform: &dyn Platform) -> Result<Self, FlashError> {
+        Ok(Self::with_flash(Flash::new(
+            platform,
+            CRASH_PARTITION,
+            CRASH_NAMESPACE,
+        )?))
+    }
+
+    /// A damaged log reads as empty, rather than blocking the boot.
+    pub fn with_flash(flash: Flash) -> Self {
+        CrashLog {
+            flash: Mutex::new(flash.with_recovery(|_, _| Recovery::UseDefault)),
+        }
+    }
+
+    /// Log the boot, pairing the reset reason with the pending crash, if any, which is then cleared.
+    pub fn record_boot(&self, reset_reason: ResetReason) -> Result<CrashRecord, FlashError> {
+        let mut flash = self.flash.lock().unwrap();
+        let pending: Pending = read_or_default(&flash, KEY_PENDING)?;
+        let mut records: Vec<CrashRecord> = read_or_default(&flash, KEY_LOG)?;
+
+        let record = CrashRecord {
+            reset_reason,
+            panic: pending.panic,
+            error: pending.error,
+        };
+
+        records.push(record.clone());
+        let excess = records.len().saturating_sub(CRASH_LOG_LEN);
+        records.drain(..excess);
+
+        flash.write(KEY_LOG, Json(&records))?;
+        flash.write(KEY_PENDING, Json(Pending::default()))?;
+        Ok(record)
+    }
+
+    /// Recent boots, oldest first.
+    pub fn records(&self) -> Result<Vec<CrashRecord>, FlashError> {
+        read_or_default(&self.flash.lock().unwrap(), KEY_LOG)
+    }
+
+    /// Record a fatal error, to be logged with the next boot.
+    pub fn record_error(&self, error: &OsError) -> Result<(), FlashError> {
+        let record = ErrorRecord {
+            code: error.code(),
+            esp_code: error.esp_code().map(|c| c.0),
+            message: fit(describe(error)),
+        };
+
+        self.update_pending(|p| p.error = Some(record))
+    }
+
+    /// Record a panic, to be logged with the next boot.
+    pub fn record_panic(&self, message: &str, location: Option<String>) -> Result<(), FlashError> {
+        let record = PanicRecord {
+            message: fit(message.to_string()),
+            location,
+        };
+
+        self.update_pending(|p| p.panic = Some(record))
+    }
+
+    /// Forget every logged boot.
+    pub fn clear(&self) -> Result<(), FlashError> {
+        self.flash
+            .lock()
+            .unwrap()
+            .write(KEY_LOG, Json(Vec::<CrashRecord>::new()))
+    }
+
+    fn update_pending(&self, f: impl FnOnce(&mut Pending)) -> Result<(), FlashError> {
+        // A panic may strike while the log is in use, in which case the panic goes unrecorded rather than deadlocking
+        let mut flash = self.flash.try_lock().map_err(|_| FlashError::IoTimeout)?;
+        let mut pending: Pending = read_or_default(&flash, KEY_PENDING)?;
+        f(&mut pending);
+        flash.write(KEY_PENDING, Json(pending))
+    }
+}
+
+/// The crash log that panics are recorded to. Set by `install_panic_hook`.
+static PANIC_LOG: Mutex<Option<Arc<CrashLog>>> = Mutex::new(None);
+
+/// Record every panic to the given log, before the default panic handling runs.
+pub fn install_panic_hook(log: Arc<CrashLog>) {
+    static INSTALL: Once = Once::new();
+
+    if let Ok(mut current) = PANIC_LOG.lock() {
+        *current = Some(log);
+    }
+
+    INSTALL.call_once(|| {
+        let previous = panic::take_hook();
+
+        panic::set_hook(Box::new(move |info| {
+            let message = panic_message(info.payload());
+
+            if let Some(log) = PANIC_LOG.try_lock().ok().and_then(|l| l.clone()) {
+                if let Err(e) = log.record_panic(message, info.location().map(|l| l.to_string())) {
+                    log::error!(target: LOG_TGT, "Failed to record panic: {:?}", e);
+                }
+            }
+
+            previous(info);
+        }));
+    });
+}
+
+/// The message a panic was raised with.
+pub fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    payload
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("Box<dyn Any>")
+}
+
+fn read_or_default<T: Default + serde::de::DeserializeOwned>(
+    flash: &Flash,
+    key: &str,
+) -> Result<T, FlashError> {
+    match flash.read(key) {
+        Ok(Json(value)) => Ok(value),
+        Err(FlashError::NotFound) => Ok(T::default()),
+        Err(e) => Err(e),
+    }
+}
+
+/// The error followed by each of its sources, eg. "settings error: settings storage error: flash I/O fault".
+pub fn describe(error: &dyn Error) -> String {
+    let mut message = error.to_string();
+    let mut source = error.source();
+
+    while let Some(e) = source {
+        message.push_str(": ");
+        message.push_str(&e.to_string());
+        source = e.source();
+    }
+    message
+}
+
+#[cfg(all(test, feature = "host"))]
+mod tests {
+    use super::*;
+    use crate::error::{FlashError, SettingsError};
+    use crate::hal::host::MemoryStorage;
+
+    #[test]
+    fn crashes_are_logged_with_the_next_boot() {
+        let store = MemoryStorage::new();
+        let log = || CrashLog::with_flash(Flash::with_storage(Box::new(store.clone())));
+
+        let first = log().record_boot(ResetReason::PowerOn).unwrap();
+        assert!(!first.is_crash());
+
+        log()
+            .record_error(&SettingsError::Storage(FlashError::IoFault).into())
+            .unwrap();
+        log()
+            .record_panic("boom", Some("src/main.rs:1:1".into()))
+            .unwrap();
+
+        let crash = log().record_boot(ResetReason::Panic).unwrap();
+        assert!(crash.is_crash());
+        assert_eq!(crash.error.as_ref().unwrap().code, 404);
+        assert_eq!(
+            crash.error.as_ref().unwrap().message,
+            "settings error: settings storage error: flash I/O fault"
+        );
+
+        let report = crash.report("inu.test");
+        assert_eq!(report.error_code, 404);
+        assert_eq!(report.detail, "boom at src/main.rs:1:1");
+
+        // The pending crash is consumed by the boot that logs it
+        let clean = log().record_boot(ResetReason::Software).unwrap();
+        assert!(!clean.is_crash());
+        assert_eq!(log().records().unwrap(), vec![first, crash, clean]);
+    }
+
+    #[test]
+    fn log_keeps_the_most_recent_boots() {
+        let log = CrashLog::with_flash(Flash::with_storage(Box::new(MemoryStorage::new())));
+
+        for _ in 0..CRASH_LOG_LEN {
+            log.record_boot(ResetReason::PowerOn).unwrap();
+        }
+        log.record_boot(ResetReason::Watchdog).unwrap();
+
+        let records = log.records().unwrap();
+        assert_eq!(records.len(), CRASH_LOG_LEN);
+        assert_eq!(records.last().unwrap().reset_reason, ResetReason::Watchdog);
+    }
+}
@@ -1,4 +1,26 @@
+//! OS error types.
+//!
+//! Every error has a stable numeric code, from `code()`, that is safe to report over the network or persist in a crash
+//! log. Codes are grouped by the kind of error:
+//!
+//! | Range   | Kind                      |
+//! |---------|---------------------------|
+//! | 1-99    | `OsError` general errors  |
+//! | 100-199 | `PinError`                |
+//! | 200-299 | `WifiError`               |
+//! | 300-399 | `FlashError`              |
+//! | 400-499 | `SettingsError`           |
+//! | 500-599 | `ProtocolError`           |
+//! | 600-699 | `OtaError`                |
+//! | 700-799 | `ServiceError`            |
+//! | 800-899 | `SleepError`              |
+//!
+//! Codes are never reused or renumbered; new variants take the next free code in their range.
+
+#[cfg(feature = "esp32s3")]
 use esp_idf_svc::sys::EspError;
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
 use std::str::Utf8Error;
 
 #[derive(Debug)]
@@ -7,9 +29,20 @@ pub enum OsError {
     Pin(PinError),
     Wifi(WifiError),
     FlashStorage(FlashError),
+    Settings(SettingsError),
     Parse(String),
+    Protocol(ProtocolError),
+    Ota(OtaError),
+    Io(std::io::Error),
+    Esp(EspCode),
+    Service(ServiceError),
+    Sleep(SleepError),
 }
 
+/// An ESP-IDF `esp_err_t` error code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EspCode(pub i32);
+
 #[derive(Debug)]
 pub enum FlashError {
     IoFault,
@@ -20,6 +53,94 @@ pub enum FlashError {
     Corrupted,
     ChecksumMismatch,
     NotFound,
+    Esp(EspCode),
+}
+
+#[derive(Debug)]
+pub enum SettingsError {
+    /// A required setting has not been provisioned.
+    Missing(&'static str),
+    Invalid {
+        key: &'static str,
+        reason: String,
+    },
+    /// A migration step failed, leaving the store at the given schema version.
+    Migration {
+        version: u16,
+        error: FlashError,
+    },
+    Storage(FlashError),
+}
+
+/// A malformed Inu protocol packet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProtocolError {
+    /// Not an Inu packet.
+    BadMagic,
+    UnsupportedVersion(u8),
+    UnknownKind(u8),
+    /// The packet ended part way through a field.
+    Truncated,
+    InvalidString,
+    /// A field is too long to encode.
+    TooLong,
+    TrailingBytes(usize),
+    InvalidLevel(u8),
+}
+
+/// A firmware update was rejected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OtaError {
+    /// The running image occupies the only OTA slot, so there is nowhere to write an update.
+    NoUpdateSlot,
+    SizeMismatch {
+        expected: usize,
+        actual: usize,
+    },
+    HashMismatch,
+    /// The image is for another firmware edition.
+    WrongEdition(String),
+    /// The image is older than the minimum build the device accepts.
+    Downgrade {
+        build: u32,
+        min_build: u32,
+    },
+    /// The image is unsigned.
+    SignatureRequired,
+    BadSignature,
+    BadReleaseKey,
+    /// The image would be downloaded over a connection other than HTTPS.
+    InsecureUrl(String),
+}
+
+/// A service could not be registered or started.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ServiceError {
+    /// A service with this name is already registered.
+    Duplicate(String),
+    UnknownDependency {
+        service: String,
+        dependency: String,
+    },
+    /// The named service depends on itself, directly or through other services.
+    DependencyCycle(String),
+}
+
+/// The device could not be put to sleep as asked.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SleepError {
+    /// Nothing would wake the device.
+    NoWakeSource,
+    /// Only RTC GPIOs can wake the device.
+    NotRtcPin(u8),
+    NotTouchPad(u8),
+    /// GPIO wake sources must share a level, and only one touch pad can wake the device.
+    ConflictingSources,
+    /// State too large for RTC memory.
+    StateTooLarge {
+        len: usize,
+        max: usize,
+    },
 }
 
 #[derive(Debug)]
@@ -27,6 +148,7 @@ pub enum PinError {
     InvalidPin(u8),
     PinInUse(u8),
     Generic { pin: u8, error: String },
+    Esp { pin: u8, code: EspCode },
 }
 
 impl From<Utf8Error> for OsError {
@@ -43,7 +165,7 @@ impl From<serde_json::Error> for OsError {
 
 impl From<std::io::Error> for OsError {
     fn from(e: std::io::Error) -> Self {
-        OsError::Generic(format!("I/O error: {}", e))
+        OsError::Io(e)
     }
 }
 
@@ -53,17 +175,28 @@ pub enum WifiError {
     NotInitialised,
     Disconnected,
     NoIpAllocation,
+    /// None of the known networks could be joined.
+    NoAccessPoint,
 }
 
+#[cfg(feature = "esp32s3")]
+impl From<EspError> for EspCode {
+    fn from(e: EspError) -> Self {
+        EspCode(e.code())
+    }
+}
+
+#[cfg(feature = "esp32s3")]
 impl From<EspError> for OsError {
     fn from(e: EspError) -> Self {
-        OsError::Generic(format!("ESP error: {:?}", e))
+        OsError::Esp(e.into())
     }
 }
 
+#[cfg(feature = "esp32s3")]
 impl From<EspError> for FlashError {
     fn from(e: EspError) -> Self {
-        FlashError::Generic(format!("ESP error: {:?}", e))
+        FlashError::Esp(e.into())
     }
 }
 
@@ -84,3 +217,441 @@ impl From<WifiError> for OsError {
         OsError::Wifi(e)
     }
 }
+
+impl From<SettingsError> for OsError {
+    fn from(e: SettingsError) -> Self {
+        OsError::Settings(e)
+    }
+}
+
+impl From<FlashError> for SettingsError {
+    fn from(e: FlashError) -> Self {
+        SettingsError::Storage(e)
+    }
+}
+
+impl From<ProtocolError> for OsError {
+    fn from(e: ProtocolError) -> Self {
+        OsError::Protocol(e)
+    }
+}
+
+impl From<OtaError> for OsError {
+    fn from(e: OtaError) -> Self {
+        OsError::Ota(e)
+    }
+}
+
+impl From<ServiceError> for OsError {
+    fn from(e: ServiceError) -> Self {
+        OsError::Service(e)
+    }
+}
+
+impl From<SleepError> for OsError {
+    fn from(e: SleepError) -> Self {
+        OsError::Sleep(e)
+    }
+}
+
+impl OsError {
+    /// Stable numeric code identifying the error.
+    pub fn code(&self) -> u16 {
+        match self {
+            OsError::Generic(_) => 1,
+            OsError::Parse(_) => 2,
+            OsError::Io(_) => 3,
+            OsError::Esp(_) => 4,
+            OsError::Pin(e) => e.code(),
+            OsError::Wifi(e) => e.code(),
+            OsError::FlashStorage(e) => e.code(),
+            OsError::Settings(e) => e.code(),
+            OsError::Protocol(e) => e.code(),
+            OsError::Ota(e) => e.code(),
+            OsError::Service(e) => e.code(),
+            OsError::Sleep(e) => e.code(),
+        }
+    }
+
+    /// The ESP-IDF error code behind this error, if any.
+    pub fn esp_code(&self) -> Option<EspCode> {
+        match self {
+            OsError::Esp(code)
+            | OsError::Pin(PinError::Esp { code, .. })
+            | OsError::FlashStorage(FlashError::Esp(code))
+            | OsError::Settings(SettingsError::Storage(FlashError::Esp(code)))
+            | OsError::Settings(SettingsError::Migration {
+                error: FlashError::Esp(code),
+                ..
+            }) => Some(*code),
+            _ => None,
+        }
+    }
+}
+
+impl PinError {
+    pub fn code(&self) -> u16 {
+        match self {
+            PinError::InvalidPin(_) => 101,
+            PinError::PinInUse(_) => 102,
+            PinError::Generic { .. } => 103,
+            PinError::Esp { .. } => 104,
+        }
+    }
+}
+
+impl WifiError {
+    pub fn code(&self) -> u16 {
+        match self {
+            WifiError::Unknown(_) => 201,
+            WifiError::NotInitialised => 202,
+            WifiError::Disconnected => 203,
+            WifiError::NoIpAllocation => 204,
+            WifiError::NoAccessPoint => 205,
+        }
+    }
+}
+
+impl FlashError {
+    pub fn code(&self) -> u16 {
+        match self {
+            FlashError::IoFault => 301,
+            FlashError::IoTimeout => 302,
+            FlashError::Generic(_) => 303,
+            FlashError::OutOfBounds(_) => 304,
+            FlashError::Uninitialised => 305,
+            FlashError::Corrupted => 306,
+            FlashError::ChecksumMismatch => 307,
+            FlashError::NotFound => 308,
+            FlashError::Esp(_) => 309,
+        }
+    }
+}
+
+impl SettingsError {
+    pub fn code(&self) -> u16 {
+        match self {
+            SettingsError::Missing(_) => 401,
+            SettingsError::Invalid { .. } => 402,
+            SettingsError::Migration { .. } => 403,
+            SettingsError::Storage(_) => 404,
+        }
+    }
+}
+
+impl ProtocolError {
+    pub fn code(&self) -> u16 {
+        match self {
+            ProtocolError::BadMagic => 501,
+            ProtocolError::UnsupportedVersion(_) => 502,
+            ProtocolError::UnknownKind(_) => 503,
+            ProtocolError::Truncated => 504,
+            ProtocolError::InvalidString => 505,
+            ProtocolError::TooLong => 506,
+            ProtocolError::TrailingBytes(_) => 507,
+            ProtocolError::InvalidLevel(_) => 508,
+        }
+    }
+}
+
+impl OtaError {
+    pub fn code(&self) -> u16 {
+        match self {
+            OtaError::NoUpdateSlot => 601,
+            OtaError::SizeMismatch { .. } => 602,
+            OtaError::HashMismatch => 603,
+            OtaError::WrongEdition(_) => 604,
+            OtaError::Downgrade { .. } => 605,
+            OtaError::SignatureRequired => 606,
+            OtaError::BadSignature => 607,
+            OtaError::BadReleaseKey => 608,
+            OtaError::InsecureUrl(_) => 609,
+        }
+    }
+}
+
+impl ServiceError {
+    pub fn code(&self) -> u16 {
+        match self {
+            ServiceError::Duplicate(_) => 701,
+            ServiceError::UnknownDependency { .. } => 702,
+            ServiceError::DependencyCycle(_) => 703,
+        }
+    }
+}
+
+impl SleepError {
+    pub fn code(&self) -> u16 {
+        match self {
+            SleepError::NoWakeSource => 801,
+            SleepError::NotRtcPin(_) => 802,
+            SleepError::NotTouchPad(_) => 803,
+            SleepError::ConflictingSources => 804,
+            SleepError::StateTooLarge { .. } => 805,
+        }
+    }
+}
+
+impl Display for OsError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            OsError::Generic(msg) => f.write_str(msg),
+            OsError::Pin(_) => f.write_str("GPIO error"),
+            OsError::Wifi(_) => f.write_str("WiFi error"),
+            OsError::FlashStorage(_) => f.write_str("flash storage error"),
+            OsError::Settings(_) => f.write_str("settings error"),
+            OsError::Parse(msg) => write!(f, "parse error: {}", msg),
+            OsError::Protocol(_) => f.write_str("Inu protocol error"),
+            OsError::Ota(_) => f.write_str("firmware update error"),
+            OsError::Io(_) => f.write_str("I/O error"),
+            OsError::Esp(code) => code.fmt(f),
+            OsError::Service(_) => f.write_str("service error"),
+            OsError::Sleep(_) => f.write_str("sleep error"),
+        }
+    }
+}
+
+impl Error for OsError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            OsError::Pin(e) => Some(e),
+            OsError::Wifi(e) => Some(e),
+            OsError::FlashStorage(e) => Some(e),
+            OsError::Settings(e) => Some(e),
+            OsError::Protocol(e) => Some(e),
+            OsError::Ota(e) => Some(e),
+            OsError::Io(e) => Some(e),
+            OsError::Service(e) => Some(e),
+            OsError::Sleep(e) => Some(e),
+            OsError::Generic(_) | OsError::Parse(_) | OsError::Esp(_) => None,
+        }
+    }
+}
+
+impl Display for EspCode {
+    #[cfg(feature = "esp32s3")]
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match EspError::from(self.0) {
+            Some(e) => write!(f, "ESP error {} ({:#x})", e, self.0),
+            None => f.write_str("ESP_OK"),
+        }
+    }
+
+    #[cfg(not(feature = "esp32s3"))]
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "ESP error {:#x}", self.0)
+    }
+}
+
+impl Error for EspCode {}
+
+impl Display for PinError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            PinError::InvalidPin(pin) => write!(f, "GPIO{} does not exist", pin),
+            PinError::PinInUse(pin) => write!(f, "GPIO{} is already in use", pin),
+            PinError::Generic { pin, error } => write!(f, "GPIO{}: {}", pin, error),
+            PinError::Esp { pin, .. } => write!(f, "GPIO{} driver error", pin),
+        }
+    }
+}
+
+impl Error for PinError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            PinError::Esp { code, .. } => Some(code),
+            _ => None,
+        }
+    }
+}
+
+impl Display for WifiError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            WifiError::Unknown(msg) => f.write_str(msg),
+            WifiError::NotInitialised => f.write_str("WiFi is not initialised"),
+            WifiError::Disconnected => f.write_str("WiFi is disconnected"),
+            WifiError::NoIpAllocation => f.write_str("no IP address was allocated"),
+            WifiError::NoAccessPoint => f.write_str("none of the known networks could be joined"),
+        }
+    }
+}
+
+impl Error for WifiError {}
+
+impl Display for FlashError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            FlashError::IoFault => f.write_str("flash I/O fault"),
+            FlashError::IoTimeout => f.write_str("flash I/O timed out"),
+            FlashError::Generic(msg) => f.write_str(msg),
+            FlashError::OutOfBounds(msg) => write!(f, "out of bounds: {}", msg),
+            FlashError::Uninitialised => f.write_str("flash is not initialised"),
+            FlashError::Corrupted => f.write_str("stored value is corrupted"),
+            FlashError::ChecksumMismatch => f.write_str("stored value failed its checksum"),
+            FlashError::NotFound => f.write_str("value not found"),
+            FlashError::Esp(_) => f.write_str("flash driver error"),
+        }
+    }
+}
+
+impl Error for FlashError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            FlashError::Esp(code) => Some(code),
+            _ => None,
+        }
+    }
+}
+
+impl Display for SettingsError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            SettingsError::Missing(key) => write!(f, "setting '{}' has not been provisioned", key),
+            SettingsError::Invalid { key, reason } => {
+                write!(f, "setting '{}' is invalid: {}", key, reason)
+            }
+            SettingsError::Migration { version, .. } => {
+                write!(f, "settings migration failed at schema version {}", version)
+            }
+            SettingsError::Storage(_) => f.write_str("settings storage error"),
+        }
+    }
+}
+
+impl Error for SettingsError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            SettingsError::Migration { error, .. } | SettingsError::Storage(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl Display for ProtocolError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::BadMagic => f.write_str("not an Inu packet"),
+            ProtocolError::UnsupportedVersion(v) => write!(f, "unsupported protocol version {}", v),
+            ProtocolError::UnknownKind(k) => write!(f, "unknown message kind {:#04x}", k),
+            ProtocolError::Truncated => f.write_str("packet is truncated"),
+            ProtocolError::InvalidString => f.write_str("string is not valid UTF-8"),
+            ProtocolError::TooLong => f.write_str("field is too long to encode"),
+            ProtocolError::TrailingBytes(n) => write!(f, "{} unexpected bytes at end of packet", n),
+            ProtocolError::InvalidLevel(l) => write!(f, "invalid log level {}", l),
+        }
+    }
+}
+
+impl Error for ProtocolError {}
+
+impl Display for OtaError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            OtaError::NoUpdateSlot => f.write_str("no OTA slot is free for the update"),
+            OtaError::SizeMismatch { expected, actual } => {
+                write!(f, "image is {} bytes, expected {}", actual, expected)
+            }
+            OtaError::HashMismatch => f.write_str("image hash does not match the manifest"),
+            OtaError::WrongEdition(edition) => write!(f, "image is for the {} edition", edition),
+            OtaError::Downgrade { build, min_build } => {
+                write!(
+                    f,
+                    "build {} is older than the minimum build {}",
+                    build, min_build
+                )
+            }
+            OtaError::SignatureRequired => f.write_str("manifest is not signed"),
+            OtaError::BadSignature => f.write_str("manifest signature is invalid"),
+            OtaError::BadReleaseKey => f.write_str("release key is invalid"),
+            OtaError::InsecureUrl(url) => write!(f, "image URL '{}' is not HTTPS", url),
+        }
+    }
+}
+
+impl Error for OtaError {}
+
+impl Display for ServiceError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ServiceError::Duplicate(name) => write!(f, "service '{}' is already registered", name),
+            ServiceError::UnknownDependency {
+                service,
+                dependency,
+            } => write!(
+                f,
+                "service '{}' depends on unknown service '{}'",
+                service, dependency
+            ),
+            ServiceError::DependencyCycle(name) => {
+                write!(f, "service '{}' depends on itself", name)
+            }
+        }
+    }
+}
+
+impl Error for ServiceError {}
+
+impl Display for SleepError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            SleepError::NoWakeSource => f.write_str("no wake source"),
+            SleepError::NotRtcPin(pin) => write!(f, "GPIO{} is not an RTC GPIO", pin),
+            SleepError::NotTouchPad(pad) => write!(f, "GPIO{} is not a touch pad", pad),
+            SleepError::ConflictingSources => f.write_str("conflicting wake sources"),
+            SleepError::StateTooLarge { len, max } => {
+                write!(f, "{} bytes of state exceeds RTC memory of {}", len, max)
+            }
+        }
+    }
+}
+
+impl Error for SleepError {}
+
+#[cfg(all(test, feature = "host"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn errors_chain_to_their_source() {
+        let e = OsError::from(SettingsError::Storage(FlashError::Esp(EspCode(0x1102))));
+
+        let mut chain = vec![e.to_string()];
+        let mut source = e.source();
+        while let Some(s) = source {
+            chain.push(s.to_string());
+            source = s.source();
+        }
+        assert_eq!(
+            chain,
+            [
+                "settings error",
+                "settings storage error",
+                "flash driver error",
+                "ESP error 0x1102"
+            ]
+        );
+        assert_eq!(e.esp_code(), Some(EspCode(0x1102)));
+
+        let io = OsError::from(std::io::Error::from(std::io::ErrorKind::TimedOut));
+        assert!(io.source().is_some());
+        assert_eq!(io.esp_code(), None);
+    }
+
+    #[test]
+    fn error_codes_are_stable() {
+        assert_eq!(OsError::Generic("".into()).code(), 1);
+        assert_eq!(OsError::Esp(EspCode(-1)).code(), 4);
+        assert_eq!(OsError::from(PinError::PinInUse(9)).code(), 102);
+        assert_eq!(OsError::from(WifiError::NoAccessPoint).code(), 205);
+        assert_eq!(OsError::from(FlashError::NotFound).code(), 308);
+        assert_eq!(OsError::from(SettingsError::Missing("wifi_ap")).code(), 401);
+        assert_eq!(OsError::from(ProtocolError::TrailingBytes(1)).code(), 507);
+        assert_eq!(OsError::from(OtaError::BadReleaseKey).code(), 608);
+        assert_eq!(
+            OsError::from(ServiceError::DependencyCycle("inu".into())).code(),
+            703
+        );
+        assert_eq!(OsError::from(SleepError::NotRtcPin(38)).code(), 802);
+    }
+}
@@ -1,21 +1,168 @@
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
 use crate::error::FlashError;
-use esp_idf_svc::nvs::{EspCustomNvsPartition, EspNvs, EspNvsPartition, NvsCustom};
+use crate::hal::{Platform, Storage};
+
+const LOG_TGT: &str = "inu.flash";
+
+/// Size of the frame header: a u32 payload length followed by the MD5 digest of the payload.
+pub const HEADER_LEN: usize = 4 + 16;
+
+/// What to do when a stored value fails its integrity check.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Recovery {
+    /// Return the error to the caller.
+    Fail,
+    /// Treat the value as absent, so the caller falls back to its default.
+    UseDefault,
+    /// Use the last good copy from the backup store, or treat the value as absent if there is none.
+    PreviousCopy,
+}
+
+/// Decides how to recover a damaged field. Given the field name & the integrity error.
+pub type RecoveryHook = Box<dyn Fn(&str, &FlashError) -> Recovery + Send>;
 
 pub struct Flash {
-    nvs: EspNvs<NvsCustom>,
+    storage: Box<dyn Storage>,
+    backup: Option<Box<dyn Storage>>,
+    recovery: RecoveryHook,
 }
 
 /// Flash storage implementation with a header to store the length of the data & an MD5 hash.
 /// Intended for use on NVS partitions, but not limited to that.
+///
+/// Every value is stored as a blob framed by the header, which is verified on read. Damaged values are handled by
+/// the recovery hook, which by default fails the read.
 impl Flash {
-    pub fn new(partition: &str, namespace: &str) -> Result<Self, FlashError> {
-        let partition: EspNvsPartition<NvsCustom> = EspCustomNvsPartition::take(partition).unwrap();
-        let nvs: EspNvs<NvsCustom> = EspNvs::new(partition, namespace, false)?;
+    pub fn new(
+        platform: &dyn Platform,
+        partition: &str,
+        namespace: &str,
+    ) -> Result<Self, FlashError> {
+        Ok(Self::with_storage(platform.storage(partition, namespace)?))
+    }
+
+    /// Create a flash instance on top of an already opened store.
+    pub fn with_storage(storage: Box<dyn Storage>) -> Self {
+        Flash {
+            storage,
+            backup: None,
+            recovery: Box::new(|_, _| Recovery::Fail),
+        }
+    }
+
+    /// Keep the previous good copy of each field in a second store, used by `Recovery::PreviousCopy`.
+    pub fn with_backup(mut self, backup: Box<dyn Storage>) -> Self {
+        self.backup = Some(backup);
+        self
+    }
+
+    /// Set the hook deciding how damaged fields are recovered.
+    pub fn with_recovery<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&str, &FlashError) -> Recovery + Send + 'static,
+    {
+        self.recovery = Box::new(hook);
+        self
+    }
+
+    /// Check if a field exists.
+    pub fn contains(&self, field: &str) -> Result<bool, FlashError> {
+        self.storage.contains(field)
+    }
+
+    /// Remove a field, returning true if it existed.
+    pub fn remove(&mut self, field: &str) -> Result<bool, FlashError> {
+        if let Some(backup) = self.backup.as_mut() {
+            backup.remove(field)?;
+        }
+        self.storage.remove(field)
+    }
+
+    /// Direct access to the underlying store, bypassing the frame header.
+    ///
+    /// Intended for reading values written by other tools, or by firmware that predates the header.
+    pub fn raw(&mut self) -> &mut dyn Storage {
+        self.storage.as_mut()
+    }
+
+    /// Read & verify the payload of a field.
+    fn read_payload(&self, field: &str) -> Result<Vec<u8>, FlashError> {
+        let bytes = found(self.storage.get_blob(field)?)?;
+
+        match unframe(&bytes) {
+            Ok(payload) => Ok(payload.to_vec()),
+            Err(e) => self.recover(field, e),
+        }
+    }
 
-        Ok(Flash { nvs })
+    fn recover(&self, field: &str, error: FlashError) -> Result<Vec<u8>, FlashError> {
+        let policy = (self.recovery)(field, &error);
+        log::warn!(target: LOG_TGT, "Field '{}' is damaged ({:?}), recovering with {:?}", field, error, policy);
+
+        match policy {
+            Recovery::Fail => Err(error),
+            Recovery::UseDefault => Err(FlashError::NotFound),
+            Recovery::PreviousCopy => {
+                let copy = match self.backup.as_ref() {
+                    Some(b) => b.get_blob(field).ok().flatten(),
+                    None => None,
+                };
+
+                match copy.as_deref().map(unframe) {
+                    Some(Ok(payload)) => Ok(payload.to_vec()),
+                    _ => {
+                        log::warn!(target: LOG_TGT, "No good copy of '{}' to recover", field);
+                        Err(FlashError::NotFound)
+                    }
+                }
+            }
+        }
+    }
+
+    /// Frame & write the payload of a field, first moving the current value to the backup store if it is good.
+    fn write_payload(&mut self, field: &str, payload: &[u8]) -> Result<(), FlashError> {
+        if let Some(backup) = self.backup.as_mut() {
+            if let Ok(Some(current)) = self.storage.get_blob(field) {
+                if unframe(&current).is_ok() {
+                    backup.set_blob(field, &current)?;
+                }
+            }
+        }
+
+        self.storage.set_blob(field, &frame(payload))
     }
 }
 
+/// Prefix a payload with its length & MD5 digest.
+pub fn frame(payload: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(HEADER_LEN + payload.len());
+    bytes.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(&md5::compute(payload).0);
+    bytes.extend_from_slice(payload);
+    bytes
+}
+
+/// Verify a framed value, returning its payload.
+pub fn unframe(bytes: &[u8]) -> Result<&[u8], FlashError> {
+    if bytes.len() < HEADER_LEN {
+        return Err(FlashError::Corrupted);
+    }
+
+    let (header, payload) = bytes.split_at(HEADER_LEN);
+    let len = u32::from_le_bytes([header[0], header[1], header[2], header[3]]) as usize;
+    if len != payload.len() {
+        return Err(FlashError::Corrupted);
+    }
+
+    if md5::compute(payload).0 != header[4..] {
+        return Err(FlashError::ChecksumMismatch);
+    }
+
+    Ok(payload)
+}
+
 pub trait Readable<T> {
     fn read(&self, field: &str) -> Result<T, FlashError>;
 }
@@ -24,28 +171,213 @@ pub trait Writable<T> {
     fn write(&mut self, field: &str, value: T) -> Result<(), FlashError>;
 }
 
+/// Wrapper to store any serde-serialisable value in flash, encoded as JSON.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Json<T>(pub T);
+
+fn found<T>(value: Option<T>) -> Result<T, FlashError> {
+    value.ok_or(FlashError::NotFound)
+}
+
 impl Readable<String> for Flash {
     fn read(&self, field: &str) -> Result<String, FlashError> {
-        let mut buffer = [0u8; 16];
-        match self.nvs.get_str(field, buffer.as_mut_slice())? {
-            Some(s) => Ok(s.to_string()),
-            None => Err(FlashError::NotFound),
-        }
+        String::from_utf8(self.read_payload(field)?).map_err(|_| FlashError::Corrupted)
     }
 }
 
-impl Readable<u16> for Flash {
-    fn read(&self, field: &str) -> Result<u16, FlashError> {
-        match self.nvs.get_u16(field)? {
-            Some(s) => Ok(s),
-            None => Err(FlashError::NotFound),
+impl Writable<String> for Flash {
+    fn write(&mut self, field: &str, value: String) -> Result<(), FlashError> {
+        self.write_payload(field, value.as_bytes())
+    }
+}
+
+impl Writable<&str> for Flash {
+    fn write(&mut self, field: &str, value: &str) -> Result<(), FlashError> {
+        self.write_payload(field, value.as_bytes())
+    }
+}
+
+impl Readable<Vec<u8>> for Flash {
+    fn read(&self, field: &str) -> Result<Vec<u8>, FlashError> {
+        self.read_payload(field)
+    }
+}
+
+impl Writable<Vec<u8>> for Flash {
+    fn write(&mut self, field: &str, value: Vec<u8>) -> Result<(), FlashError> {
+        self.write_payload(field, &value)
+    }
+}
+
+impl Writable<&[u8]> for Flash {
+    fn write(&mut self, field: &str, value: &[u8]) -> Result<(), FlashError> {
+        self.write_payload(field, value)
+    }
+}
+
+impl Readable<bool> for Flash {
+    fn read(&self, field: &str) -> Result<bool, FlashError> {
+        match self.read_payload(field)?.as_slice() {
+            [0] => Ok(false),
+            [1] => Ok(true),
+            _ => Err(FlashError::Corrupted),
         }
     }
 }
 
-impl Writable<String> for Flash {
-    fn write(&mut self, field: &str, value: String) -> Result<(), FlashError> {
-        self.nvs.set_str(field, &value)?;
-        Ok(())
+impl Writable<bool> for Flash {
+    fn write(&mut self, field: &str, value: bool) -> Result<(), FlashError> {
+        self.write_payload(field, &[value as u8])
+    }
+}
+
+impl<T: DeserializeOwned> Readable<Json<T>> for Flash {
+    fn read(&self, field: &str) -> Result<Json<T>, FlashError> {
+        serde_json::from_slice(&self.read_payload(field)?)
+            .map(Json)
+            .map_err(|e| FlashError::Generic(format!("Failed to decode '{}': {}", field, e)))
+    }
+}
+
+impl<T: Serialize> Writable<Json<T>> for Flash {
+    fn write(&mut self, field: &str, value: Json<T>) -> Result<(), FlashError> {
+        let bytes = serde_json::to_vec(&value.0)
+            .map_err(|e| FlashError::Generic(format!("Failed to encode '{}': {}", field, e)))?;
+        self.write_payload(field, &bytes)
+    }
+}
+
+/// Implements `Readable` & `Writable` for an integer type, stored little-endian.
+macro_rules! flash_int {
+    ($t:ty) => {
+        impl Readable<$t> for Flash {
+            fn read(&self, field: &str) -> Result<$t, FlashError> {
+                let payload = self.read_payload(field)?;
+                let bytes = payload.try_into().map_err(|_| FlashError::Corrupted)?;
+                Ok(<$t>::from_le_bytes(bytes))
+            }
+        }
+
+        impl Writable<$t> for Flash {
+            fn write(&mut self, field: &str, value: $t) -> Result<(), FlashError> {
+                self.write_payload(field, &value.to_le_bytes())
+            }
+        }
+    };
+}
+
+flash_int!(u8);
+flash_int!(i8);
+flash_int!(u16);
+flash_int!(i16);
+flash_int!(u32);
+flash_int!(i32);
+flash_int!(u64);
+flash_int!(i64);
+
+#[cfg(all(test, feature = "host"))]
+mod tests {
+    use super::*;
+    use crate::hal::host::MemoryStorage;
+    use crate::hal::Storage;
+    use serde::Deserialize;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Sample {
+        name: String,
+        count: u32,
+    }
+
+    fn flash() -> Flash {
+        Flash::with_storage(Box::new(MemoryStorage::new()))
+    }
+
+    #[test]
+    fn values_round_trip() {
+        let mut f = flash();
+        let long = "a WiFi password that is well over sixteen characters long";
+
+        f.write("str", long).unwrap();
+        f.write("blob", vec![0u8, 1, 2, 255]).unwrap();
+        f.write("flag", true).unwrap();
+        f.write("u8", u8::MAX).unwrap();
+        f.write("i16", i16::MIN).unwrap();
+        f.write("u32", u32::MAX).unwrap();
+        f.write("i64", i64::MIN).unwrap();
+
+        assert_eq!(Readable::<String>::read(&f, "str").unwrap(), long);
+        assert_eq!(
+            Readable::<Vec<u8>>::read(&f, "blob").unwrap(),
+            vec![0, 1, 2, 255]
+        );
+        assert!(Readable::<bool>::read(&f, "flag").unwrap());
+        assert_eq!(Readable::<u8>::read(&f, "u8").unwrap(), u8::MAX);
+        assert_eq!(Readable::<i16>::read(&f, "i16").unwrap(), i16::MIN);
+        assert_eq!(Readable::<u32>::read(&f, "u32").unwrap(), u32::MAX);
+        assert_eq!(Readable::<i64>::read(&f, "i64").unwrap(), i64::MIN);
+        assert!(matches!(
+            Readable::<u64>::read(&f, "missing"),
+            Err(FlashError::NotFound)
+        ));
+    }
+
+    #[test]
+    fn damaged_values_are_detected() {
+        let mut store = MemoryStorage::new();
+        let mut f = Flash::with_storage(Box::new(store.clone()));
+        f.write("name", "inu").unwrap();
+
+        let mut bytes = store.get_blob("name").unwrap().unwrap();
+        bytes[HEADER_LEN] ^= 0xff;
+        store.set_blob("name", &bytes).unwrap();
+        assert!(matches!(
+            Readable::<String>::read(&f, "name"),
+            Err(FlashError::ChecksumMismatch)
+        ));
+
+        bytes.pop();
+        store.set_blob("name", &bytes).unwrap();
+        assert!(matches!(
+            Readable::<String>::read(&f, "name"),
+            Err(FlashError::Corrupted)
+        ));
+
+        let f = f.with_recovery(|_, _| Recovery::UseDefault);
+        assert!(matches!(
+            Readable::<String>::read(&f, "name"),
+            Err(FlashError::NotFound)
+        ));
+    }
+
+    #[test]
+    fn previous_copy_is_recovered() {
+        let mut store = MemoryStorage::new();
+        let mut f = Flash::with_storage(Box::new(store.clone()))
+            .with_backup(Box::new(MemoryStorage::new()))
+            .with_recovery(|_, _| Recovery::PreviousCopy);
+
+        f.write("count", 1u32).unwrap();
+        f.write("count", 2u32).unwrap();
+        store.set_blob("count", &[0u8; 3]).unwrap();
+        assert_eq!(Readable::<u32>::read(&f, "count").unwrap(), 1);
+
+        // Damaged values are never copied to the backup
+        f.write("count", 3u32).unwrap();
+        assert_eq!(Readable::<u32>::read(&f, "count").unwrap(), 3);
+        store.set_blob("count", &[0u8; 3]).unwrap();
+        assert_eq!(Readable::<u32>::read(&f, "count").unwrap(), 1);
+    }
+
+    #[test]
+    fn serde_values_round_trip() {
+        let mut f = flash();
+        let sample = Sample {
+            name: "inu".into(),
+            count: 3,
+        };
+
+        f.write("sample", Json(&sample)).unwrap();
+        let Json(read): Json<Sample> = f.read("sample").unwrap();
+        assert_eq!(read, sample);
     }
 }
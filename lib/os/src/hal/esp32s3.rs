@@ -0,0 +1,815 @@
+//! ESP-IDF backend for the ESP32-S3.
+
+use core::num::NonZeroU32;
+use core::str::FromStr;
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use esp_idf_hal::cpu;
+use esp_idf_hal::delay::{self, TickType};
+use esp_idf_hal::task::{self, thread::ThreadSpawnConfiguration};
+use esp_idf_svc::eventloop::{
+    EspEvent, EspEventDeserializer, EspEventSource, EspSubscription, EspSystemEventLoop, System,
+};
+use esp_idf_svc::hal::gpio::{self as esp_gpio, AnyIOPin, InterruptType, PinDriver};
+use esp_idf_svc::hal::modem::Modem;
+use esp_idf_svc::http::client::{self as esp_client, EspHttpConnection};
+use esp_idf_svc::http::server::{self as esp_http, EspHttpServer};
+use esp_idf_svc::io::Write;
+use esp_idf_svc::nvs::{EspCustomNvsPartition, EspNvs, EspNvsPartition, NvsCustom};
+use esp_idf_svc::ota::{self as esp_ota, EspOta, EspOtaUpdate, SlotState};
+use esp_idf_svc::sys::{self, esp, EspError};
+use esp_idf_svc::wifi::{
+    AccessPointConfiguration, AuthMethod, BlockingWifi, Configuration, EspWifi,
+};
+
+use crate::error::{FlashError, OsError, PinError, WifiError};
+use crate::hal::clock::SystemClock;
+use crate::hal::gpio::{Gpio, InputPin, Level, OutputPin, Pull};
+use crate::hal::http::{self, Download, HttpHandler, HttpServer, Request, Response};
+use crate::hal::ota::{ImageState, OtaDriver, OtaUpdate, Slot};
+use crate::hal::storage::Storage;
+use crate::hal::wifi::{ClientConfiguration, IpInfo, ScanResult, WifiDriver, WifiEvent};
+use crate::hal::{
+    Clock, Core, HeapStats, Platform, PowerConfig, ResetReason, TaskId, ThreadOptions, WakeCause,
+    WakeLevel, WakeSource, Watchdog, RTC_MEMORY_LEN,
+};
+
+/// Kept across deep sleep, zeroed at power on.
+#[link_section = ".rtc.data"]
+static mut RTC_MEMORY: [u8; RTC_MEMORY_LEN] = [0; RTC_MEMORY_LEN];
+
+pub struct EspPlatform {
+    sysloop: EspSystemEventLoop,
+    modem: Mutex<Option<Modem>>,
+    partitions: Mutex<HashMap<String, EspNvsPartition<NvsCustom>>>,
+    gpio: Arc<EspGpio>,
+    clock: Arc<SystemClock>,
+    rtc_lock: Mutex<()>,
+}
+
+impl EspPlatform {
+    /// Take the device peripherals.
+    ///
+    /// # Safety
+    /// Singleton. Create only once.
+    pub unsafe fn take() -> Result<Self, OsError> {
+        Ok(Self {
+            sysloop: EspSystemEventLoop::take()?,
+            modem: Mutex::new(Some(Modem::new())),
+            partitions: Mutex::new(HashMap::new()),
+            gpio: Arc::new(EspGpio),
+            clock: Arc::new(SystemClock::new()),
+            rtc_lock: Mutex::new(()),
+        })
+    }
+
+    /// The ESP-IDF system event loop.
+    pub fn sysloop(&self) -> EspSystemEventLoop {
+        self.sysloop.clone()
+    }
+
+    /// NVS partitions can only be taken once, so each is cached & shared between namespaces.
+    fn partition(&self, name: &str) -> Result<EspNvsPartition<NvsCustom>, FlashError> {
+        let mut partitions = self
+            .partitions
+            .lock()
+            .map_err(|e| FlashError::Generic(format!("Partition mutex poisoned: {:?}", e)))?;
+
+        if let Some(p) = partitions.get(name) {
+            return Ok(p.clone());
+        }
+
+        let p = EspCustomNvsPartition::take(name)?;
+        partitions.insert(name.to_string(), p.clone());
+        Ok(p)
+    }
+}
+
+impl Platform for EspPlatform {
+    fn storage(&self, partition: &str, namespace: &str) -> Result<Box<dyn Storage>, FlashError> {
+        let nvs = EspNvs::new(self.partition(partition)?, namespace, true)?;
+        Ok(Box::new(EspStorage { nvs }))
+    }
+
+    fn gpio(&self) -> Arc<dyn Gpio> {
+        self.gpio.clone()
+    }
+
+    fn wifi(&self) -> Result<Box<dyn WifiDriver>, OsError> {
+        let modem = self
+            .modem
+            .lock()
+            .map_err(|e| OsError::Generic(format!("Modem mutex poisoned: {:?}", e)))?
+            .take()
+            .ok_or(WifiError::Unknown("WiFi modem already taken".into()))?;
+
+        let esp_wifi = EspWifi::new(modem, self.sysloop.clone(), None)?;
+        let wifi = BlockingWifi::wrap(esp_wifi, self.sysloop.clone())?;
+
+        Ok(Box::new(EspWifiDriver {
+            wifi,
+            sysloop: self.sysloop.clone(),
+            subscriptions: vec![],
+        }))
+    }
+
+    fn http_server(&self, port: u16, handler: HttpHandler) -> Result<Box<dyn HttpServer>, OsError> {
+        let mut server = EspHttpServer::new(&esp_http::Configuration {
+            http_port: port,
+            uri_match_wildcard: true,
+            ..Default::default()
+        })
+        .map_err(|e| OsError::Generic(format!("Failed to start HTTP server: {:?}", e)))?;
+
+        for method in [http::Method::Get, http::Method::Post] {
+            let handler = handler.clone();
+            let esp_method = match method {
+                http::Method::Get => esp_http::Method::Get,
+                http::Method::Post => esp_http::Method::Post,
+            };
+
+            server.fn_handler("/*", esp_method, move |mut req| {
+                let mut body = vec![];
+                let mut buffer = [0u8; 256];
+                let mut too_large = false;
+                loop {
+                    let len = req.read(&mut buffer)?;
+                    if len == 0 {
+                        break;
+                    }
+                    if body.len() + len > http::MAX_BODY_LEN {
+                        too_large = true;
+                        break;
+                    }
+                    body.extend_from_slice(&buffer[..len]);
+                }
+
+                let response = match too_large {
+                    true => Response::too_large(),
+                    false => handler(&Request {
+                        method,
+                        uri: req.uri().to_string(),
+                        body,
+                    }),
+                };
+
+                req.into_response(
+                    response.status,
+                    None,
+                    &[("Content-Type", response.content_type)],
+                )?
+                .write_all(&response.body)
+            })?;
+        }
+
+        Ok(Box::new(EspHttpServerHandle(server)))
+    }
+
+    fn download(&self, url: &str) -> Result<Box<dyn Download>, OsError> {
+        let mut connection = EspHttpConnection::new(&esp_client::Configuration {
+            crt_bundle_attach: Some(sys::esp_crt_bundle_attach),
+            ..Default::default()
+        })?;
+        connection.initiate_request(esp_client::Method::Get, url, &[])?;
+        connection.initiate_response()?;
+
+        match connection.status() {
+            200 => Ok(Box::new(EspDownload(connection))),
+            status => Err(OsError::Generic(format!(
+                "HTTP {} fetching {}",
+                status, url
+            ))),
+        }
+    }
+
+    fn ota(&self) -> Result<Box<dyn OtaDriver>, OsError> {
+        Ok(Box::new(EspOtaDriver(EspOta::new()?)))
+    }
+
+    fn clock(&self) -> Arc<dyn Clock> {
+        self.clock.clone()
+    }
+
+    fn random(&self) -> u32 {
+        // Hardware RNG, seeded from RF noise while the radio is on
+        unsafe { sys::esp_random() }
+    }
+
+    fn current_core(&self) -> Core {
+        match cpu::core() {
+            cpu::Core::Core0 => Core::Core0,
+            cpu::Core::Core1 => Core::Core1,
+        }
+    }
+
+    fn spawn(
+        &self,
+        options: ThreadOptions,
+        f: Box<dyn FnOnce() + Send>,
+    ) -> Result<JoinHandle<()>, OsError> {
+        // Set the default thread configuration
+        ThreadSpawnConfiguration {
+            stack_size: options.stack_size,
+            priority: options.priority,
+            pin_to_core: options.core.map(|c| match c {
+                Core::Core0 => cpu::Core::Core0,
+                Core::Core1 => cpu::Core::Core1,
+            }),
+            ..Default::default()
+        }
+        .set()?;
+
+        // Create the new thread
+        let thread = std::thread::Builder::new().spawn(f)?;
+
+        // ..and reset the default thread config
+        ThreadSpawnConfiguration::default().set()?;
+
+        Ok(thread)
+    }
+
+    fn current_task(&self) -> TaskId {
+        TaskId(unsafe { sys::xTaskGetCurrentTaskHandle() } as usize)
+    }
+
+    fn stack_high_water(&self, task: TaskId) -> Option<usize> {
+        // ESP-IDF measures FreeRTOS stacks in bytes
+        Some(unsafe { sys::uxTaskGetStackHighWaterMark(task.0 as sys::TaskHandle_t) } as usize)
+    }
+
+    fn heap_stats(&self) -> Option<HeapStats> {
+        unsafe {
+            Some(HeapStats {
+                free: sys::heap_caps_get_free_size(sys::MALLOC_CAP_8BIT),
+                min_free: sys::heap_caps_get_minimum_free_size(sys::MALLOC_CAP_8BIT),
+                largest_block: sys::heap_caps_get_largest_free_block(sys::MALLOC_CAP_8BIT),
+            })
+        }
+    }
+
+    fn watchdog(&self) -> Arc<dyn Watchdog> {
+        Arc::new(EspWatchdog)
+    }
+
+    fn configure_power(&self, config: &PowerConfig) -> Result<(), OsError> {
+        let pm = sys::esp_pm_config_t {
+            max_freq_mhz: config.max_mhz as i32,
+            min_freq_mhz: config.min_mhz as i32,
+            light_sleep_enable: config.light_sleep,
+        };
+
+        esp!(unsafe { sys::esp_pm_configure(&pm as *const _ as *const core::ffi::c_void) })?;
+        Ok(())
+    }
+
+    fn set_wake_sources(&self, sources: &[WakeSource]) -> Result<(), OsError> {
+        esp!(unsafe {
+            sys::esp_sleep_disable_wakeup_source(sys::esp_sleep_source_t_ESP_SLEEP_WAKEUP_ALL)
+        })?;
+
+        for source in sources {
+            match source {
+                WakeSource::Gpio { pins, level } => {
+                    let mask = pins.iter().fold(0u64, |mask, pin| mask | 1 << pin);
+                    let mode = match level {
+                        WakeLevel::AnyHigh => {
+                            sys::esp_sleep_ext1_wakeup_mode_t_ESP_EXT1_WAKEUP_ANY_HIGH
+                        }
+                        WakeLevel::AnyLow => {
+                            sys::esp_sleep_ext1_wakeup_mode_t_ESP_EXT1_WAKEUP_ANY_LOW
+                        }
+                    };
+                    esp!(unsafe { sys::esp_sleep_enable_ext1_wakeup(mask, mode) })?;
+                }
+                WakeSource::Timer(after) => {
+                    esp!(unsafe { sys::esp_sleep_enable_timer_wakeup(after.as_micros() as u64) })?;
+                }
+                WakeSource::Touch { pad, threshold } => unsafe {
+                    let pad = *pad as sys::touch_pad_t;
+                    esp!(sys::touch_pad_init())?;
+                    esp!(sys::touch_pad_config(pad))?;
+                    esp!(sys::touch_pad_set_fsm_mode(
+                        sys::touch_fsm_mode_t_TOUCH_FSM_MODE_TIMER
+                    ))?;
+                    esp!(sys::touch_pad_fsm_start())?;
+                    esp!(sys::touch_pad_sleep_channel_enable(pad, true))?;
+                    esp!(sys::touch_pad_sleep_set_threshold(pad, *threshold))?;
+                    esp!(sys::esp_sleep_enable_touchpad_wakeup())?;
+                },
+            }
+        }
+
+        Ok(())
+    }
+
+    fn deep_sleep(&self) -> ! {
+        unsafe { sys::esp_deep_sleep_start() }
+    }
+
+    fn wake_cause(&self) -> Option<WakeCause> {
+        unsafe {
+            match sys::esp_sleep_get_wakeup_cause() {
+                sys::esp_sleep_source_t_ESP_SLEEP_WAKEUP_UNDEFINED => None,
+                sys::esp_sleep_source_t_ESP_SLEEP_WAKEUP_EXT1 => {
+                    let mask = sys::esp_sleep_get_ext1_wakeup_status();
+                    Some(WakeCause::Gpio(
+                        (0..64).filter(|pin| mask & 1 << pin != 0).collect(),
+                    ))
+                }
+                sys::esp_sleep_source_t_ESP_SLEEP_WAKEUP_TIMER => Some(WakeCause::Timer),
+                sys::esp_sleep_source_t_ESP_SLEEP_WAKEUP_TOUCHPAD => Some(WakeCause::Touch(
+                    sys::esp_sleep_get_touchpad_wakeup_status() as u8,
+                )),
+                _ => Some(WakeCause::Other),
+            }
+        }
+    }
+
+    fn read_rtc(&self) -> [u8; RTC_MEMORY_LEN] {
+        let _lock = self.rtc_lock.lock().unwrap();
+        unsafe { core::ptr::addr_of!(RTC_MEMORY).read_volatile() }
+    }
+
+    fn write_rtc(&self, data: &[u8; RTC_MEMORY_LEN]) {
+        let _lock = self.rtc_lock.lock().unwrap();
+        unsafe { core::ptr::addr_of_mut!(RTC_MEMORY).write_volatile(*data) }
+    }
+
+    fn reset_reason(&self) -> ResetReason {
+        use esp_idf_svc::hal::reset::ResetReason as Esp;
+
+        match Esp::get() {
+            Esp::PowerOn => ResetReason::PowerOn,
+            Esp::Software => ResetReason::Software,
+            Esp::Panic => ResetReason::Panic,
+            Esp::Watchdog | Esp::InterruptWatchdog | Esp::TaskWatchdog => ResetReason::Watchdog,
+            Esp::Brownout => ResetReason::Brownout,
+            Esp::ExternalPin => ResetReason::ExternalPin,
+            Esp::DeepSleep => ResetReason::DeepSleep,
+            _ => ResetReason::Unknown,
+        }
+    }
+
+    fn restart(&self) -> ! {
+        esp_idf_svc::hal::reset::restart();
+    }
+}
+
+/// Implements the integer getter & setter of `Storage` by delegating to the NVS method of the same name.
+macro_rules! esp_nvs_int {
+    ($t:ty, $get:ident, $set:ident) => {
+        fn $get(&self, key: &str) -> Result<Option<$t>, FlashError> {
+            Ok(self.nvs.$get(key)?)
+        }
+
+        fn $set(&mut self, key: &str, value: $t) -> Result<(), FlashError> {
+            Ok(self.nvs.$set(key, value)?)
+        }
+    };
+}
+
+/// NVS backed storage.
+pub struct EspStorage {
+    nvs: EspNvs<NvsCustom>,
+}
+
+impl Storage for EspStorage {
+    fn contains(&self, key: &str) -> Result<bool, FlashError> {
+        Ok(self.nvs.contains(key)?)
+    }
+
+    fn remove(&mut self, key: &str) -> Result<bool, FlashError> {
+        Ok(self.nvs.remove(key)?)
+    }
+
+    fn get_str(&self, key: &str) -> Result<Option<String>, FlashError> {
+        // Length includes the null terminator
+        let len = match self.nvs.str_len(key)? {
+            Some(len) => len,
+            None => return Ok(None),
+        };
+
+        let mut buffer = vec![0u8; len];
+        Ok(self
+            .nvs
+            .get_str(key, buffer.as_mut_slice())?
+            .map(|s| s.to_string()))
+    }
+
+    fn set_str(&mut self, key: &str, value: &str) -> Result<(), FlashError> {
+        Ok(self.nvs.set_str(key, value)?)
+    }
+
+    fn get_blob(&self, key: &str) -> Result<Option<Vec<u8>>, FlashError> {
+        let len = match self.nvs.blob_len(key)? {
+            Some(len) => len,
+            None => return Ok(None),
+        };
+
+        let mut buffer = vec![0u8; len];
+        Ok(self
+            .nvs
+            .get_blob(key, buffer.as_mut_slice())?
+            .map(|b| b.to_vec()))
+    }
+
+    fn set_blob(&mut self, key: &str, value: &[u8]) -> Result<(), FlashError> {
+        Ok(self.nvs.set_blob(key, value)?)
+    }
+
+    esp_nvs_int!(u8, get_u8, set_u8);
+    esp_nvs_int!(i8, get_i8, set_i8);
+    esp_nvs_int!(u16, get_u16, set_u16);
+    esp_nvs_int!(i16, get_i16, set_i16);
+    esp_nvs_int!(u32, get_u32, set_u32);
+    esp_nvs_int!(i32, get_i32, set_i32);
+    esp_nvs_int!(u64, get_u64, set_u64);
+    esp_nvs_int!(i64, get_i64, set_i64);
+}
+
+/// The ESP-IDF task watchdog (TWDT), started by ESP-IDF at boot.
+pub struct EspWatchdog;
+
+impl Watchdog for EspWatchdog {
+    fn timeout(&self) -> Duration {
+        Duration::from_secs(sys::CONFIG_ESP_TASK_WDT_TIMEOUT_S as u64)
+    }
+
+    fn subscribe(&self) -> Result<(), OsError> {
+        Ok(esp!(unsafe {
+            sys::esp_task_wdt_add(core::ptr::null_mut())
+        })?)
+    }
+
+    fn unsubscribe(&self) -> Result<(), OsError> {
+        Ok(esp!(unsafe {
+            sys::esp_task_wdt_delete(core::ptr::null_mut())
+        })?)
+    }
+
+    fn feed(&self) -> Result<(), OsError> {
+        Ok(esp!(unsafe { sys::esp_task_wdt_reset() })?)
+    }
+}
+
+/// GPIO driver. Pins are created on demand as the `PinManager` guarantees exclusive ownership.
+pub struct EspGpio;
+
+impl Gpio for EspGpio {
+    fn input(&self, pin: u8, pull: Pull) -> Result<Box<dyn InputPin>, PinError> {
+        let p = unsafe { AnyIOPin::new(pin as i32) };
+        let mut input = PinDriver::input(p).map_err(|e| PinError::Esp {
+            pin,
+            code: e.into(),
+        })?;
+
+        input.set_pull(pull.into()).map_err(|e| PinError::Esp {
+            pin,
+            code: e.into(),
+        })?;
+
+        Ok(Box::new(EspInputPin {
+            pin,
+            driver: input,
+            task: None,
+        }))
+    }
+
+    fn output(&self, pin: u8, level: Level) -> Result<Box<dyn OutputPin>, PinError> {
+        let p = unsafe { AnyIOPin::new(pin as i32) };
+        let driver = PinDriver::output(p).map_err(|e| PinError::Esp {
+            pin,
+            code: e.into(),
+        })?;
+
+        let mut output = EspOutputPin { pin, driver };
+        output.set_level(level)?;
+
+        Ok(Box::new(output))
+    }
+}
+
+pub struct EspInputPin {
+    pin: u8,
+    driver: PinDriver<'static, AnyIOPin, esp_gpio::Input>,
+    /// Task notified by the pin interrupt, once subscribed.
+    task: Option<usize>,
+}
+
+impl EspInputPin {
+    fn esp_error(&self, e: EspError) -> PinError {
+        PinError::Esp {
+            pin: self.pin,
+            code: e.into(),
+        }
+    }
+}
+
+impl InputPin for EspInputPin {
+    fn pin(&self) -> u8 {
+        self.pin
+    }
+
+    fn get_level(&self) -> Level {
+        self.driver.get_level().into()
+    }
+
+    fn wait_for_level(&mut self, level: Level, timeout: Duration) -> Result<bool, PinError> {
+        let current = task::current().ok_or_else(|| PinError::Generic {
+            pin: self.pin,
+            error: "Can't wait in an interrupt".into(),
+        })? as usize;
+
+        if self.task != Some(current) {
+            // The ISR only notifies the waiting task, the driver disarms the interrupt each time it fires
+            unsafe {
+                self.driver.subscribe(move || {
+                    task::notify_and_yield(current as sys::TaskHandle_t, NonZeroU32::MIN);
+                })
+            }
+            .map_err(|e| self.esp_error(e))?;
+            esp!(unsafe { sys::esp_sleep_enable_gpio_wakeup() }).map_err(|e| self.esp_error(e))?;
+            self.task = Some(current);
+        }
+
+        // Level rather than edge triggered, so a level reached before the interrupt is armed isn't missed, and so
+        // the pin can wake the CPU from light sleep
+        let interrupt = match level {
+            Level::High => InterruptType::HighLevel,
+            Level::Low => InterruptType::LowLevel,
+        };
+        self.driver
+            .disable_interrupt()
+            .map_err(|e| self.esp_error(e))?;
+        task::wait_notification(delay::NON_BLOCK);
+        self.driver
+            .set_interrupt_type(interrupt)
+            .map_err(|e| self.esp_error(e))?;
+        esp!(unsafe { sys::gpio_wakeup_enable(self.pin as i32, interrupt.into()) })
+            .map_err(|e| self.esp_error(e))?;
+        self.driver
+            .enable_interrupt()
+            .map_err(|e| self.esp_error(e))?;
+
+        Ok(task::wait_notification(TickType::from(timeout).ticks()).is_some())
+    }
+}
+
+pub struct EspOutputPin {
+    pin: u8,
+    driver: PinDriver<'static, AnyIOPin, esp_gpio::Output>,
+}
+
+impl OutputPin for EspOutputPin {
+    fn pin(&self) -> u8 {
+        self.pin
+    }
+
+    fn set_level(&mut self, level: Level) -> Result<(), PinError> {
+        self.driver
+            .set_level(level.into())
+            .map_err(|e| PinError::Esp {
+                pin: self.pin,
+                code: e.into(),
+            })
+    }
+}
+
+impl From<esp_gpio::Level> for Level {
+    fn from(level: esp_gpio::Level) -> Self {
+        match level {
+            esp_gpio::Level::Low => Level::Low,
+            esp_gpio::Level::High => Level::High,
+        }
+    }
+}
+
+impl From<Level> for esp_gpio::Level {
+    fn from(level: Level) -> Self {
+        match level {
+            Level::Low => esp_gpio::Level::Low,
+            Level::High => esp_gpio::Level::High,
+        }
+    }
+}
+
+impl From<Pull> for esp_gpio::Pull {
+    fn from(pull: Pull) -> Self {
+        match pull {
+            Pull::Floating => esp_gpio::Pull::Floating,
+            Pull::Up => esp_gpio::Pull::Up,
+            Pull::Down => esp_gpio::Pull::Down,
+            Pull::UpDown => esp_gpio::Pull::UpDown,
+        }
+    }
+}
+
+/// The server runs on its own IDF task, the handle is only kept to stop it.
+struct EspHttpServerHandle(#[allow(dead_code)] EspHttpServer<'static>);
+
+unsafe impl Send for EspHttpServerHandle {}
+
+impl HttpServer for EspHttpServerHandle {}
+
+struct EspDownload(EspHttpConnection);
+
+/// The connection is only used by the thread that owns the download.
+unsafe impl Send for EspDownload {}
+
+impl Download for EspDownload {
+    fn content_length(&self) -> Option<usize> {
+        self.0.header("Content-Length").and_then(|l| l.parse().ok())
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, OsError> {
+        Ok(self.0.read(buf)?)
+    }
+}
+
+struct EspOtaDriver(EspOta);
+
+impl EspOtaDriver {
+    fn slot(slot: esp_ota::Slot) -> Slot {
+        Slot {
+            label: slot.label.to_string(),
+            state: match slot.state {
+                SlotState::Factory => ImageState::Factory,
+                SlotState::Valid => ImageState::Valid,
+                SlotState::Unverified => ImageState::PendingVerify,
+                SlotState::Invalid => ImageState::Invalid,
+                SlotState::Unknown => ImageState::Unknown,
+            },
+        }
+    }
+}
+
+impl OtaDriver for EspOtaDriver {
+    fn running_slot(&self) -> Result<Slot, OsError> {
+        Ok(Self::slot(self.0.get_running_slot()?))
+    }
+
+    fn update_slot(&self) -> Result<Slot, OsError> {
+        Ok(Self::slot(self.0.get_update_slot()?))
+    }
+
+    fn begin(&mut self) -> Result<Box<dyn OtaUpdate + '_>, OsError> {
+        Ok(Box::new(EspOtaWriter(self.0.initiate_update()?)))
+    }
+
+    fn mark_valid(&mut self) -> Result<(), OsError> {
+        Ok(self.0.mark_running_slot_valid()?)
+    }
+
+    fn rollback(&mut self) -> Result<(), OsError> {
+        Err(self.0.mark_running_slot_invalid_and_reboot().into())
+    }
+}
+
+/// Dropping the IDF update handle aborts the update.
+struct EspOtaWriter<'a>(EspOtaUpdate<'a>);
+
+impl OtaUpdate for EspOtaWriter<'_> {
+    fn write(&mut self, data: &[u8]) -> Result<(), OsError> {
+        Ok(self.0.write(data)?)
+    }
+
+    fn complete(self: Box<Self>) -> Result<(), OsError> {
+        Ok(self.0.complete()?)
+    }
+}
+
+/// WiFi station driver.
+pub struct EspWifiDriver {
+    wifi: BlockingWifi<EspWifi<'static>>,
+    sysloop: EspSystemEventLoop,
+    subscriptions: Vec<EspSubscription<'static, System>>,
+}
+
+/// Station events. `esp_idf_svc::wifi::WifiEvent` drops the disconnect reason, so the payload is read here.
+enum StaEvent {
+    Connected,
+    Disconnected(u16),
+    Other,
+}
+
+unsafe impl EspEventSource for StaEvent {
+    fn source() -> Option<&'static core::ffi::CStr> {
+        Some(unsafe { core::ffi::CStr::from_ptr(sys::WIFI_EVENT) })
+    }
+}
+
+impl EspEventDeserializer for StaEvent {
+    type Data<'d> = StaEvent;
+
+    #[allow(non_upper_case_globals)]
+    fn deserialize<'d>(data: &EspEvent<'d>) -> StaEvent {
+        match data.event_id as u32 {
+            sys::wifi_event_t_WIFI_EVENT_STA_CONNECTED => StaEvent::Connected,
+            sys::wifi_event_t_WIFI_EVENT_STA_DISCONNECTED => {
+                let reason = data
+                    .payload
+                    .map(|p| p as *const _ as *const sys::wifi_event_sta_disconnected_t)
+                    .and_then(|p| unsafe { p.as_ref() })
+                    .map_or(0, |p| p.reason as u16);
+                StaEvent::Disconnected(reason)
+            }
+            _ => StaEvent::Other,
+        }
+    }
+}
+
+impl WifiDriver for EspWifiDriver {
+    fn set_configuration(&mut self, config: &ClientConfiguration) -> Result<(), OsError> {
+        Ok(self
+            .wifi
+            .set_configuration(&Configuration::Client(config.clone()))?)
+    }
+
+    fn start(&mut self) -> Result<(), OsError> {
+        Ok(self.wifi.start()?)
+    }
+
+    fn scan(&mut self) -> Result<Vec<ScanResult>, OsError> {
+        Ok(self
+            .wifi
+            .scan()?
+            .into_iter()
+            .map(|ap| ScanResult {
+                ssid: ap.ssid.to_string(),
+                bssid: ap.bssid,
+                channel: ap.channel,
+                rssi: ap.signal_strength,
+                auth_method: ap.auth_method,
+            })
+            .collect())
+    }
+
+    fn connect(&mut self) -> Result<(), OsError> {
+        Ok(self.wifi.connect()?)
+    }
+
+    fn disconnect(&mut self) -> Result<(), OsError> {
+        Ok(self.wifi.disconnect()?)
+    }
+
+    fn is_connected(&self) -> Result<bool, OsError> {
+        Ok(self.wifi.is_connected()?)
+    }
+
+    fn subscribe(&mut self) -> Result<Receiver<WifiEvent>, OsError> {
+        let (tx, rx) = mpsc::channel();
+
+        let subscription = self.sysloop.subscribe::<StaEvent, _>(move |event| {
+            let event = match event {
+                StaEvent::Connected => WifiEvent::Connected,
+                StaEvent::Disconnected(reason) => WifiEvent::Disconnected { reason },
+                StaEvent::Other => return,
+            };
+
+            // The receiver may have been dropped, there's nothing to be done about it here
+            let _ = tx.send(event);
+        })?;
+
+        self.subscriptions.push(subscription);
+        Ok(rx)
+    }
+
+    fn rssi(&self) -> Result<i8, OsError> {
+        let mut info = sys::wifi_ap_record_t::default();
+        esp!(unsafe { sys::esp_wifi_sta_get_ap_info(&mut info) })?;
+        Ok(info.rssi)
+    }
+
+    fn wait_netif_up(&mut self) -> Result<(), OsError> {
+        Ok(self.wifi.wait_netif_up()?)
+    }
+
+    fn ip_info(&self) -> Result<IpInfo, OsError> {
+        Ok(self.wifi.wifi().sta_netif().get_ip_info()?)
+    }
+
+    fn start_access_point(&mut self, ssid: &str) -> Result<Ipv4Addr, OsError> {
+        let ssid = heapless::String::<32>::from_str(ssid)
+            .map_err(|_| WifiError::Unknown(format!("SSID '{}' is too long", ssid)))?;
+
+        if self.wifi.is_started()? {
+            self.wifi.stop()?;
+        }
+
+        self.wifi
+            .set_configuration(&Configuration::AccessPoint(AccessPointConfiguration {
+                ssid,
+                auth_method: AuthMethod::None,
+                ..Default::default()
+            }))?;
+        self.wifi.start()?;
+        self.wifi.wait_netif_up()?;
+
+        Ok(self.wifi.wifi().ap_netif().get_ip_info()?.ip)
+    }
+}
@@ -0,0 +1,80 @@
+use core::ops::Not;
+use std::time::Duration;
+
+use crate::error::PinError;
+
+/// Logic level of a GPIO pin.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Level {
+    Low,
+    High,
+}
+
+impl From<bool> for Level {
+    fn from(high: bool) -> Self {
+        if high {
+            Level::High
+        } else {
+            Level::Low
+        }
+    }
+}
+
+impl From<Level> for bool {
+    fn from(level: Level) -> Self {
+        level == Level::High
+    }
+}
+
+impl Not for Level {
+    type Output = Level;
+
+    fn not(self) -> Self::Output {
+        match self {
+            Level::Low => Level::High,
+            Level::High => Level::Low,
+        }
+    }
+}
+
+/// Internal pull resistor configuration for an input pin.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Pull {
+    Floating,
+    Up,
+    Down,
+    UpDown,
+}
+
+/// A pin configured as an input.
+pub trait InputPin: Send {
+    /// The GPIO number of this pin.
+    fn pin(&self) -> u8;
+
+    /// Read the current level of the pin.
+    fn get_level(&self) -> Level;
+
+    /// Block until the pin is at the given level or the timeout passes, returning whether it reached the level. A
+    /// level that came & went while waiting counts, even if the pin has since returned.
+    ///
+    /// On the device this waits on a pin interrupt, which also wakes the CPU from light sleep.
+    fn wait_for_level(&mut self, level: Level, timeout: Duration) -> Result<bool, PinError>;
+}
+
+/// A pin configured as an output.
+pub trait OutputPin: Send {
+    /// The GPIO number of this pin.
+    fn pin(&self) -> u8;
+
+    /// Drive the pin to the given level.
+    fn set_level(&mut self, level: Level) -> Result<(), PinError>;
+}
+
+/// Creates pin drivers. Pin ownership is tracked by the `PinManager`, not the driver.
+pub trait Gpio: Send + Sync {
+    /// Configure a pin as an input.
+    fn input(&self, pin: u8, pull: Pull) -> Result<Box<dyn InputPin>, PinError>;
+
+    /// Configure a pin as an output, driven to the given initial level.
+    fn output(&self, pin: u8, level: Level) -> Result<Box<dyn OutputPin>, PinError>;
+}
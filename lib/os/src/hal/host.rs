@@ -0,0 +1,1025 @@
+//! Simulated backend for running the OS on a development machine.
+//!
+//! Every peripheral is backed by in-memory state. Handles returned by the simulator share that state with the
+//! drivers given to the kernel, so a test can drive inputs, drop the WiFi link or inspect flash contents while the
+//! kernel is running.
+
+use std::collections::HashMap;
+use std::mem;
+use std::net::Ipv4Addr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use embedded_svc::ipv4::{Mask, Subnet};
+
+use crate::error::{FlashError, OsError, PinError, WifiError};
+use crate::hal::clock::SystemClock;
+use crate::hal::gpio::{Gpio, InputPin, Level, OutputPin, Pull};
+use crate::hal::http::{Download, HttpHandler, HttpServer, Request, Response, MAX_BODY_LEN};
+use crate::hal::ota::{ImageState, OtaDriver, OtaUpdate, Slot};
+use crate::hal::storage::Storage;
+use crate::hal::wifi::{ClientConfiguration, IpInfo, ScanResult, WifiDriver, WifiEvent};
+use crate::hal::{
+    Clock, Core, HeapStats, Platform, PowerConfig, ResetReason, TaskId, ThreadOptions, WakeCause,
+    WakeSource, Watchdog, RTC_MEMORY_LEN,
+};
+use crate::physical::hardware;
+
+pub struct HostPlatform {
+    partitions: Mutex<HashMap<String, MemoryStorage>>,
+    gpio: Arc<SimGpio>,
+    wifi: SimWifi,
+    wifi_taken: Mutex<bool>,
+    http: SimHttp,
+    ota: SimOta,
+    ota_taken: Mutex<bool>,
+    clock: Arc<dyn Clock>,
+    seed: Mutex<u32>,
+    reset_reason: ResetReason,
+    watchdog: Arc<SimWatchdog>,
+    power: Mutex<Option<PowerConfig>>,
+    wake_sources: Mutex<Vec<WakeSource>>,
+    wake_cause: Option<WakeCause>,
+    rtc: Arc<Mutex<[u8; RTC_MEMORY_LEN]>>,
+}
+
+impl HostPlatform {
+    pub fn new() -> Self {
+        Self {
+            partitions: Mutex::new(HashMap::new()),
+            gpio: Arc::new(SimGpio::new()),
+            wifi: SimWifi::new(),
+            wifi_taken: Mutex::new(false),
+            http: SimHttp::default(),
+            ota: SimOta::new(),
+            ota_taken: Mutex::new(false),
+            clock: Arc::new(SystemClock::new()),
+            seed: Mutex::new(0x9e37_79b9),
+            reset_reason: ResetReason::PowerOn,
+            watchdog: Arc::new(SimWatchdog::default()),
+            power: Mutex::new(None),
+            wake_sources: Mutex::new(vec![]),
+            wake_cause: None,
+            rtc: Arc::new(Mutex::new([0; RTC_MEMORY_LEN])),
+        }
+    }
+
+    /// Replace the system clock, typically with a `ManualClock`.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Simulate booting after the given kind of reset.
+    pub fn with_reset_reason(mut self, reason: ResetReason) -> Self {
+        self.reset_reason = reason;
+        self
+    }
+
+    /// Handle to the simulated GPIO bank.
+    /// Threads are numbered in the order they first ask for their ID.
+    fn task() -> TaskId {
+        static NEXT: AtomicUsize = AtomicUsize::new(1);
+        thread_local! {
+            static TASK: TaskId = TaskId(NEXT.fetch_add(1, Ordering::Relaxed));
+        }
+
+        TASK.with(|t| *t)
+    }
+
+    /// Boot as if woken from deep sleep.
+    pub fn with_wake_cause(mut self, cause: WakeCause) -> Self {
+        self.reset_reason = ResetReason::DeepSleep;
+        self.wake_cause = Some(cause);
+        self
+    }
+
+    /// Share RTC memory with another platform, as kept across deep sleep.
+    pub fn with_rtc_of(mut self, other: &HostPlatform) -> Self {
+        self.rtc = other.rtc.clone();
+        self
+    }
+
+    /// The wake sources last armed.
+    pub fn wake_sources(&self) -> Vec<WakeSource> {
+        self.wake_sources.lock().unwrap().clone()
+    }
+
+    /// The power configuration last applied.
+    pub fn power_config(&self) -> Option<PowerConfig> {
+        *self.power.lock().unwrap()
+    }
+
+    pub fn watchdog_sim(&self) -> Arc<SimWatchdog> {
+        self.watchdog.clone()
+    }
+
+    pub fn gpio_sim(&self) -> Arc<SimGpio> {
+        self.gpio.clone()
+    }
+
+    /// Handle to the simulated WiFi network.
+    pub fn wifi_sim(&self) -> SimWifi {
+        self.wifi.clone()
+    }
+
+    /// Handle to the simulated HTTP servers.
+    pub fn http_sim(&self) -> SimHttp {
+        self.http.clone()
+    }
+
+    /// Handle to the simulated app partitions.
+    pub fn ota_sim(&self) -> SimOta {
+        self.ota.clone()
+    }
+
+    /// Handle to a simulated flash namespace, shared with any `Storage` opened on it.
+    pub fn storage_sim(&self, partition: &str, namespace: &str) -> MemoryStorage {
+        let mut partitions = self.partitions.lock().unwrap();
+        partitions
+            .entry(format!("{}/{}", partition, namespace))
+            .or_default()
+            .clone()
+    }
+}
+
+impl Default for HostPlatform {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Platform for HostPlatform {
+    fn storage(&self, partition: &str, namespace: &str) -> Result<Box<dyn Storage>, FlashError> {
+        Ok(Box::new(self.storage_sim(partition, namespace)))
+    }
+
+    fn gpio(&self) -> Arc<dyn Gpio> {
+        self.gpio.clone()
+    }
+
+    fn wifi(&self) -> Result<Box<dyn WifiDriver>, OsError> {
+        let mut taken = self.wifi_taken.lock().unwrap();
+        if *taken {
+            return Err(WifiError::Unknown("WiFi modem already taken".into()).into());
+        }
+        *taken = true;
+
+        Ok(Box::new(self.wifi.clone()))
+    }
+
+    fn http_server(&self, port: u16, handler: HttpHandler) -> Result<Box<dyn HttpServer>, OsError> {
+        let mut servers = self.http.servers.lock().unwrap();
+        if servers.contains_key(&port) {
+            return Err(OsError::Generic(format!("Port {} already in use", port)));
+        }
+        servers.insert(port, handler);
+
+        Ok(Box::new(SimHttpServer {
+            port,
+            http: self.http.clone(),
+        }))
+    }
+
+    fn download(&self, url: &str) -> Result<Box<dyn Download>, OsError> {
+        let body = self.http.files.lock().unwrap().get(url).cloned();
+        let body = body.ok_or_else(|| OsError::Generic(format!("HTTP 404 fetching {}", url)))?;
+
+        Ok(Box::new(SimDownload { body, pos: 0 }))
+    }
+
+    fn ota(&self) -> Result<Box<dyn OtaDriver>, OsError> {
+        let mut taken = self.ota_taken.lock().unwrap();
+        if *taken {
+            return Err(OsError::Generic("OTA driver already taken".into()));
+        }
+        *taken = true;
+
+        Ok(Box::new(self.ota.clone()))
+    }
+
+    fn clock(&self) -> Arc<dyn Clock> {
+        self.clock.clone()
+    }
+
+    fn random(&self) -> u32 {
+        // xorshift32, deterministic so that tests are repeatable
+        let mut seed = self.seed.lock().unwrap();
+        *seed ^= *seed << 13;
+        *seed ^= *seed >> 17;
+        *seed ^= *seed << 5;
+        *seed
+    }
+
+    fn current_core(&self) -> Core {
+        Core::Core0
+    }
+
+    fn spawn(
+        &self,
+        _options: ThreadOptions,
+        f: Box<dyn FnOnce() + Send>,
+    ) -> Result<JoinHandle<()>, OsError> {
+        Ok(std::thread::Builder::new().spawn(f)?)
+    }
+
+    fn current_task(&self) -> TaskId {
+        Self::task()
+    }
+
+    fn stack_high_water(&self, _task: TaskId) -> Option<usize> {
+        None
+    }
+
+    fn heap_stats(&self) -> Option<HeapStats> {
+        None
+    }
+
+    fn watchdog(&self) -> Arc<dyn Watchdog> {
+        self.watchdog.clone()
+    }
+
+    fn configure_power(&self, config: &PowerConfig) -> Result<(), OsError> {
+        *self.power.lock().unwrap() = Some(*config);
+        Ok(())
+    }
+
+    fn set_wake_sources(&self, sources: &[WakeSource]) -> Result<(), OsError> {
+        *self.wake_sources.lock().unwrap() = sources.to_vec();
+        Ok(())
+    }
+
+    fn deep_sleep(&self) -> ! {
+        panic!("Device deep sleep requested");
+    }
+
+    fn wake_cause(&self) -> Option<WakeCause> {
+        self.wake_cause.clone()
+    }
+
+    fn read_rtc(&self) -> [u8; RTC_MEMORY_LEN] {
+        *self.rtc.lock().unwrap()
+    }
+
+    fn write_rtc(&self, data: &[u8; RTC_MEMORY_LEN]) {
+        *self.rtc.lock().unwrap() = *data;
+    }
+
+    fn reset_reason(&self) -> ResetReason {
+        self.reset_reason
+    }
+
+    fn restart(&self) -> ! {
+        panic!("Device restart requested");
+    }
+}
+
+/// Task watchdog that never fires, recording the tasks subscribed to it.
+#[derive(Default)]
+pub struct SimWatchdog {
+    subscribed: Mutex<Vec<TaskId>>,
+}
+
+impl SimWatchdog {
+    /// Number of tasks subscribed.
+    pub fn subscribed(&self) -> usize {
+        self.subscribed.lock().unwrap().len()
+    }
+}
+
+impl Watchdog for SimWatchdog {
+    fn timeout(&self) -> Duration {
+        Duration::from_secs(5)
+    }
+
+    fn subscribe(&self) -> Result<(), OsError> {
+        let task = HostPlatform::task();
+        let mut subscribed = self.subscribed.lock().unwrap();
+        if subscribed.contains(&task) {
+            return Err(OsError::Generic("Task already subscribed".into()));
+        }
+        subscribed.push(task);
+        Ok(())
+    }
+
+    fn unsubscribe(&self) -> Result<(), OsError> {
+        let task = HostPlatform::task();
+        self.subscribed.lock().unwrap().retain(|t| *t != task);
+        Ok(())
+    }
+
+    fn feed(&self) -> Result<(), OsError> {
+        match self
+            .subscribed
+            .lock()
+            .unwrap()
+            .contains(&HostPlatform::task())
+        {
+            true => Ok(()),
+            false => Err(OsError::Generic("Task not subscribed".into())),
+        }
+    }
+}
+
+/// A clock that only moves when told to.
+#[derive(Default)]
+pub struct ManualClock {
+    now: Mutex<Duration>,
+}
+
+impl ManualClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.now.lock().unwrap() += by;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Duration {
+        *self.now.lock().unwrap()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Str(String),
+    Blob(Vec<u8>),
+    U8(u8),
+    I8(i8),
+    U16(u16),
+    I16(i16),
+    U32(u32),
+    I32(i32),
+    U64(u64),
+    I64(i64),
+}
+
+/// In-memory key-value store. Clones share the same contents.
+#[derive(Clone, Default)]
+pub struct MemoryStorage {
+    values: Arc<Mutex<HashMap<String, Value>>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn get(&self, key: &str) -> Option<Value> {
+        self.values.lock().unwrap().get(key).cloned()
+    }
+
+    /// Like NVS, a key holding a value of one type can't be overwritten with another until it is removed.
+    fn set(&self, key: &str, value: Value) -> Result<(), FlashError> {
+        let mut values = self.values.lock().unwrap();
+        match values.get(key) {
+            Some(v) if mem::discriminant(v) != mem::discriminant(&value) => Err(
+                FlashError::Generic(format!("Type mismatch writing '{}'", key)),
+            ),
+            _ => {
+                values.insert(key.to_string(), value);
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Implements the integer getter & setter of `Storage` for the given `Value` variant.
+macro_rules! memory_int {
+    ($t:ty, $variant:ident, $get:ident, $set:ident) => {
+        fn $get(&self, key: &str) -> Result<Option<$t>, FlashError> {
+            match self.get(key) {
+                Some(Value::$variant(v)) => Ok(Some(v)),
+                Some(_) => Err(type_mismatch(key)),
+                None => Ok(None),
+            }
+        }
+
+        fn $set(&mut self, key: &str, value: $t) -> Result<(), FlashError> {
+            self.set(key, Value::$variant(value))
+        }
+    };
+}
+
+fn type_mismatch(key: &str) -> FlashError {
+    FlashError::Generic(format!("Type mismatch reading '{}'", key))
+}
+
+impl Storage for MemoryStorage {
+    fn contains(&self, key: &str) -> Result<bool, FlashError> {
+        Ok(self.values.lock().unwrap().contains_key(key))
+    }
+
+    fn remove(&mut self, key: &str) -> Result<bool, FlashError> {
+        Ok(self.values.lock().unwrap().remove(key).is_some())
+    }
+
+    fn get_str(&self, key: &str) -> Result<Option<String>, FlashError> {
+        match self.get(key) {
+            Some(Value::Str(s)) => Ok(Some(s)),
+            Some(_) => Err(type_mismatch(key)),
+            None => Ok(None),
+        }
+    }
+
+    fn set_str(&mut self, key: &str, value: &str) -> Result<(), FlashError> {
+        self.set(key, Value::Str(value.to_string()))
+    }
+
+    fn get_blob(&self, key: &str) -> Result<Option<Vec<u8>>, FlashError> {
+        match self.get(key) {
+            Some(Value::Blob(b)) => Ok(Some(b)),
+            Some(_) => Err(type_mismatch(key)),
+            None => Ok(None),
+        }
+    }
+
+    fn set_blob(&mut self, key: &str, value: &[u8]) -> Result<(), FlashError> {
+        self.set(key, Value::Blob(value.to_vec()))
+    }
+
+    memory_int!(u8, U8, get_u8, set_u8);
+    memory_int!(i8, I8, get_i8, set_i8);
+    memory_int!(u16, U16, get_u16, set_u16);
+    memory_int!(i16, I16, get_i16, set_i16);
+    memory_int!(u32, U32, get_u32, set_u32);
+    memory_int!(i32, I32, get_i32, set_i32);
+    memory_int!(u64, U64, get_u64, set_u64);
+    memory_int!(i64, I64, get_i64, set_i64);
+}
+
+#[derive(Clone, Copy, Default)]
+struct SimPinState {
+    driven: Option<Level>,
+    pull: Option<Pull>,
+    /// Number of times the level of the pin has changed.
+    edges: u32,
+    /// Configuring the pin fails.
+    faulty: bool,
+}
+
+/// Pin states, shared by the bank & its pins.
+struct SimBank {
+    pins: Mutex<[SimPinState; hardware::MAX_PINS as usize]>,
+    changed: Condvar,
+}
+
+impl SimBank {
+    /// Modify the state of a pin, waking any task waiting for its level to change.
+    fn update(&self, pin: u8, f: impl FnOnce(&mut SimPinState)) {
+        let mut pins = self.pins.lock().unwrap();
+        let state = &mut pins[pin as usize];
+        let before = level_of(state);
+        f(state);
+
+        if level_of(state) != before {
+            state.edges += 1;
+            self.changed.notify_all();
+        }
+    }
+
+    fn level(&self, pin: u8) -> Level {
+        level_of(&self.pins.lock().unwrap()[pin as usize])
+    }
+}
+
+/// Simulated GPIO bank.
+///
+/// Inputs read the level driven onto the pin (by an output or by `SimGpio::drive`), falling back to the pull
+/// resistor when nothing is driving it.
+pub struct SimGpio {
+    bank: Arc<SimBank>,
+}
+
+impl SimGpio {
+    pub fn new() -> Self {
+        Self {
+            bank: Arc::new(SimBank {
+                pins: Mutex::new([SimPinState::default(); hardware::MAX_PINS as usize]),
+                changed: Condvar::new(),
+            }),
+        }
+    }
+
+    /// Drive a pin externally, eg. a switch closing.
+    pub fn drive(&self, pin: u8, level: Level) {
+        self.bank.update(pin, |s| s.driven = Some(level));
+    }
+
+    /// Stop driving a pin, leaving it to the pull resistor.
+    pub fn release(&self, pin: u8) {
+        self.bank.update(pin, |s| s.driven = None);
+    }
+
+    /// The level currently seen on a pin.
+    pub fn level(&self, pin: u8) -> Level {
+        self.bank.level(pin)
+    }
+
+    /// Fail to configure a pin from now on, as the driver would with a fault.
+    pub fn fail(&self, pin: u8) {
+        self.bank.update(pin, |s| s.faulty = true);
+    }
+
+    fn check(&self, pin: u8) -> Result<(), PinError> {
+        match self.bank.pins.lock().unwrap()[pin as usize].faulty {
+            true => Err(PinError::Generic {
+                pin,
+                error: "Simulated fault".into(),
+            }),
+            false => Ok(()),
+        }
+    }
+}
+
+impl Default for SimGpio {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn level_of(state: &SimPinState) -> Level {
+    match (state.driven, state.pull) {
+        (Some(level), _) => level,
+        (None, Some(Pull::Up)) => Level::High,
+        _ => Level::Low,
+    }
+}
+
+impl Gpio for SimGpio {
+    fn input(&self, pin: u8, pull: Pull) -> Result<Box<dyn InputPin>, PinError> {
+        self.check(pin)?;
+        self.bank.update(pin, |s| s.pull = Some(pull));
+        Ok(Box::new(SimPin {
+            pin,
+            bank: self.bank.clone(),
+        }))
+    }
+
+    fn output(&self, pin: u8, level: Level) -> Result<Box<dyn OutputPin>, PinError> {
+        self.check(pin)?;
+        let mut out = SimPin {
+            pin,
+            bank: self.bank.clone(),
+        };
+        out.set_level(level)?;
+        Ok(Box::new(out))
+    }
+}
+
+pub struct SimPin {
+    pin: u8,
+    bank: Arc<SimBank>,
+}
+
+impl InputPin for SimPin {
+    fn pin(&self) -> u8 {
+        self.pin
+    }
+
+    fn get_level(&self) -> Level {
+        self.bank.level(self.pin)
+    }
+
+    fn wait_for_level(&mut self, level: Level, timeout: Duration) -> Result<bool, PinError> {
+        let pins = self.bank.pins.lock().unwrap();
+        let edges = pins[self.pin as usize].edges;
+
+        // Any change while waiting counts, as a pulse may have come & gone before this task runs again
+        let (pins, _) = self
+            .bank
+            .changed
+            .wait_timeout_while(pins, timeout, |pins| {
+                let state = &pins[self.pin as usize];
+                level_of(state) != level && state.edges == edges
+            })
+            .unwrap();
+
+        let state = &pins[self.pin as usize];
+        Ok(level_of(state) == level || state.edges != edges)
+    }
+}
+
+impl OutputPin for SimPin {
+    fn pin(&self) -> u8 {
+        self.pin
+    }
+
+    fn set_level(&mut self, level: Level) -> Result<(), PinError> {
+        self.bank.update(self.pin, |s| s.driven = Some(level));
+        Ok(())
+    }
+}
+
+struct SimWifiState {
+    in_range: bool,
+    access_points: Vec<ScanResult>,
+    started: bool,
+    connected: bool,
+    config: Option<ClientConfiguration>,
+    hosting: Option<String>,
+    ip: IpInfo,
+    subscribers: Vec<Sender<WifiEvent>>,
+}
+
+/// Simulated WiFi network & station driver. Clones share the same network.
+///
+/// With no access points defined, any configuration connects while the network is in range. Once access points
+/// are added, only configurations matching one of them (by SSID, and BSSID if pinned) connect.
+#[derive(Clone)]
+pub struct SimWifi {
+    state: Arc<Mutex<SimWifiState>>,
+}
+
+impl SimWifi {
+    pub fn new() -> Self {
+        Self {
+            state: Arc::new(Mutex::new(SimWifiState {
+                in_range: true,
+                access_points: vec![],
+                started: false,
+                connected: false,
+                config: None,
+                hosting: None,
+                subscribers: vec![],
+                ip: IpInfo {
+                    ip: Ipv4Addr::new(192, 168, 1, 100),
+                    subnet: Subnet {
+                        gateway: Ipv4Addr::new(192, 168, 1, 1),
+                        mask: Mask(24),
+                    },
+                    dns: None,
+                    secondary_dns: None,
+                },
+            })),
+        }
+    }
+
+    /// Set whether the access point can be reached. Taking it out of range drops any connection.
+    pub fn set_in_range(&self, in_range: bool) {
+        let mut state = self.state.lock().unwrap();
+        state.in_range = in_range;
+        if !in_range {
+            state.drop_link(REASON_BEACON_TIMEOUT);
+        }
+    }
+
+    /// Replace the access points in range. Drops the connection if its access point has gone.
+    pub fn set_access_points(&self, access_points: Vec<ScanResult>) {
+        let mut state = self.state.lock().unwrap();
+        state.access_points = access_points;
+        if !state.can_join() {
+            state.drop_link(REASON_BEACON_TIMEOUT);
+        }
+    }
+
+    /// SSID of the network hosted in access point mode.
+    pub fn hosted_access_point(&self) -> Option<String> {
+        self.state.lock().unwrap().hosting.clone()
+    }
+
+    /// The configuration last given to the driver.
+    pub fn configuration(&self) -> Option<ClientConfiguration> {
+        self.state.lock().unwrap().config.clone()
+    }
+}
+
+/// Reason codes reported by the simulated driver, matching ESP-IDF.
+const REASON_ASSOC_LEAVE: u16 = 8;
+const REASON_BEACON_TIMEOUT: u16 = 200;
+const REASON_NO_AP_FOUND: u16 = 201;
+
+impl SimWifiState {
+    fn emit(&mut self, event: WifiEvent) {
+        self.subscribers.retain(|s| s.send(event).is_ok());
+    }
+
+    fn drop_link(&mut self, reason: u16) {
+        if self.connected {
+            self.connected = false;
+            self.emit(WifiEvent::Disconnected { reason });
+        }
+    }
+
+    fn can_join(&self) -> bool {
+        let config = match (&self.config, self.in_range) {
+            (Some(c), true) => c,
+            (None, true) => return self.access_points.is_empty(),
+            (_, false) => return false,
+        };
+
+        self.access_points.is_empty()
+            || self.access_points.iter().any(|ap| {
+                ap.ssid == config.ssid.as_str() && config.bssid.map_or(true, |b| b == ap.bssid)
+            })
+    }
+}
+
+impl Default for SimWifi {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl WifiDriver for SimWifi {
+    fn set_configuration(&mut self, config: &ClientConfiguration) -> Result<(), OsError> {
+        self.state.lock().unwrap().config = Some(config.clone());
+        Ok(())
+    }
+
+    fn start(&mut self) -> Result<(), OsError> {
+        self.state.lock().unwrap().started = true;
+        Ok(())
+    }
+
+    fn scan(&mut self) -> Result<Vec<ScanResult>, OsError> {
+        let state = self.state.lock().unwrap();
+        if !state.started {
+            return Err(WifiError::NotInitialised.into());
+        }
+
+        match state.in_range {
+            true => Ok(state.access_points.clone()),
+            false => Ok(vec![]),
+        }
+    }
+
+    fn connect(&mut self) -> Result<(), OsError> {
+        let mut state = self.state.lock().unwrap();
+        if !state.started {
+            return Err(WifiError::NotInitialised.into());
+        }
+        if !state.can_join() {
+            state.emit(WifiEvent::Disconnected {
+                reason: REASON_NO_AP_FOUND,
+            });
+            return Err(WifiError::Disconnected.into());
+        }
+        state.connected = true;
+        state.emit(WifiEvent::Connected);
+        Ok(())
+    }
+
+    fn disconnect(&mut self) -> Result<(), OsError> {
+        self.state.lock().unwrap().drop_link(REASON_ASSOC_LEAVE);
+        Ok(())
+    }
+
+    fn is_connected(&self) -> Result<bool, OsError> {
+        Ok(self.state.lock().unwrap().connected)
+    }
+
+    fn subscribe(&mut self) -> Result<Receiver<WifiEvent>, OsError> {
+        let (tx, rx) = mpsc::channel();
+        self.state.lock().unwrap().subscribers.push(tx);
+        Ok(rx)
+    }
+
+    /// Signal strength of the access point matching the configuration, or -50 dBm if none are defined.
+    fn rssi(&self) -> Result<i8, OsError> {
+        let state = self.state.lock().unwrap();
+        if !state.connected {
+            return Err(WifiError::Disconnected.into());
+        }
+
+        let ssid = state.config.as_ref().map(|c| c.ssid.as_str());
+        Ok(state
+            .access_points
+            .iter()
+            .find(|ap| Some(ap.ssid.as_str()) == ssid)
+            .map_or(-50, |ap| ap.rssi))
+    }
+
+    fn start_access_point(&mut self, ssid: &str) -> Result<Ipv4Addr, OsError> {
+        let mut state = self.state.lock().unwrap();
+        state.drop_link(REASON_ASSOC_LEAVE);
+        state.started = true;
+        state.hosting = Some(ssid.to_string());
+        Ok(Ipv4Addr::new(192, 168, 71, 1))
+    }
+
+    fn wait_netif_up(&mut self) -> Result<(), OsError> {
+        match self.state.lock().unwrap().connected {
+            true => Ok(()),
+            false => Err(WifiError::NoIpAllocation.into()),
+        }
+    }
+
+    fn ip_info(&self) -> Result<IpInfo, OsError> {
+        Ok(self.state.lock().unwrap().ip)
+    }
+}
+
+/// Simulated HTTP servers, keyed by port. Clones share the same servers.
+#[derive(Clone, Default)]
+pub struct SimHttp {
+    servers: Arc<Mutex<HashMap<u16, HttpHandler>>>,
+    files: Arc<Mutex<HashMap<String, Vec<u8>>>>,
+}
+
+impl SimHttp {
+    /// Serve `body` to downloads of the given URL.
+    pub fn serve_file(&self, url: &str, body: impl Into<Vec<u8>>) {
+        self.files
+            .lock()
+            .unwrap()
+            .insert(url.to_string(), body.into());
+    }
+
+    /// Send a request to the server on the given port, if one is running.
+    pub fn request(&self, port: u16, request: &Request) -> Option<Response> {
+        // Don't hold the lock while handling, the handler may stop the server
+        let handler = self.servers.lock().unwrap().get(&port).cloned();
+        handler.map(|h| match request.body.len() > MAX_BODY_LEN {
+            true => Response::too_large(),
+            false => h(request),
+        })
+    }
+}
+
+struct SimHttpServer {
+    port: u16,
+    http: SimHttp,
+}
+
+impl HttpServer for SimHttpServer {}
+
+impl Drop for SimHttpServer {
+    fn drop(&mut self) {
+        self.http.servers.lock().unwrap().remove(&self.port);
+    }
+}
+
+struct SimDownload {
+    body: Vec<u8>,
+    pos: usize,
+}
+
+impl Download for SimDownload {
+    fn content_length(&self) -> Option<usize> {
+        Some(self.body.len())
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, OsError> {
+        let len = buf.len().min(self.body.len() - self.pos);
+        buf[..len].copy_from_slice(&self.body[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+/// Simulated app partitions, a factory slot & a single OTA slot as on the device.
+///
+/// Restarts are not simulated, the test calls `restart` to boot the selected slot.
+#[derive(Clone)]
+pub struct SimOta {
+    state: Arc<Mutex<SimOtaState>>,
+}
+
+struct SimOtaState {
+    running: Slot,
+    boot: String,
+    /// Contents of the OTA slot.
+    image: Vec<u8>,
+    /// An image has been written but not yet booted.
+    pending: bool,
+}
+
+impl SimOta {
+    pub const FACTORY: &'static str = "factory";
+    pub const OTA_0: &'static str = "ota_0";
+
+    pub fn new() -> Self {
+        SimOta {
+            state: Arc::new(Mutex::new(SimOtaState {
+                running: Slot {
+                    label: Self::FACTORY.into(),
+                    state: ImageState::Factory,
+                },
+                boot: Self::FACTORY.into(),
+                image: vec![],
+                pending: false,
+            })),
+        }
+    }
+
+    /// Contents of the OTA slot.
+    pub fn image(&self) -> Vec<u8> {
+        self.state.lock().unwrap().image.clone()
+    }
+
+    /// Label of the slot that boots on the next restart.
+    pub fn boot_slot(&self) -> String {
+        self.state.lock().unwrap().boot.clone()
+    }
+
+    /// Simulate a restart into the boot slot. A newly written image boots pending verification, while an image
+    /// that was never confirmed is rolled back, as the bootloader does.
+    pub fn restart(&self) {
+        let mut state = self.state.lock().unwrap();
+
+        if state.running.state == ImageState::PendingVerify && state.boot == state.running.label {
+            state.boot = Self::FACTORY.into();
+        }
+
+        let label = state.boot.clone();
+        let image_state = if label == Self::FACTORY {
+            ImageState::Factory
+        } else if state.pending {
+            ImageState::PendingVerify
+        } else {
+            ImageState::Valid
+        };
+
+        state.pending = false;
+        state.running = Slot {
+            label,
+            state: image_state,
+        };
+    }
+}
+
+impl Default for SimOta {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl OtaDriver for SimOta {
+    fn running_slot(&self) -> Result<Slot, OsError> {
+        Ok(self.state.lock().unwrap().running.clone())
+    }
+
+    fn update_slot(&self) -> Result<Slot, OsError> {
+        // As the device, the only OTA slot is returned even when it is running
+        let state = self.state.lock().unwrap();
+        Ok(match state.running.label == Self::OTA_0 {
+            true => state.running.clone(),
+            false => Slot {
+                label: Self::OTA_0.into(),
+                state: ImageState::Unknown,
+            },
+        })
+    }
+
+    fn begin(&mut self) -> Result<Box<dyn OtaUpdate + '_>, OsError> {
+        let mut state = self.state.lock().unwrap();
+        if state.running.label == Self::OTA_0 {
+            return Err(OsError::Generic("Can't write to the running slot".into()));
+        }
+        state.image.clear();
+
+        Ok(Box::new(SimOtaUpdate {
+            ota: self.clone(),
+            buffer: vec![],
+        }))
+    }
+
+    fn mark_valid(&mut self) -> Result<(), OsError> {
+        let mut state = self.state.lock().unwrap();
+        if state.running.state == ImageState::PendingVerify {
+            state.running.state = ImageState::Valid;
+        }
+        Ok(())
+    }
+
+    fn rollback(&mut self) -> Result<(), OsError> {
+        {
+            let mut state = self.state.lock().unwrap();
+            if state.running.label == Self::FACTORY {
+                return Err(OsError::Generic("No image to roll back to".into()));
+            }
+            state.running.state = ImageState::Invalid;
+            state.boot = Self::FACTORY.into();
+        }
+
+        self.restart();
+        Ok(())
+    }
+}
+
+struct SimOtaUpdate {
+    ota: SimOta,
+    buffer: Vec<u8>,
+}
+
+impl OtaUpdate for SimOtaUpdate {
+    fn write(&mut self, data: &[u8]) -> Result<(), OsError> {
+        self.buffer.extend_from_slice(data);
+        Ok(())
+    }
+
+    fn complete(self: Box<Self>) -> Result<(), OsError> {
+        let mut state = self.ota.state.lock().unwrap();
+        state.image = self.buffer;
+        state.boot = SimOta::OTA_0.into();
+        state.pending = true;
+        Ok(())
+    }
+}
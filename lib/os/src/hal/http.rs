@@ -0,0 +1,58 @@
+use std::sync::Arc;
+
+use crate::error::OsError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Method {
+    Get,
+    Post,
+}
+
+/// An HTTP request, with the body read in full.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Request {
+    pub method: Method,
+    /// Path & query string, eg. "/save?x=1".
+    pub uri: String,
+    pub body: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Response {
+    pub status: u16,
+    pub content_type: &'static str,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn html(status: u16, body: impl Into<String>) -> Self {
+        Response {
+            status,
+            content_type: "text/html; charset=utf-8",
+            body: body.into().into_bytes(),
+        }
+    }
+
+    /// Reply to a request with a body beyond `MAX_BODY_LEN`, which is not passed to the handler.
+    pub fn too_large() -> Self {
+        Response::html(413, "Request body too large")
+    }
+}
+
+/// Handles every request made to a server, regardless of the path.
+pub type HttpHandler = Arc<dyn Fn(&Request) -> Response + Send + Sync>;
+
+/// A running HTTP server. The server stops when dropped.
+pub trait HttpServer: Send {}
+
+/// Request bodies beyond this size are rejected.
+pub const MAX_BODY_LEN: usize = 4096;
+
+/// A response body being fetched with an HTTP(S) GET.
+pub trait Download: Send {
+    /// Size of the body, if the server sent one.
+    fn content_length(&self) -> Option<usize>;
+
+    /// Read the next chunk of the body, returning 0 once it has all been read.
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, OsError>;
+}
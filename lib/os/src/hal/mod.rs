@@ -0,0 +1,183 @@
+//! Hardware abstraction layer.
+//!
+//! The kernel and its services only talk to the device through the traits in this module. The ESP-IDF backend is
+//! enabled with the `esp32s3` feature, while the `host` feature provides a simulated backend so that the OS can be
+//! built & tested on a development machine.
+
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::{FlashError, OsError};
+
+pub mod clock;
+pub mod gpio;
+pub mod http;
+pub mod ota;
+pub mod sleep;
+pub mod storage;
+pub mod watchdog;
+pub mod wifi;
+
+#[cfg(feature = "esp32s3")]
+pub mod esp32s3;
+#[cfg(feature = "host")]
+pub mod host;
+
+#[cfg(not(any(feature = "esp32s3", feature = "host")))]
+compile_error!("inu-os requires a hardware backend, enable either the `esp32s3` or `host` feature");
+
+pub use clock::Clock;
+pub use gpio::Gpio;
+pub use http::{Download, HttpHandler, HttpServer};
+pub use ota::OtaDriver;
+pub use sleep::{WakeCause, WakeLevel, WakeSource, RTC_MEMORY_LEN};
+pub use storage::Storage;
+pub use watchdog::Watchdog;
+pub use wifi::WifiDriver;
+
+/// A CPU core that a task may be pinned to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Core {
+    Core0,
+    Core1,
+}
+
+/// Why the device last reset.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ResetReason {
+    PowerOn = 1,
+    /// A restart requested by the firmware.
+    Software = 2,
+    Panic = 3,
+    /// A task, interrupt or hardware watchdog expired.
+    Watchdog = 4,
+    Brownout = 5,
+    /// The reset pin was pulled.
+    ExternalPin = 6,
+    DeepSleep = 7,
+    Unknown = 0,
+}
+
+impl From<u8> for ResetReason {
+    fn from(code: u8) -> Self {
+        match code {
+            1 => ResetReason::PowerOn,
+            2 => ResetReason::Software,
+            3 => ResetReason::Panic,
+            4 => ResetReason::Watchdog,
+            5 => ResetReason::Brownout,
+            6 => ResetReason::ExternalPin,
+            7 => ResetReason::DeepSleep,
+            _ => ResetReason::Unknown,
+        }
+    }
+}
+
+/// Identifies a thread (FreeRTOS task on the device) while it runs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TaskId(pub usize);
+
+/// Heap usage, in bytes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HeapStats {
+    pub free: usize,
+    /// Least free heap since boot.
+    pub min_free: usize,
+    /// Largest block that can currently be allocated.
+    pub largest_block: usize,
+}
+
+/// Scheduling options for a new thread (FreeRTOS task on the device).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ThreadOptions {
+    pub priority: u8,
+    pub core: Option<Core>,
+    pub stack_size: usize,
+}
+
+/// CPU frequency & sleep configuration, applied by ESP-IDF power management.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PowerConfig {
+    /// Clock when busy, in MHz.
+    pub max_mhz: u16,
+    /// Clock when idle, in MHz. Dynamic frequency scaling is off if this is the same as `max_mhz`.
+    pub min_mhz: u16,
+    /// Light sleep when idle, keeping WiFi associated.
+    pub light_sleep: bool,
+}
+
+/// A hardware backend. Each backend hands out the peripherals the kernel needs.
+pub trait Platform: Send + Sync {
+    /// Open a key-value store on the given flash partition & namespace.
+    fn storage(&self, partition: &str, namespace: &str) -> Result<Box<dyn Storage>, FlashError>;
+
+    /// The GPIO controller.
+    fn gpio(&self) -> Arc<dyn Gpio>;
+
+    /// Take the WiFi station driver. This can only be done once.
+    fn wifi(&self) -> Result<Box<dyn WifiDriver>, OsError>;
+
+    /// Start an HTTP server on the given port, passing every request to the handler.
+    fn http_server(&self, port: u16, handler: HttpHandler) -> Result<Box<dyn HttpServer>, OsError>;
+
+    /// Fetch a URL with a GET request. HTTPS servers are checked against the built-in CA bundle.
+    ///
+    /// Fails unless the server responds with 200 OK.
+    fn download(&self, url: &str) -> Result<Box<dyn Download>, OsError>;
+
+    /// Take the OTA driver. This can only be done once.
+    fn ota(&self) -> Result<Box<dyn OtaDriver>, OsError>;
+
+    /// Monotonic clock, measured from boot.
+    fn clock(&self) -> Arc<dyn Clock>;
+
+    /// A random number. Suitable for jitter & identifiers, not for cryptography on the host backend.
+    fn random(&self) -> u32;
+
+    /// The core the calling thread is running on.
+    fn current_core(&self) -> Core;
+
+    /// Spawn a new thread with the given scheduling options.
+    fn spawn(
+        &self,
+        options: ThreadOptions,
+        f: Box<dyn FnOnce() + Send>,
+    ) -> Result<JoinHandle<()>, OsError>;
+
+    /// The calling thread.
+    fn current_task(&self) -> TaskId;
+
+    /// Least free stack space the task has had, in bytes, if known. The task must still be running.
+    fn stack_high_water(&self, task: TaskId) -> Option<usize>;
+
+    /// Heap usage, if known.
+    fn heap_stats(&self) -> Option<HeapStats>;
+
+    /// The task watchdog.
+    fn watchdog(&self) -> Arc<dyn Watchdog>;
+
+    /// Configure CPU frequency scaling & light sleep.
+    fn configure_power(&self, config: &PowerConfig) -> Result<(), OsError>;
+
+    /// Arm the sources that wake the device from deep sleep, replacing any armed before.
+    fn set_wake_sources(&self, sources: &[WakeSource]) -> Result<(), OsError>;
+
+    /// Enter deep sleep until an armed source wakes the device, which then boots afresh.
+    fn deep_sleep(&self) -> !;
+
+    /// What woke the device from deep sleep, if it was asleep.
+    fn wake_cause(&self) -> Option<WakeCause>;
+
+    /// Read RTC memory, which is kept across deep sleep but not a reset.
+    fn read_rtc(&self) -> [u8; RTC_MEMORY_LEN];
+
+    fn write_rtc(&self, data: &[u8; RTC_MEMORY_LEN]);
+
+    /// Why the device last reset.
+    fn reset_reason(&self) -> ResetReason;
+
+    /// Hard restart of the device.
+    fn restart(&self) -> !;
+}
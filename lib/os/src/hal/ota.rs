@@ -0,0 +1,47 @@
+use crate::error::OsError;
+
+/// State of the firmware image in an app slot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageState {
+    /// The factory image, which is never rolled back.
+    Factory,
+    Valid,
+    /// Booting for the first time, the image must be marked valid or it is rolled back on the next restart.
+    PendingVerify,
+    Invalid,
+    Unknown,
+}
+
+/// An app partition.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Slot {
+    pub label: String,
+    pub state: ImageState,
+}
+
+/// Access to the app partitions & the boot selection.
+pub trait OtaDriver: Send {
+    /// The slot the running image was booted from.
+    fn running_slot(&self) -> Result<Slot, OsError>;
+
+    /// The slot the next update will be written to.
+    fn update_slot(&self) -> Result<Slot, OsError>;
+
+    /// Start writing an image to the update slot. Dropping the update before it is complete aborts it.
+    fn begin(&mut self) -> Result<Box<dyn OtaUpdate + '_>, OsError>;
+
+    /// Confirm the running image, cancelling the rollback.
+    fn mark_valid(&mut self) -> Result<(), OsError>;
+
+    /// Mark the running image invalid & restart into the previous one. On the device this does not return unless
+    /// it fails.
+    fn rollback(&mut self) -> Result<(), OsError>;
+}
+
+/// An image being written to the update slot.
+pub trait OtaUpdate {
+    fn write(&mut self, data: &[u8]) -> Result<(), OsError>;
+
+    /// Validate the written image & boot from it on the next restart.
+    fn complete(self: Box<Self>) -> Result<(), OsError>;
+}
@@ -0,0 +1,37 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+/// Bytes of RTC memory kept across deep sleep.
+pub const RTC_MEMORY_LEN: usize = 256;
+
+/// Level of the GPIO wake pins that wakes the device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WakeLevel {
+    /// Any of the pins is high.
+    AnyHigh,
+    /// Any of the pins is low.
+    AnyLow,
+}
+
+/// Something that wakes the device from deep sleep.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WakeSource {
+    /// RTC GPIOs reaching the level (EXT1).
+    Gpio { pins: Vec<u8>, level: WakeLevel },
+    /// Time spent asleep.
+    Timer(Duration),
+    /// A touch pad reading crossing the threshold.
+    Touch { pad: u8, threshold: u32 },
+}
+
+/// What woke the device from deep sleep.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum WakeCause {
+    /// The GPIOs that were at the wake level.
+    Gpio(Vec<u8>),
+    Timer,
+    Touch(u8),
+    /// A source not armed by the OS, such as the ULP coprocessor.
+    Other,
+}
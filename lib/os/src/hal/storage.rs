@@ -0,0 +1,43 @@
+use crate::error::FlashError;
+
+/// A key-value store on a flash partition, modelled on the ESP-IDF NVS API.
+///
+/// Getters return `Ok(None)` when the key does not exist. Reading a key with a getter for a different type than it
+/// was written with is an error.
+pub trait Storage: Send {
+    /// Check if a key exists in the store.
+    fn contains(&self, key: &str) -> Result<bool, FlashError>;
+
+    /// Remove a key, returning true if it existed.
+    fn remove(&mut self, key: &str) -> Result<bool, FlashError>;
+
+    fn get_str(&self, key: &str) -> Result<Option<String>, FlashError>;
+    fn set_str(&mut self, key: &str, value: &str) -> Result<(), FlashError>;
+
+    fn get_blob(&self, key: &str) -> Result<Option<Vec<u8>>, FlashError>;
+    fn set_blob(&mut self, key: &str, value: &[u8]) -> Result<(), FlashError>;
+
+    fn get_u8(&self, key: &str) -> Result<Option<u8>, FlashError>;
+    fn set_u8(&mut self, key: &str, value: u8) -> Result<(), FlashError>;
+
+    fn get_i8(&self, key: &str) -> Result<Option<i8>, FlashError>;
+    fn set_i8(&mut self, key: &str, value: i8) -> Result<(), FlashError>;
+
+    fn get_u16(&self, key: &str) -> Result<Option<u16>, FlashError>;
+    fn set_u16(&mut self, key: &str, value: u16) -> Result<(), FlashError>;
+
+    fn get_i16(&self, key: &str) -> Result<Option<i16>, FlashError>;
+    fn set_i16(&mut self, key: &str, value: i16) -> Result<(), FlashError>;
+
+    fn get_u32(&self, key: &str) -> Result<Option<u32>, FlashError>;
+    fn set_u32(&mut self, key: &str, value: u32) -> Result<(), FlashError>;
+
+    fn get_i32(&self, key: &str) -> Result<Option<i32>, FlashError>;
+    fn set_i32(&mut self, key: &str, value: i32) -> Result<(), FlashError>;
+
+    fn get_u64(&self, key: &str) -> Result<Option<u64>, FlashError>;
+    fn set_u64(&mut self, key: &str, value: u64) -> Result<(), FlashError>;
+
+    fn get_i64(&self, key: &str) -> Result<Option<i64>, FlashError>;
+    fn set_i64(&mut self, key: &str, value: i64) -> Result<(), FlashError>;
+}
@@ -0,0 +1,18 @@
+use std::time::Duration;
+
+use crate::error::OsError;
+
+/// The task watchdog, which resets the device when a subscribed task stops feeding it.
+pub trait Watchdog: Send + Sync {
+    /// Longest a subscribed task may go without feeding the watchdog.
+    fn timeout(&self) -> Duration;
+
+    /// Subscribe the calling task.
+    fn subscribe(&self) -> Result<(), OsError>;
+
+    /// Unsubscribe the calling task.
+    fn unsubscribe(&self) -> Result<(), OsError>;
+
+    /// Feed the watchdog on behalf of the calling task.
+    fn feed(&self) -> Result<(), OsError>;
+}
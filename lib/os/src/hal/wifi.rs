@@ -0,0 +1,63 @@
+pub use embedded_svc::ipv4::IpInfo;
+pub use embedded_svc::wifi::{AuthMethod, ClientConfiguration};
+
+use std::net::Ipv4Addr;
+use std::sync::mpsc::Receiver;
+
+use crate::error::OsError;
+
+/// An access point found by a scan.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScanResult {
+    pub ssid: String,
+    pub bssid: [u8; 6],
+    pub channel: u8,
+    /// Signal strength in dBm.
+    pub rssi: i8,
+    pub auth_method: Option<AuthMethod>,
+}
+
+/// Station link events.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WifiEvent {
+    Connected,
+    /// The link went down or a connection attempt failed. The reason is an 802.11 reason code, or one of the
+    /// ESP-IDF `wifi_err_reason_t` extensions (eg. 200 beacon timeout, 201 no AP found).
+    Disconnected {
+        reason: u16,
+    },
+}
+
+/// A WiFi station (client) driver.
+pub trait WifiDriver: Send {
+    fn set_configuration(&mut self, config: &ClientConfiguration) -> Result<(), OsError>;
+
+    /// Start the WiFi radio.
+    fn start(&mut self) -> Result<(), OsError>;
+
+    /// Scan for access points in range. The radio must be started.
+    fn scan(&mut self) -> Result<Vec<ScanResult>, OsError>;
+
+    /// Associate with the configured access point, blocking until done.
+    fn connect(&mut self) -> Result<(), OsError>;
+
+    fn disconnect(&mut self) -> Result<(), OsError>;
+
+    fn is_connected(&self) -> Result<bool, OsError>;
+
+    /// Subscribe to link events. Events are delivered until the receiver is dropped.
+    fn subscribe(&mut self) -> Result<Receiver<WifiEvent>, OsError>;
+
+    /// Signal strength of the connected access point in dBm.
+    fn rssi(&self) -> Result<i8, OsError>;
+
+    /// Block until the network interface is up & has an IP address.
+    fn wait_netif_up(&mut self) -> Result<(), OsError>;
+
+    /// IP configuration of the station interface.
+    fn ip_info(&self) -> Result<IpInfo, OsError>;
+
+    /// Switch to access point mode, hosting an open network with the given SSID. Returns the address of the device
+    /// on that network.
+    fn start_access_point(&mut self, ssid: &str) -> Result<Ipv4Addr, OsError>;
+}
@@ -0,0 +1,414 @@
+//! Task & memory health.
+//!
+//! Every thread spawned by the kernel is registered here while it runs, so that its stack high-water mark can be
+//! sampled. A thread may also opt in to the task watchdog with `watch`, after which it must `feed` the watchdog at
+//! least every `FEED_INTERVAL` or the device is reset. The monitor service logs a summary periodically & warns when a
+//! task is low on stack, stops feeding the watchdog, or the heap runs low.
+
+use core::fmt;
+use std::cell::RefCell;
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::crash;
+use crate::error::OsError;
+use crate::hal::{Clock, HeapStats, Platform, TaskId, ThreadOptions, Watchdog};
+
+const LOG_TGT: &str = "inu.health";
+
+/// Longest a watched thread should go between feeding the watchdog.
+pub const FEED_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Free stack, in bytes, below which a task is reported as running out.
+pub const LOW_STACK: usize = 256;
+
+/// Free heap, in bytes, below which the heap is reported as running out.
+pub const LOW_HEAP: usize = 16 * 1024;
+
+thread_local! {
+    /// The registry & ID of the calling thread, if it was spawned through `Health`.
+    static CURRENT: RefCell<Option<(Arc<Health>, TaskId)>> = const { RefCell::new(None) };
+}
+
+/// Health of a running task.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TaskHealth {
+    pub name: String,
+    pub options: ThreadOptions,
+    /// Least free stack the task has had, in bytes, if known.
+    pub stack_free: Option<usize>,
+    /// Time since the task last fed the watchdog, if it is watched.
+    pub since_feed: Option<Duration>,
+}
+
+/// Snapshot of the device's health.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HealthReport {
+    pub uptime: Duration,
+    pub heap: Option<HeapStats>,
+    pub tasks: Vec<TaskHealth>,
+    /// Longest a watched task may go without feeding the watchdog.
+    pub watchdog_timeout: Duration,
+}
+
+/// A problem found in a `HealthReport`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HealthWarning {
+    LowStack {
+        task: String,
+        free: usize,
+    },
+    /// A watched task hasn't fed the watchdog for over half its timeout.
+    Stalled {
+        task: String,
+        since_feed: Duration,
+    },
+    LowHeap {
+        min_free: usize,
+    },
+}
+
+impl HealthReport {
+    pub fn warnings(&self) -> Vec<HealthWarning> {
+        let mut warnings = vec![];
+
+        for task in &self.tasks {
+            if let Some(free) = task.stack_free.filter(|f| *f < LOW_STACK) {
+                warnings.push(HealthWarning::LowStack {
+                    task: task.name.clone(),
+                    free,
+                });
+            }
+            if let Some(since_feed) = task.since_feed.filter(|s| *s > self.watchdog_timeout / 2) {
+                warnings.push(HealthWarning::Stalled {
+                    task: task.name.clone(),
+                    since_feed,
+                });
+            }
+        }
+
+        if let Some(heap) = self.heap.filter(|h| h.min_free < LOW_HEAP) {
+            warnings.push(HealthWarning::LowHeap {
+                min_free: heap.min_free,
+            });
+        }
+
+        warnings
+    }
+}
+
+impl fmt::Display for HealthWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HealthWarning::LowStack { task, free } => {
+                write!(f, "Task '{}' has {} bytes of stack left", task, free)
+            }
+            HealthWarning::Stalled { task, since_feed } => write!(
+                f,
+                "Task '{}' hasn't fed the watchdog for {:?}",
+                task, since_feed
+            ),
+            HealthWarning::LowHeap { min_free } => {
+                write!(f, "Free heap has fallen to {} bytes", min_free)
+            }
+        }
+    }
+}
+
+struct Task {
+    id: TaskId,
+    name: String,
+    options: ThreadOptions,
+    /// When the task last fed the watchdog, if it is watched.
+    last_feed: Option<Duration>,
+}
+
+/// Registry of running tasks.
+pub struct Health {
+    platform: Arc<dyn Platform>,
+    watchdog: Arc<dyn Watchdog>,
+    clock: Arc<dyn Clock>,
+    tasks: Mutex<Vec<Task>>,
+}
+
+impl Health {
+    pub fn new(platform: Arc<dyn Platform>) -> Arc<Self> {
+        Arc::new(Health {
+            watchdog: platform.watchdog(),
+            clock: platform.clock(),
+            platform,
+            tasks: Mutex::new(vec![]),
+        })
+    }
+
+    /// Spawn a thread, registered under the given name while it runs.
+    pub fn spawn(
+        self: &Arc<Self>,
+        name: &str,
+        options: ThreadOptions,
+        f: Box<dyn FnOnce() + Send>,
+    ) -> Result<JoinHandle<()>, OsError> {
+        let health = self.clone();
+        let name = name.to_string();
+
+        self.platform.spawn(
+            options,
+            Box::new(move || {
+                let _task = health.enter(name, options);
+                f();
+            }),
+        )
+    }
+
+    /// Health of the device & every registered task.
+    pub fn report(&self) -> HealthReport {
+        let now = self.clock.now();
+        let tasks = self.tasks.lock().unwrap();
+
+        HealthReport {
+            uptime: now,
+            heap: self.platform.heap_stats(),
+            // Tasks remove themselves under the lock before exiting, so every ID here is still valid
+            tasks: tasks
+                .iter()
+                .map(|t| TaskHealth {
+                    name: t.name.clone(),
+                    options: t.options,
+                    stack_free: self.platform.stack_high_water(t.id),
+                    since_feed: t.last_feed.map(|at| now.saturating_sub(at)),
+                })
+                .collect(),
+            watchdog_timeout: self.watchdog.timeout(),
+        }
+    }
+
+    /// Log a summary every interval, warning of any problems.
+    pub fn run(&self, interval: Duration) -> ! {
+        if let Err(e) = watch() {
+            log::warn!(target: LOG_TGT, "Health monitor unwatched: {}", crash::describe(&e));
+        }
+
+        loop {
+            let report = self.report();
+
+            if let Some(heap) = report.heap {
+                log::debug!(
+                    target: LOG_TGT,
+                    "Heap {} bytes free, {} min, {} largest block",
+                    heap.free,
+                    heap.min_free,
+                    heap.largest_block
+                );
+            }
+            for task in &report.tasks {
+                if let Some(free) = task.stack_free {
+                    log::debug!(
+                        target: LOG_TGT,
+                        "Task '{}' stack {}/{} bytes free",
+                        task.name,
+                        free,
+                        task.options.stack_size
+                    );
+                }
+            }
+            for warning in report.warnings() {
+                log::warn!(target: LOG_TGT, "{}", warning);
+            }
+
+            sleep(interval);
+        }
+    }
+
+    fn enter(self: &Arc<Self>, name: String, options: ThreadOptions) -> TaskGuard {
+        let id = self.platform.current_task();
+        self.tasks.lock().unwrap().push(Task {
+            id,
+            name,
+            options,
+            last_feed: None,
+        });
+        CURRENT.with(|c| *c.borrow_mut() = Some((self.clone(), id)));
+
+        TaskGuard
+    }
+
+    /// Update the calling task, if it is registered.
+    fn with_current<T>(f: impl FnOnce(&Health, &mut Task) -> T) -> Option<T> {
+        CURRENT.with(|c| {
+            let current = c.borrow();
+            let (health, id) = current.as_ref()?;
+            let mut tasks = health.tasks.lock().unwrap();
+            let task = tasks.iter_mut().find(|t| t.id == *id)?;
+            Some(f(health, task))
+        })
+    }
+}
+
+/// Removes the task from the registry when it exits, even by panicking.
+struct TaskGuard;
+
+impl Drop for TaskGuard {
+    fn drop(&mut self) {
+        let _ = unwatch();
+        if let Some((health, id)) = CURRENT.with(|c| c.borrow_mut().take()) {
+            health.tasks.lock().unwrap().retain(|t| t.id != id);
+        }
+    }
+}
+
+/// Subscribe the calling thread to the task watchdog. It must then `feed` the watchdog at least every
+/// `FEED_INTERVAL`, until it exits.
+///
+/// Does nothing if already watched. Fails on threads not spawned through `Health`.
+pub fn watch() -> Result<(), OsError> {
+    Health::with_current(|health, task| {
+        if task.last_feed.is_some() {
+            return Ok(());
+        }
+        health.watchdog.subscribe()?;
+        task.last_feed = Some(health.clock.now());
+        log::debug!(target: LOG_TGT, "Task '{}' watched", task.name);
+        Ok(())
+    })
+    .unwrap_or_else(|| Err(OsError::Generic("Task not registered".into())))
+}
+
+/// Unsubscribe the calling thread from the task watchdog.
+fn unwatch() -> Result<(), OsError> {
+    Health::with_current(|health, task| match task.last_feed.take() {
+        Some(_) => health.watchdog.unsubscribe(),
+        None => Ok(()),
+    })
+    .unwrap_or(Ok(()))
+}
+
+/// Feed the watchdog, if the calling thread is watched.
+pub fn feed() {
+    Health::with_current(|health, task| {
+        if task.last_feed.is_some() {
+            if let Err(e) = health.watchdog.feed() {
+                log::warn!(target: LOG_TGT, "Failed to feed watchdog: {}", crash::describe(&e));
+            }
+            task.last_feed = Some(health.clock.now());
+        }
+    });
+}
+
+/// Run a blocking operation that may outlast the watchdog timeout, with the calling thread unwatched meanwhile.
+pub fn unwatched<T>(f: impl FnOnce() -> T) -> T {
+    let watched = Health::with_current(|_, task| task.last_feed.is_some()).unwrap_or(false);
+    if watched {
+        let _ = unwatch();
+    }
+
+    let result = f();
+
+    if watched {
+        if let Err(e) = watch() {
+            log::warn!(target: LOG_TGT, "Failed to watch task again: {}", crash::describe(&e));
+        }
+    }
+    result
+}
+
+/// Sleep, feeding the watchdog throughout.
+pub fn sleep(duration: Duration) {
+    let mut remaining = duration;
+    while !remaining.is_zero() {
+        feed();
+        let step = remaining.min(FEED_INTERVAL);
+        thread::sleep(step);
+        remaining -= step;
+    }
+    feed();
+}
+
+#[cfg(all(test, feature = "host"))]
+mod tests {
+    use super::*;
+    use crate::hal::host::HostPlatform;
+    use std::sync::mpsc;
+
+    const OPTIONS: ThreadOptions = ThreadOptions {
+        priority: 5,
+        core: None,
+        stack_size: 4096,
+    };
+
+    #[test]
+    fn tasks_are_tracked_while_running() {
+        let platform = HostPlatform::new();
+        let watchdog = platform.watchdog_sim();
+        let health = Health::new(Arc::new(platform));
+
+        let (started, running) = mpsc::channel();
+        let (stop, stopped) = mpsc::channel::<()>();
+        let handle = health
+            .spawn(
+                "net",
+                OPTIONS,
+                Box::new(move || {
+                    watch().unwrap();
+                    watch().unwrap();
+                    feed();
+                    started.send(()).unwrap();
+                    let _ = stopped.recv();
+                }),
+            )
+            .unwrap();
+
+        running.recv().unwrap();
+        let report = health.report();
+        assert_eq!(report.tasks.len(), 1);
+        assert_eq!(report.tasks[0].name, "net");
+        assert!(report.tasks[0].since_feed.unwrap() < Duration::from_secs(1));
+        assert!(report.warnings().is_empty());
+        assert_eq!(watchdog.subscribed(), 1);
+
+        drop(stop);
+        handle.join().unwrap();
+        assert!(health.report().tasks.is_empty());
+        assert_eq!(watchdog.subscribed(), 0);
+        assert!(watch().is_err());
+    }
+
+    #[test]
+    fn problems_are_warned_of() {
+        let task = |name: &str, stack_free, since_feed| TaskHealth {
+            name: name.into(),
+            options: OPTIONS,
+            stack_free,
+            since_feed,
+        };
+        let report = HealthReport {
+            uptime: Duration::from_secs(60),
+            heap: Some(HeapStats {
+                free: 40_000,
+                min_free: 12_000,
+                largest_block: 30_000,
+            }),
+            tasks: vec![
+                task("net", Some(120), Some(Duration::from_secs(1))),
+                task("inu", Some(1800), Some(Duration::from_secs(4))),
+                task("thread", None, None),
+            ],
+            watchdog_timeout: Duration::from_secs(5),
+        };
+
+        assert_eq!(
+            report.warnings(),
+            [
+                HealthWarning::LowStack {
+                    task: "net".into(),
+                    free: 120
+                },
+                HealthWarning::Stalled {
+                    task: "inu".into(),
+                    since_feed: Duration::from_secs(4)
+                },
+                HealthWarning::LowHeap { min_free: 12_000 },
+            ]
+        );
+    }
+}
@@ -0,0 +1,508 @@
+//! Inu network protocol.
+//!
+//! Devices find each other over UDP multicast. Each device joins the Inu group while online, announces itself with a
+//! periodic heartbeat & answers discovery pings with a heartbeat sent directly to the pinger.
+//!
+//! Inputs publish triggers to the group and devices request actions of each other. Inbound triggers & actions are
+//! dispatched to handlers registered by code, so a button on one device can drive outputs on another.
+
+use std::io::ErrorKind;
+use std::mem;
+use std::net::{Ipv4Addr, SocketAddr, UdpSocket};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread::{self, ThreadId};
+use std::time::Duration;
+
+use crate::connectivity::Connectivity;
+use crate::error::{OsError, WifiError};
+use crate::hal::Clock;
+use crate::health;
+use crate::types::LinkState;
+
+pub mod protocol;
+
+use protocol::{Action, Heartbeat, Message, Trigger, MAX_PACKET_LEN};
+
+const LOG_TGT: &str = "inu.proto";
+
+/// Multicast group & port every Inu device listens on.
+pub const INU_GROUP: Ipv4Addr = Ipv4Addr::new(239, 255, 73, 85);
+pub const INU_PORT: u16 = 7385;
+
+/// Default time between heartbeats.
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How long the socket waits for a packet before checking whether a heartbeat is due or the link has gone.
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Identity of this device on the Inu network.
+#[derive(Debug, Clone)]
+pub struct Identity {
+    pub device_id: String,
+    pub edition: String,
+    pub build: u32,
+}
+
+type HandlerFn<T> = Box<dyn FnMut(&T) + Send>;
+
+/// A handler registered for a code. It may be called from any thread, one caller at a time.
+struct Handler<T> {
+    code: u16,
+    f: Mutex<HandlerFn<T>>,
+    /// Thread calling the handler, so a handler firing its own code isn't re-entered.
+    caller: Mutex<Option<ThreadId>>,
+}
+
+impl<T> Handler<T> {
+    fn new(code: u16, f: HandlerFn<T>) -> Arc<Self> {
+        Arc::new(Handler {
+            code,
+            f: Mutex::new(f),
+            caller: Mutex::new(None),
+        })
+    }
+
+    /// Call the handler, waiting for any other thread calling it to finish first.
+    fn call(&self, arg: &T) {
+        let me = thread::current().id();
+        if *self.caller.lock().unwrap() == Some(me) {
+            log::warn!(target: LOG_TGT, "Handler for {} fired its own code, ignoring", self.code);
+            return;
+        }
+
+        // A handler that panicked is still called again
+        let mut f = self.f.lock().unwrap_or_else(PoisonError::into_inner);
+        *self.caller.lock().unwrap() = Some(me);
+        let _calling = Calling(&self.caller);
+        f(arg);
+    }
+}
+
+/// Clears the caller of a handler once it returns, even by panicking.
+struct Calling<'a>(&'a Mutex<Option<ThreadId>>);
+
+impl Drop for Calling<'_> {
+    fn drop(&mut self) {
+        *self.0.lock().unwrap() = None;
+    }
+}
+
+#[derive(Default)]
+struct Handlers {
+    triggers: Vec<Arc<Handler<Trigger>>>,
+    actions: Vec<Arc<Handler<Action>>>,
+}
+
+struct Inner {
+    identity: Identity,
+    clock: Arc<dyn Clock>,
+    /// Present while joined to the group.
+    socket: Mutex<Option<UdpSocket>>,
+    handlers: Mutex<Handlers>,
+    /// Messages waiting to be published once joined to the group.
+    outbox: Mutex<Vec<Message>>,
+}
+
+/// Inu protocol service. Run it on its own thread with `run`; clones share the service, so may be handed to inputs
+/// that publish triggers.
+#[derive(Clone)]
+pub struct InuService {
+    inner: Arc<Inner>,
+    interval: Duration,
+}
+
+impl InuService {
+    pub fn new(identity: Identity, clock: Arc<dyn Clock>) -> Self {
+        InuService {
+            inner: Arc::new(Inner {
+                identity,
+                clock,
+                socket: Mutex::new(None),
+                handlers: Mutex::new(Handlers::default()),
+                outbox: Mutex::new(vec![]),
+            }),
+            interval: HEARTBEAT_INTERVAL,
+        }
+    }
+
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    pub fn device_id(&self) -> &str {
+        &self.inner.identity.device_id
+    }
+
+    /// The heartbeat announcing this device.
+    pub fn heartbeat(&self) -> Message {
+        let identity = &self.inner.identity;
+
+        Message::Heartbeat(Heartbeat {
+            device_id: identity.device_id.clone(),
+            edition: identity.edition.clone(),
+            build: identity.build,
+            uptime: self.inner.clock.now().as_secs().min(u32::MAX as u64) as u32,
+        })
+    }
+
+    /// Call `f` for every trigger with the given code, from any device including this one.
+    ///
+    /// Handlers run on the Inu service thread, or the caller of `trigger` for local triggers, so should return quickly.
+    pub fn on_trigger<F>(&self, code: u16, f: F)
+    where
+        F: FnMut(&Trigger) + Send + 'static,
+    {
+        self.inner
+            .handlers
+            .lock()
+            .unwrap()
+            .triggers
+            .push(Handler::new(code, Box::new(f)));
+    }
+
+    /// Call `f` for every action with the given code addressed to this device, as for `on_trigger`.
+    pub fn on_action<F>(&self, code: u16, f: F)
+    where
+        F: FnMut(&Action) + Send + 'static,
+    {
+        self.inner
+            .handlers
+            .lock()
+            .unwrap()
+            .actions
+            .push(Handler::new(code, Box::new(f)));
+    }
+
+    /// Fire a trigger, dispatching it locally and publishing it to the group.
+    ///
+    /// Local handlers run even while offline, in which case an error is returned for the publish.
+    pub fn trigger(&self, code: u16) -> Result<(), OsError> {
+        let trigger = Trigger {
+            device_id: self.device_id().to_string(),
+            code,
+        };
+        self.dispatch(&Message::Trigger(trigger.clone()));
+        self.send(&Message::Trigger(trigger))
+    }
+
+    /// Ask a device to perform an action, or all devices if no target is given.
+    pub fn action(&self, target: Option<&str>, code: u16) -> Result<(), OsError> {
+        let action = Message::Action(Action {
+            device_id: self.device_id().to_string(),
+            target: target.map(String::from),
+            code,
+        });
+
+        self.dispatch(&action);
+        match target {
+            Some(t) if t == self.device_id() => Ok(()),
+            _ => self.send(&action),
+        }
+    }
+
+    /// Publish a message to the group.
+    pub fn send(&self, message: &Message) -> Result<(), OsError> {
+        let socket = self.inner.socket.lock().unwrap();
+        let socket = socket.as_ref().ok_or(WifiError::Disconnected)?;
+        socket.send_to(&message.encode()?, (INU_GROUP, INU_PORT))?;
+        Ok(())
+    }
+
+    /// Publish a message to the group once, as soon as the device is online.
+    pub fn announce(&self, message: Message) {
+        self.inner.outbox.lock().unwrap().push(message);
+    }
+
+    /// Handle an incoming packet, returning the reply to send back to the sender, if any.
+    pub fn handle(&self, packet: &[u8]) -> Option<Message> {
+        let message = match Message::decode(packet) {
+            Ok(m) => m,
+            Err(e) => {
+                log::debug!(target: LOG_TGT, "Ignoring packet: {:?}", e);
+                return None;
+            }
+        };
+
+        match message {
+            Message::Discover { target } => match target {
+                Some(t) if t != self.device_id() => None,
+                _ => Some(self.heartbeat()),
+            },
+            Message::Heartbeat(hb) => {
+                if hb.device_id != self.device_id() {
+                    log::debug!(target: LOG_TGT, "Heartbeat from '{}' ({} build {})", hb.device_id, hb.edition, hb.build);
+                }
+                None
+            }
+            Message::Trigger(_) | Message::Action(_) => {
+                self.dispatch(&message);
+                None
+            }
+            Message::Log(_) => None,
+            Message::CrashReport(c) => {
+                log::warn!(target: LOG_TGT, "'{}' restarted after {:?} (error {}): {}", c.device_id, c.reset_reason, c.error_code, c.detail);
+                None
+            }
+        }
+    }
+
+    /// Pass triggers & actions addressed to this device to their handlers.
+    fn dispatch(&self, message: &Message) {
+        // Matching handlers are called outside the lock, so a handler may fire a trigger or register another without
+        // deadlocking
+        fn matching<T>(handlers: &[Arc<Handler<T>>], code: u16) -> Vec<Arc<Handler<T>>> {
+            handlers
+                .iter()
+                .filter(|h| h.code == code)
+                .cloned()
+                .collect()
+        }
+
+        match message {
+            Message::Trigger(t) => {
+                let handlers = matching(&self.inner.handlers.lock().unwrap().triggers, t.code);
+                handlers.iter().for_each(|h| h.call(t));
+            }
+            Message::Action(a) if a.target.as_ref().map_or(true, |t| t == self.device_id()) => {
+                let handlers = matching(&self.inner.handlers.lock().unwrap().actions, a.code);
+                handlers.iter().for_each(|h| h.call(a));
+            }
+            _ => {}
+        }
+    }
+
+    /// Serve the protocol whenever the device is online.
+    pub fn run(&self, connectivity: Arc<Connectivity>) -> ! {
+        log::info!(target: LOG_TGT, "Inu service started");
+        let events = connectivity.subscribe();
+
+        loop {
+            // Only transitions matter, the current state is read directly
+            events.try_iter().for_each(drop);
+            health::feed();
+
+            let ip = match connectivity.state().link {
+                LinkState::Connected(info) => info.ip,
+                _ => {
+                    let _ = events.recv_timeout(health::FEED_INTERVAL);
+                    continue;
+                }
+            };
+
+            let result = self.serve(ip, &connectivity);
+            *self.inner.socket.lock().unwrap() = None;
+
+            if let Err(e) = result {
+                log::warn!(target: LOG_TGT, "Inu service interrupted: {:?}", e);
+                health::sleep(Duration::from_secs(1));
+            }
+        }
+    }
+
+    /// Publish queued messages. Any not sent are kept for the next attempt.
+    fn flush_outbox(&self, socket: &UdpSocket, group: SocketAddr) -> Result<(), OsError> {
+        let mut outbox = mem::take(&mut *self.inner.outbox.lock().unwrap()).into_iter();
+
+        while let Some(message) = outbox.next() {
+            let packet = match message.encode() {
+                Ok(p) => p,
+                Err(e) => {
+                    log::error!(target: LOG_TGT, "Dropping unencodable message: {:?}", e);
+                    continue;
+                }
+            };
+
+            if let Err(e) = socket.send_to(&packet, group) {
+                let mut current = self.inner.outbox.lock().unwrap();
+                let newer = mem::take(&mut *current);
+                current.push(message);
+                current.extend(outbox);
+                current.extend(newer);
+                return Err(e.into());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Join the group on the given interface & serve until the link goes down.
+    fn serve(&self, ip: Ipv4Addr, connectivity: &Connectivity) -> Result<(), OsError> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, INU_PORT))?;
+        socket.join_multicast_v4(&INU_GROUP, &ip)?;
+        socket.set_multicast_loop_v4(false)?;
+        socket.set_read_timeout(Some(POLL_INTERVAL))?;
+        *self.inner.socket.lock().unwrap() = Some(socket.try_clone()?);
+        log::info!(target: LOG_TGT, "Joined Inu group {}:{}", INU_GROUP, INU_PORT);
+
+        let group = SocketAddr::from((INU_GROUP, INU_PORT));
+        let mut buffer = [0u8; MAX_PACKET_LEN];
+        let mut next_heartbeat = Duration::ZERO;
+
+        while connectivity.is_online() {
+            health::feed();
+            self.flush_outbox(&socket, group)?;
+
+            let now = self.inner.clock.now();
+            if now >= next_heartbeat {
+                socket.send_to(&self.heartbeat().encode()?, group)?;
+                next_heartbeat = now + self.interval;
+            }
+
+            match socket.recv_from(&mut buffer) {
+                Ok((len, from)) => {
+                    if let Some(reply) = self.handle(&buffer[..len]) {
+                        socket.send_to(&reply.encode()?, from)?;
+                    }
+                }
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "host"))]
+mod tests {
+    use super::*;
+    use crate::hal::host::ManualClock;
+
+    fn service(device_id: &str) -> InuService {
+        let clock = Arc::new(ManualClock::new());
+        clock.advance(Duration::from_secs(90));
+
+        InuService::new(
+            Identity {
+                device_id: device_id.into(),
+                edition: "Ferric".into(),
+                build: 2,
+            },
+            clock,
+        )
+    }
+
+    fn discover(target: Option<&str>) -> Vec<u8> {
+        Message::Discover {
+            target: target.map(String::from),
+        }
+        .encode()
+        .unwrap()
+    }
+
+    #[test]
+    fn discovery_pings_are_answered() {
+        let svc = service("inu.test");
+        let expected = Message::Heartbeat(Heartbeat {
+            device_id: "inu.test".into(),
+            edition: "Ferric".into(),
+            build: 2,
+            uptime: 90,
+        });
+
+        assert_eq!(svc.handle(&discover(None)), Some(expected.clone()));
+        assert_eq!(svc.handle(&discover(Some("inu.test"))), Some(expected));
+        assert_eq!(svc.handle(&discover(Some("inu.other"))), None);
+
+        // Heartbeats & junk get no reply
+        assert_eq!(svc.handle(&svc.heartbeat().encode().unwrap()), None);
+        assert_eq!(svc.handle(b"GET / HTTP/1.1"), None);
+    }
+
+    #[test]
+    fn triggers_and_actions_reach_their_handlers() {
+        let svc = service("inu.lights");
+        let seen = Arc::new(Mutex::new(vec![]));
+
+        let log = seen.clone();
+        svc.on_trigger(7, move |t| {
+            log.lock()
+                .unwrap()
+                .push(format!("trigger {} from {}", t.code, t.device_id))
+        });
+        let log = seen.clone();
+        svc.on_action(1, move |a| {
+            log.lock()
+                .unwrap()
+                .push(format!("action {} from {}", a.code, a.device_id))
+        });
+
+        let packet = |m: Message| m.encode().unwrap();
+        let trigger = |code| {
+            packet(Message::Trigger(Trigger {
+                device_id: "inu.button".into(),
+                code,
+            }))
+        };
+        let action = |target: Option<&str>| {
+            packet(Message::Action(Action {
+                device_id: "inu.button".into(),
+                target: target.map(String::from),
+                code: 1,
+            }))
+        };
+
+        assert_eq!(svc.handle(&trigger(7)), None);
+        svc.handle(&trigger(8));
+        svc.handle(&action(Some("inu.lights")));
+        svc.handle(&action(Some("inu.other")));
+        svc.handle(&action(None));
+
+        // Local triggers are dispatched even while offline
+        assert!(svc.trigger(7).is_err());
+
+        assert_eq!(
+            *seen.lock().unwrap(),
+            vec![
+                "trigger 7 from inu.button",
+                "action 1 from inu.button",
+                "action 1 from inu.button",
+                "trigger 7 from inu.lights",
+            ]
+        );
+    }
+
+    #[test]
+    fn handlers_survive_concurrent_dispatch() {
+        let svc = service("inu.lights");
+        let calls = Arc::new(Mutex::new(0));
+
+        let count = calls.clone();
+        let inner = svc.clone();
+        svc.on_trigger(7, move |_| {
+            // Firing its own code is ignored rather than deadlocking
+            let _ = inner.trigger(7);
+            std::thread::sleep(Duration::from_millis(50));
+            *count.lock().unwrap() += 1;
+        });
+
+        // A local trigger & an inbound packet dispatched at the same time both reach the handler
+        let packet = Message::Trigger(Trigger {
+            device_id: "inu.button".into(),
+            code: 7,
+        })
+        .encode()
+        .unwrap();
+        let remote = svc.clone();
+        let inbound = std::thread::spawn(move || remote.handle(&packet));
+        let _ = svc.trigger(7);
+        inbound.join().unwrap();
+        assert_eq!(*calls.lock().unwrap(), 2);
+
+        // A handler that panics stays registered
+        let svc = service("inu.lights");
+        let count = calls.clone();
+        svc.on_trigger(9, move |_| {
+            *count.lock().unwrap() += 1;
+            panic!("handler failed");
+        });
+        for _ in 0..2 {
+            let panicking = svc.clone();
+            assert!(std::thread::spawn(move || panicking.trigger(9))
+                .join()
+                .is_err());
+        }
+        assert_eq!(*calls.lock().unwrap(), 4);
+    }
+}
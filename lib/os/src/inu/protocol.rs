@@ -0,0 +1,394 @@
+//! Inu wire format.
+//!
+//! Every packet starts with a header, followed by the fields of the message kind:
+//!
+//! | Field   | Size | Notes                      |
+//! |---------|------|----------------------------|
+//! | magic   | 3    | `INU`                      |
+//! | version | 1    | `PROTOCOL_VERSION`         |
+//! | kind    | 1    | see `Message`              |
+//!
+//! Integers are big-endian. Strings are a `u8` length followed by that many bytes of UTF-8. An empty device ID in a
+//! target field addresses every device.
+
+use log::Level;
+
+use crate::error::ProtocolError;
+use crate::hal::ResetReason;
+
+pub const MAGIC: &[u8; 3] = b"INU";
+pub const PROTOCOL_VERSION: u8 = 1;
+
+/// No packet is larger than this.
+pub const MAX_PACKET_LEN: usize = 1024;
+
+/// Longest string a field can hold, in bytes.
+pub const MAX_STRING_LEN: usize = u8::MAX as usize;
+
+const KIND_HEARTBEAT: u8 = 0x01;
+const KIND_DISCOVER: u8 = 0x02;
+const KIND_TRIGGER: u8 = 0x03;
+const KIND_ACTION: u8 = 0x04;
+const KIND_CRASH_REPORT: u8 = 0x05;
+const KIND_LOG: u8 = 0x06;
+
+/// Periodic announcement of a device on the network, also sent in reply to a discovery ping.
+///
+/// `device_id` & `edition` are strings, `build` a `u32` and `uptime` a `u32` of seconds since boot.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Heartbeat {
+    pub device_id: String,
+    pub edition: String,
+    pub build: u32,
+    pub uptime: u32,
+}
+
+/// An input on a device fired, eg. a button was pressed. Any device may act on it.
+///
+/// `device_id` is the source device, a string, followed by the `u16` trigger code.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Trigger {
+    pub device_id: String,
+    pub code: u16,
+}
+
+/// A request for a device to perform an action.
+///
+/// Strings for the source `device_id` and `target` are followed by the `u16` action code.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Action {
+    pub device_id: String,
+    pub target: Option<String>,
+    pub code: u16,
+}
+
+/// A device restarted unexpectedly. Sent once, when it is next online.
+///
+/// The `device_id` string is followed by the `u8` reset reason, the `u16` code of the fatal error (zero if there was
+/// none) & a string describing the panic or error.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CrashReport {
+    pub device_id: String,
+    pub reset_reason: ResetReason,
+    pub error_code: u16,
+    pub detail: String,
+}
+
+/// A log record streamed from a device.
+///
+/// The `device_id` string is followed by the `u8` level (1 for error through 5 for trace), then the `target` &
+/// `message` strings.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogEntry {
+    pub device_id: String,
+    pub level: Level,
+    pub target: String,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    Heartbeat(Heartbeat),
+    /// Ask devices to announce themselves. A single string field holds the device ID to ask, empty for all devices.
+    Discover {
+        target: Option<String>,
+    },
+    Trigger(Trigger),
+    Action(Action),
+    CrashReport(CrashReport),
+    Log(LogEntry),
+}
+
+impl Message {
+    pub fn encode(&self) -> Result<Vec<u8>, ProtocolError> {
+        let mut w = Writer::default();
+        w.bytes(MAGIC);
+        w.u8(PROTOCOL_VERSION);
+
+        match self {
+            Message::Heartbeat(hb) => {
+                w.u8(KIND_HEARTBEAT);
+                w.string(&hb.device_id)?;
+                w.string(&hb.edition)?;
+                w.u32(hb.build);
+                w.u32(hb.uptime);
+            }
+            Message::Discover { target } => {
+                w.u8(KIND_DISCOVER);
+                w.string(target.as_deref().unwrap_or(""))?;
+            }
+            Message::Trigger(t) => {
+                w.u8(KIND_TRIGGER);
+                w.string(&t.device_id)?;
+                w.u16(t.code);
+            }
+            Message::Action(a) => {
+                w.u8(KIND_ACTION);
+                w.string(&a.device_id)?;
+                w.string(a.target.as_deref().unwrap_or(""))?;
+                w.u16(a.code);
+            }
+            Message::CrashReport(c) => {
+                w.u8(KIND_CRASH_REPORT);
+                w.string(&c.device_id)?;
+                w.u8(c.reset_reason as u8);
+                w.u16(c.error_code);
+                w.string(&c.detail)?;
+            }
+            Message::Log(l) => {
+                w.u8(KIND_LOG);
+                w.string(&l.device_id)?;
+                w.u8(l.level as u8);
+                w.string(&l.target)?;
+                w.string(&l.message)?;
+            }
+        }
+
+        Ok(w.buffer)
+    }
+
+    pub fn decode(packet: &[u8]) -> Result<Message, ProtocolError> {
+        let mut r = Reader { packet, pos: 0 };
+
+        if r.bytes(MAGIC.len()).map_err(|_| ProtocolError::BadMagic)? != MAGIC {
+            return Err(ProtocolError::BadMagic);
+        }
+
+        let version = r.u8()?;
+        if version != PROTOCOL_VERSION {
+            return Err(ProtocolError::UnsupportedVersion(version));
+        }
+
+        let message = match r.u8()? {
+            KIND_HEARTBEAT => Message::Heartbeat(Heartbeat {
+                device_id: r.string()?,
+                edition: r.string()?,
+                build: r.u32()?,
+                uptime: r.u32()?,
+            }),
+            KIND_DISCOVER => Message::Discover {
+                target: r.target()?,
+            },
+            KIND_TRIGGER => Message::Trigger(Trigger {
+                device_id: r.string()?,
+                code: r.u16()?,
+            }),
+            KIND_ACTION => Message::Action(Action {
+                device_id: r.string()?,
+                target: r.target()?,
+                code: r.u16()?,
+            }),
+            KIND_CRASH_REPORT => Message::CrashReport(CrashReport {
+                device_id: r.string()?,
+                reset_reason: r.u8()?.into(),
+                error_code: r.u16()?,
+                detail: r.string()?,
+            }),
+            KIND_LOG => Message::Log(LogEntry {
+                device_id: r.string()?,
+                level: r.level()?,
+                target: r.string()?,
+                message: r.string()?,
+            }),
+            kind => return Err(ProtocolError::UnknownKind(kind)),
+        };
+
+        match packet.len() - r.pos {
+            0 => Ok(message),
+            n => Err(ProtocolError::TrailingBytes(n)),
+        }
+    }
+}
+
+/// Truncate a string to fit a field, on a character boundary.
+pub fn fit(mut s: String) -> String {
+    if s.len() > MAX_STRING_LEN {
+        let mut end = MAX_STRING_LEN;
+        while !s.is_char_boundary(end) {
+            end -= 1;
+        }
+        s.truncate(end);
+    }
+    s
+}
+
+#[derive(Default)]
+struct Writer {
+    buffer: Vec<u8>,
+}
+
+impl Writer {
+    fn bytes(&mut self, b: &[u8]) {
+        self.buffer.extend_from_slice(b);
+    }
+
+    fn u8(&mut self, v: u8) {
+        self.buffer.push(v);
+    }
+
+    fn u16(&mut self, v: u16) {
+        self.bytes(&v.to_be_bytes());
+    }
+
+    fn u32(&mut self, v: u32) {
+        self.bytes(&v.to_be_bytes());
+    }
+
+    fn string(&mut self, s: &str) -> Result<(), ProtocolError> {
+        let len = u8::try_from(s.len()).map_err(|_| ProtocolError::TooLong)?;
+        self.u8(len);
+        self.bytes(s.as_bytes());
+        Ok(())
+    }
+}
+
+struct Reader<'a> {
+    packet: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], ProtocolError> {
+        let b = self
+            .packet
+            .get(self.pos..self.pos + len)
+            .ok_or(ProtocolError::Truncated)?;
+        self.pos += len;
+        Ok(b)
+    }
+
+    fn u8(&mut self) -> Result<u8, ProtocolError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, ProtocolError> {
+        let b = self.bytes(2)?;
+        Ok(u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, ProtocolError> {
+        let b = self.bytes(4)?;
+        Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn string(&mut self) -> Result<String, ProtocolError> {
+        let len = self.u8()? as usize;
+        let b = self.bytes(len)?;
+        String::from_utf8(b.to_vec()).map_err(|_| ProtocolError::InvalidString)
+    }
+
+    fn level(&mut self) -> Result<Level, ProtocolError> {
+        match self.u8()? {
+            1 => Ok(Level::Error),
+            2 => Ok(Level::Warn),
+            3 => Ok(Level::Info),
+            4 => Ok(Level::Debug),
+            5 => Ok(Level::Trace),
+            l => Err(ProtocolError::InvalidLevel(l)),
+        }
+    }
+
+    /// A device ID, where empty means all devices.
+    fn target(&mut self) -> Result<Option<String>, ProtocolError> {
+        let target = self.string()?;
+        Ok((!target.is_empty()).then_some(target))
+    }
+}
+
+#[cfg(all(test, feature = "host"))]
+mod tests {
+    use super::*;
+
+    fn heartbeat() -> Message {
+        Message::Heartbeat(Heartbeat {
+            device_id: "inu.test".into(),
+            edition: "Ferric".into(),
+            build: 2,
+            uptime: 3600,
+        })
+    }
+
+    #[test]
+    fn messages_round_trip() {
+        let messages = [
+            heartbeat(),
+            Message::Discover { target: None },
+            Message::Discover {
+                target: Some("inu.test".into()),
+            },
+            Message::Trigger(Trigger {
+                device_id: "inu.test".into(),
+                code: 12,
+            }),
+            Message::Action(Action {
+                device_id: "inu.test".into(),
+                target: Some("inu.lights".into()),
+                code: 0xbeef,
+            }),
+            Message::Action(Action {
+                device_id: "inu.test".into(),
+                target: None,
+                code: 1,
+            }),
+            Message::CrashReport(CrashReport {
+                device_id: "inu.test".into(),
+                reset_reason: ResetReason::Panic,
+                error_code: 0,
+                detail: "index out of bounds at src/main.rs:12:5".into(),
+            }),
+            Message::Log(LogEntry {
+                device_id: "inu.test".into(),
+                level: Level::Debug,
+                target: "inu.net".into(),
+                message: "Scanning".into(),
+            }),
+        ];
+
+        for m in messages {
+            assert_eq!(Message::decode(&m.encode().unwrap()).unwrap(), m);
+        }
+    }
+
+    #[test]
+    fn heartbeat_wire_format() {
+        let mut expected = b"INU\x01\x01".to_vec();
+        expected.extend_from_slice(b"\x08inu.test\x06Ferric");
+        expected.extend_from_slice(&[0, 0, 0, 2, 0, 0, 0x0e, 0x10]);
+
+        assert_eq!(heartbeat().encode().unwrap(), expected);
+    }
+
+    #[test]
+    fn malformed_packets_are_rejected() {
+        let packet = heartbeat().encode().unwrap();
+        let decode = |p: &[u8]| Message::decode(p).unwrap_err();
+
+        assert_eq!(decode(b"IN"), ProtocolError::BadMagic);
+        assert_eq!(decode(b"NUI\x01\x01"), ProtocolError::BadMagic);
+        assert_eq!(decode(b"INU\x09\x01"), ProtocolError::UnsupportedVersion(9));
+        assert_eq!(decode(b"INU\x01\x7f"), ProtocolError::UnknownKind(0x7f));
+        assert_eq!(
+            decode(&packet[..packet.len() - 1]),
+            ProtocolError::Truncated
+        );
+        assert_eq!(
+            decode(b"INU\x01\x02\x02\xff\xfe"),
+            ProtocolError::InvalidString
+        );
+
+        let mut long = packet.clone();
+        long.push(0);
+        assert_eq!(decode(&long), ProtocolError::TrailingBytes(1));
+
+        assert_eq!(
+            decode(b"INU\x01\x06\x00\x09\x00\x00"),
+            ProtocolError::InvalidLevel(9)
+        );
+
+        let too_long = Message::Discover {
+            target: Some("x".repeat(256)),
+        };
+        assert_eq!(too_long.encode().unwrap_err(), ProtocolError::TooLong);
+        assert_eq!(fit("é".repeat(200)).len(), 254);
+    }
+}
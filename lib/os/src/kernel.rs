@@ -1,120 +1,417 @@
-use core::str::FromStr;
-use std::sync::{Arc, Mutex};
+use std::convert::Infallible;
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Mutex, OnceLock, PoisonError};
 use std::thread::JoinHandle;
 use std::time::Duration;
 
-use esp_idf_hal::cpu;
-use esp_idf_hal::task::thread::ThreadSpawnConfiguration;
-use esp_idf_svc::eventloop::EspSystemEventLoop;
-use esp_idf_svc::hal::modem;
-use esp_idf_svc::wifi::{AuthMethod, BlockingWifi, ClientConfiguration, Configuration, EspWifi};
+use log::LevelFilter;
 
-use crate::error::OsError;
+use crate::boot::{BootCounter, FailurePolicy, Recover};
+use crate::connectivity::{Connectivity, ConnectivityEvent};
+use crate::crash::{self, CrashLog, CrashRecord};
+use crate::error::{OsError, SettingsError};
+use crate::flash::{Flash, Readable, Writable};
+use crate::hal::ota::ImageState;
+use crate::hal::{Core, Platform, ThreadOptions, WakeCause, WakeSource};
+use crate::health::{self, Health, HealthReport};
+use crate::inu::protocol::Message;
+use crate::inu::{Identity, InuService};
+use crate::logging::sink::{InuSink, SyslogSink};
+use crate::logging::{self, Filter, Sink};
 use crate::networking::Networking;
+use crate::ota::{self, BootCheck, Ota, ReleaseKey, UpdatePolicy, UpdateRequest};
 use crate::pin_mgr::PinManager;
-use crate::settings::Settings;
-use crate::types::{OnlineSemaphore, WifiState};
+use crate::power::{self, RtcMemory};
+use crate::provisioning;
+use crate::settings::schema::{
+    KEY_CLOCK, KEY_LIGHT_SLEEP, KEY_LOG_LEVELS, KEY_LOG_REMOTE_LVL, KEY_MIN_BUILD,
+};
+use crate::settings::{RemoteSink, SettingValue, Settings};
+use crate::supervisor::{ServiceFn, ServiceSpec, ServiceStatus, Supervisor};
+use crate::types::WifiState;
+
+#[cfg(feature = "esp32s3")]
+use crate::hal::esp32s3::EspPlatform;
 
 const LOG_TGT: &str = "inu.kernel";
 
+/// How often the health monitor samples & logs task and heap health.
+const HEALTH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Flash namespace holding the build of an installed image until it is confirmed.
+const OTA_PARTITION: &str = "cfg";
+const OTA_NAMESPACE: &str = "ota";
+const KEY_PENDING_BUILD: &str = "pending_build";
+
+/// State of the kernel, kept outside of any instance as provisioning & halting happen without one.
+static STATE: Mutex<KernelState> = Mutex::new(KernelState::Running);
+
+/// What the kernel is doing, beyond running the application, for status displays.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KernelState {
+    /// Booting or running normally.
+    Running,
+    /// Hosting the provisioning portal, in place of booting.
+    Provisioning,
+    /// Installing a firmware update, restarting once installed.
+    Updating,
+    /// Halted by a fatal error.
+    Halted,
+}
+
 pub struct Kernel {
     pub pin_mgr: PinManager,
     settings: Settings,
-    online: OnlineSemaphore,
-    _net_handle: JoinHandle<()>,
-    _sysloop: EspSystemEventLoop,
+    connectivity: Arc<Connectivity>,
+    platform: Arc<dyn Platform>,
+    inu: OnceLock<InuService>,
+    ota: Option<Arc<Ota>>,
+    boot_failures: u32,
+    crash_log: Option<Arc<CrashLog>>,
+    last_boot: Option<CrashRecord>,
+    wake_cause: Option<WakeCause>,
+    health: Arc<Health>,
+    supervisor: Supervisor,
 }
 
 impl Kernel {
-    /// Create a new kernel instance.
-    ///
-    /// If there are any errors during the creation, the device will enter a death loop.
+    /// Create a new kernel instance on the ESP32-S3, handling failures with the default `FailurePolicy`.
     ///
     /// # Safety
     /// Singleton. Create only once.
+    #[cfg(feature = "esp32s3")]
     pub unsafe fn new() -> Self {
-        log::info!("Kernel running on core {}", cpu::core() as i32);
+        Self::with_policy(FailurePolicy::default())
+    }
 
-        let sysloop = match EspSystemEventLoop::take() {
-            Ok(s) => s,
-            Err(e) => {
-                log::error!(target: LOG_TGT, "Failed to take system event loop: {:?}", e);
-                Self::death_loop();
+    /// Create a new kernel instance on the ESP32-S3, handling failures with the given policy.
+    ///
+    /// # Safety
+    /// Singleton. Create only once.
+    #[cfg(feature = "esp32s3")]
+    pub unsafe fn with_policy(policy: FailurePolicy) -> Self {
+        let platform = EspPlatform::take().unwrap_or_else(|e| {
+            log::error!(target: LOG_TGT, "Failed to take device peripherals: {:?}", e);
+            Self::death_loop();
+        });
+
+        Self::boot(Arc::new(platform), policy)
+    }
+
+    /// Create a new kernel instance on the ESP32-S3, returning any error to the caller.
+    ///
+    /// # Safety
+    /// Singleton. Create only once.
+    #[cfg(feature = "esp32s3")]
+    pub unsafe fn try_new() -> Result<Self, OsError> {
+        Self::try_with_platform(Arc::new(EspPlatform::take()?))
+    }
+
+    /// Create a new kernel instance on the given hardware backend, handling failures with the default
+    /// `FailurePolicy`.
+    pub fn with_platform(platform: Arc<dyn Platform>) -> Self {
+        Self::boot(platform, FailurePolicy::default())
+    }
+
+    /// Create a new kernel instance on the given hardware backend.
+    ///
+    /// Consecutive failed boots are counted in flash. A device that has not been provisioned, or that the policy
+    /// puts in safe mode, hosts the provisioning portal. Any other failure is handled by the policy; this only
+    /// returns once the kernel has started.
+    pub fn boot(platform: Arc<dyn Platform>, policy: FailurePolicy) -> Self {
+        let mut counter = BootCounter::new(platform.as_ref())
+            .map_err(|e| log::error!(target: LOG_TGT, "Failed to open boot counter: {:?}", e))
+            .ok();
+        let failures = match counter.as_mut().map(BootCounter::begin) {
+            Some(Ok(n)) => n,
+            Some(Err(e)) => {
+                log::error!(target: LOG_TGT, "Failed to update boot counter: {:?}", e);
+                0
             }
+            None => 0,
         };
 
-        let settings = Settings::new().unwrap_or_else(|e| {
-            log::error!(target: LOG_TGT, "Failed to read settings: {:?}", e);
-            Self::death_loop();
-        });
+        if failures > 0 {
+            log::warn!(target: LOG_TGT, "Previous {} boot(s) failed", failures);
+        }
+
+        if policy.safe_mode(failures) {
+            log::warn!(target: LOG_TGT, "Entering safe mode");
+            Self::provision(platform.as_ref());
+        }
 
-        let modem = unsafe { modem::Modem::new() };
-        let esp_wifi = match EspWifi::new(modem, sysloop.clone(), None) {
-            Ok(w) => w,
+        match Self::try_with_platform(platform.clone()) {
+            Ok(mut kernel) => {
+                if let Some(c) = counter.as_mut() {
+                    if let Err(e) = c.clear() {
+                        log::error!(target: LOG_TGT, "Failed to clear boot counter: {:?}", e);
+                    }
+                }
+                kernel.boot_failures = failures;
+                kernel
+            }
+            Err(OsError::Settings(SettingsError::Missing(key))) => {
+                log::warn!(target: LOG_TGT, "Setting '{}' missing, device needs provisioning", key);
+                Self::provision(platform.as_ref());
+            }
             Err(e) => {
-                log::error!(target: LOG_TGT, "Failed to create wifi instance: {:?}", e);
-                Self::death_loop();
+                log::error!(target: LOG_TGT, "Kernel failed to start: {:?}", e);
+
+                match policy.recover(failures + 1) {
+                    Recover::Halt => Self::death_loop(),
+                    Recover::Restart(delay) => {
+                        log::warn!(target: LOG_TGT, "Restarting in {:?}", delay);
+                        std::thread::sleep(delay);
+                        platform.restart();
+                    }
+                }
+            }
+        }
+    }
+
+    /// Create a new kernel instance on the given hardware backend, returning any error to the caller.
+    ///
+    /// A device that has not been provisioned fails with `SettingsError::Missing`.
+    pub fn try_with_platform(platform: Arc<dyn Platform>) -> Result<Self, OsError> {
+        log::info!("Kernel running on core {:?}", platform.current_core());
+
+        let (crash_log, last_boot) = Self::open_crash_log(platform.as_ref());
+        let result = Self::start(platform, crash_log.clone(), last_boot);
+
+        // An unprovisioned device isn't a failure, it's on its way to the provisioning portal
+        if let (Err(e), Some(log)) = (&result, &crash_log) {
+            if !matches!(e, OsError::Settings(SettingsError::Missing(_))) {
+                if let Err(e) = log.record_error(e) {
+                    log::error!(target: LOG_TGT, "Failed to record boot failure: {:?}", e);
+                }
+            }
+        }
+
+        result
+    }
+
+    /// Open the crash log & record this boot in it.
+    fn open_crash_log(platform: &dyn Platform) -> (Option<Arc<CrashLog>>, Option<CrashRecord>) {
+        let log = match CrashLog::new(platform) {
+            Ok(log) => Arc::new(log),
+            Err(e) => {
+                log::error!(target: LOG_TGT, "Failed to open crash log: {:?}", e);
+                return (None, None);
             }
         };
+        crash::install_panic_hook(log.clone());
 
-        let mut wifi = match BlockingWifi::wrap(esp_wifi, sysloop.clone()) {
-            Ok(w) => w,
+        match log.record_boot(platform.reset_reason()) {
+            Ok(record) => {
+                if record.is_crash() {
+                    log::warn!(target: LOG_TGT, "Recovered from a crash: {:?}", record);
+                }
+                (Some(log), Some(record))
+            }
             Err(e) => {
-                log::error!(target: LOG_TGT, "Failed to create async wifi instance: {:?}", e);
-                Self::death_loop();
+                log::error!(target: LOG_TGT, "Failed to record boot: {:?}", e);
+                (Some(log), None)
+            }
+        }
+    }
+
+    fn start(
+        platform: Arc<dyn Platform>,
+        crash_log: Option<Arc<CrashLog>>,
+        last_boot: Option<CrashRecord>,
+    ) -> Result<Self, OsError> {
+        let settings = Settings::new(platform.as_ref())?;
+        if let Some(logger) = logging::logger() {
+            logger.set_console_filter(settings.logging.levels.clone());
+        }
+        Self::apply_power(platform.as_ref(), &settings);
+        let wake_cause = platform.wake_cause();
+        if let Some(cause) = &wake_cause {
+            log::info!(target: LOG_TGT, "Woken from deep sleep by {:?}", cause);
+        }
+        let wifi = platform.wifi()?;
+
+        let connectivity = Arc::new(Connectivity::new());
+        let mut networking = Networking::new(wifi, settings.wifi.networks(), connectivity.clone())?
+            .with_clock(platform.clock())
+            .with_seed(platform.random());
+
+        let health = Health::new(platform.clone());
+        let supervisor = Supervisor::new(health.clone());
+        supervisor.register(
+            ServiceSpec::new("net", Self::options(5, Some(Core::Core1), 2048)),
+            Box::new(move || {
+                health::watch()?;
+                networking.run()
+            }),
+        )?;
+
+        let monitor = health.clone();
+        supervisor.register(
+            ServiceSpec::new("health", Self::options(2, None, 3072)),
+            Box::new(move || monitor.run(HEALTH_INTERVAL)),
+        )?;
+        supervisor.start()?;
+
+        let ota = match platform.ota() {
+            Ok(driver) => Some(Arc::new(Ota::new(driver))),
+            Err(e) => {
+                log::warn!(target: LOG_TGT, "Firmware updates unavailable: {:?}", e);
+                None
             }
         };
 
-        let ssid = heapless::String::<32>::from_str(settings.wifi.access_point.as_str()).unwrap();
-        let pw = heapless::String::<64>::from_str(settings.wifi.password.as_str()).unwrap();
-
-        // TODO: make the bssid, auth method & channel configurable
-        let wifi_configuration: Configuration = Configuration::Client(ClientConfiguration {
-            ssid,
-            bssid: None,
-            auth_method: AuthMethod::WPA2Personal,
-            password: pw,
-            channel: None,
-            ..Default::default()
-        });
+        if let Some(ota) = &ota {
+            Self::check_boot(platform.clone(), ota.clone(), connectivity.clone());
+        }
+
+        Ok(Self {
+            pin_mgr: unsafe { PinManager::new(platform.gpio()) },
+            settings,
+            connectivity,
+            platform,
+            inu: OnceLock::new(),
+            ota,
+            boot_failures: 0,
+            crash_log,
+            last_boot,
+            wake_cause,
+            health,
+            supervisor,
+        })
+    }
+
+    /// What the kernel is doing. This is process-wide, so can be followed before the kernel has booted.
+    pub fn state() -> KernelState {
+        *STATE.lock().unwrap_or_else(PoisonError::into_inner)
+    }
 
-        wifi.set_configuration(&wifi_configuration)
-            .unwrap_or_else(|e| {
-                log::error!(target: LOG_TGT, "Failed to set wifi configuration: {:?}", e);
+    fn set_state(state: KernelState) {
+        log::debug!(target: LOG_TGT, "Kernel {:?}", state);
+        *STATE.lock().unwrap_or_else(PoisonError::into_inner) = state;
+    }
+
+    /// Host the provisioning portal, restarting the device once it has been configured.
+    fn provision(platform: &dyn Platform) -> ! {
+        Self::set_state(KernelState::Provisioning);
+        let result = platform
+            .wifi()
+            .and_then(|mut wifi| provisioning::run(platform, wifi.as_mut()));
+
+        match result {
+            Ok(_) => {
+                log::info!(target: LOG_TGT, "Device provisioned");
+
+                // Boot the new settings normally, rather than back into safe mode
+                if let Err(e) = BootCounter::new(platform).and_then(|mut c| c.clear()) {
+                    log::error!(target: LOG_TGT, "Failed to clear boot counter: {:?}", e);
+                }
+                platform.restart();
+            }
+            Err(e) => {
+                log::error!(target: LOG_TGT, "Provisioning failed: {:?}", e);
                 Self::death_loop();
-            });
+            }
+        }
+    }
 
-        let online = Arc::new(Mutex::new(Default::default()));
-        let nw_online = online.clone();
+    /// If this is the first boot of a new image, confirm it once online or roll it back.
+    fn check_boot(platform: Arc<dyn Platform>, ota: Arc<Ota>, connectivity: Arc<Connectivity>) {
+        match ota.running_slot() {
+            Ok(slot) if slot.state == ImageState::PendingVerify => {}
+            Ok(_) => return,
+            Err(e) => {
+                log::error!(target: LOG_TGT, "Failed to read running image state: {:?}", e);
+                return;
+            }
+        }
 
-        let networking = Self::new_thread(5, Some(cpu::Core::Core1), 2048, move || {
-            let mut nw = Networking::new(wifi, nw_online);
-            nw.run();
-        })
-        .unwrap_or_else(|e| {
-            log::error!(target: LOG_TGT, "Failed to start networking task: {:?}", e);
-            Self::death_loop();
-        });
+        let result = platform.clone().spawn(
+            ThreadOptions {
+                priority: 4,
+                core: None,
+                stack_size: 4096,
+            },
+            Box::new(
+                move || match ota.check_boot(&connectivity, ota::HEALTH_CHECK_TIMEOUT) {
+                    Ok(BootCheck::Confirmed) => {
+                        if let Err(e) = Self::raise_min_build(platform.as_ref()) {
+                            log::error!(target: LOG_TGT, "Failed to raise minimum build: {:?}", e);
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(e) => log::error!(target: LOG_TGT, "Boot health check failed: {:?}", e),
+                },
+            ),
+        );
 
-        Self {
-            pin_mgr: PinManager::new(),
-            settings,
-            online,
-            _net_handle: networking,
-            _sysloop: sysloop,
+        if let Err(e) = result {
+            log::error!(target: LOG_TGT, "Failed to start boot health check: {:?}", e);
+        }
+    }
+
+    /// Raise `Settings::min_build` to the build of a newly confirmed image, so that older images are rejected.
+    ///
+    /// Only the stored setting is raised. The kernel's copy is stale until the next boot, but no update can be
+    /// installed until then, as the image runs from the only OTA slot.
+    fn raise_min_build(platform: &dyn Platform) -> Result<(), OsError> {
+        let mut flash = Flash::new(platform, OTA_PARTITION, OTA_NAMESPACE)?;
+        if !flash.contains(KEY_PENDING_BUILD)? {
+            return Ok(());
+        }
+
+        let build: u32 = flash.read(KEY_PENDING_BUILD)?;
+        let mut settings = Settings::new(platform)?;
+        if build > settings.min_build {
+            settings.write_setting(KEY_MIN_BUILD, SettingValue::U32(build))?;
+            log::info!(target: LOG_TGT, "Minimum build raised to {}", build);
         }
+
+        flash.remove(KEY_PENDING_BUILD)?;
+        Ok(())
+    }
+
+    /// Number of consecutive failed boots before this one.
+    pub fn boot_failures(&self) -> u32 {
+        self.boot_failures
+    }
+
+    /// Log of recent boots & the crashes behind them, unless it couldn't be opened.
+    pub fn crash_log(&self) -> Option<&Arc<CrashLog>> {
+        self.crash_log.as_ref()
+    }
+
+    /// This boot & the cause of the reset before it.
+    pub fn last_boot(&self) -> Option<&CrashRecord> {
+        self.last_boot.as_ref()
     }
 
     /// Check if the device is online.
     pub fn is_online(&self) -> bool {
-        let online = self.online.lock().unwrap();
-        matches!(*online, WifiState::Connected(_))
+        self.connectivity.is_online()
     }
 
-    /// Return wifi connection state.
+    /// Return wifi connection state & link statistics.
     pub fn wifi_state(&self) -> WifiState {
-        let online = self.online.lock().unwrap();
-        *online
+        self.connectivity.state()
+    }
+
+    /// Receive an event whenever the WiFi link changes state. Dropping the receiver ends the subscription.
+    pub fn subscribe_connectivity(&self) -> Receiver<ConnectivityEvent> {
+        self.connectivity.subscribe()
+    }
+
+    /// Call `f` whenever the WiFi link changes state. The callback runs on the networking task, so must not block.
+    pub fn on_connectivity_change<F>(&self, f: F)
+    where
+        F: FnMut(&ConnectivityEvent) + Send + 'static,
+    {
+        self.connectivity.on_change(f);
+    }
+
+    /// Shared connectivity state, for services that outlive a borrow of the kernel.
+    pub fn connectivity(&self) -> Arc<Connectivity> {
+        self.connectivity.clone()
     }
 
     /// Borrow the device settings.
@@ -122,10 +419,273 @@ impl Kernel {
         &self.settings
     }
 
+    /// The hardware backend the kernel is running on.
+    pub fn platform(&self) -> &Arc<dyn Platform> {
+        &self.platform
+    }
+
     /// Hard restart of the device.
-    pub fn restart() -> ! {
+    pub fn restart(&self) -> ! {
         log::warn!(target: LOG_TGT, "Restarting device..");
-        esp_idf_svc::hal::reset::restart();
+        self.platform.restart();
+    }
+
+    /// Enter deep sleep until one of the sources wakes the device, which then boots afresh. Save anything to be kept
+    /// to `rtc_memory` first.
+    ///
+    /// Only returns if the wake sources are invalid or can't be armed.
+    pub fn deep_sleep(&self, sources: &[WakeSource]) -> Result<Infallible, OsError> {
+        let sources = power::wake_sources(sources)?;
+        self.platform.set_wake_sources(&sources)?;
+
+        log::warn!(target: LOG_TGT, "Entering deep sleep, waking on {:?}", sources);
+        self.platform.deep_sleep();
+    }
+
+    /// What woke the device from deep sleep, if this boot was a wake.
+    pub fn wake_cause(&self) -> Option<&WakeCause> {
+        self.wake_cause.as_ref()
+    }
+
+    /// State kept across deep sleep.
+    pub fn rtc_memory(&self) -> RtcMemory {
+        RtcMemory::new(self.platform.clone())
+    }
+
+    /// Start the Inu protocol service, announcing this device with the given edition & build.
+    ///
+    /// Returns a handle to the service, for publishing triggers & registering handlers. Starting it again returns the
+    /// running service.
+    pub fn start_inu(&self, edition: &str, build: u32) -> Result<InuService, OsError> {
+        if let Some(inu) = self.inu.get() {
+            return Ok(inu.clone());
+        }
+
+        let service = InuService::new(
+            Identity {
+                device_id: self.settings.device_id.clone(),
+                edition: edition.to_string(),
+                build,
+            },
+            self.platform.clock(),
+        );
+        if let Some(record) = self.last_boot.as_ref().filter(|r| r.is_crash()) {
+            service.announce(Message::CrashReport(
+                record.report(&self.settings.device_id),
+            ));
+        }
+
+        let connectivity = self.connectivity.clone();
+        let runner = service.clone();
+
+        self.add_service(
+            ServiceSpec::new("inu", Self::options(5, Some(Core::Core1), 4096))
+                .with_dependency("net"),
+            Box::new(move || {
+                health::watch()?;
+                runner.run(connectivity.clone())
+            }),
+        )?;
+
+        let _ = self.inu.set(service.clone());
+        Ok(service)
+    }
+
+    /// Run the CPU at the given clock in MHz, one of 80, 160 or 240, light sleeping when idle if enabled. The
+    /// configuration is saved to `Settings`, so applies from boot after a restart.
+    pub fn set_power(&mut self, cpu_clock: u16, light_sleep: bool) -> Result<(), OsError> {
+        let previous = (self.settings.cpu_clock, self.settings.light_sleep);
+        self.settings.cpu_clock = cpu_clock;
+        self.settings.light_sleep = light_sleep;
+
+        let result = self
+            .settings
+            .power()
+            .map_err(OsError::from)
+            .and_then(|config| {
+                let settings = &mut self.settings;
+                settings.write_setting(KEY_CLOCK, SettingValue::U16(cpu_clock))?;
+                settings.write_setting(KEY_LIGHT_SLEEP, SettingValue::U16(light_sleep as u16))?;
+                Ok(config)
+            });
+
+        match result {
+            Ok(config) => self.platform.configure_power(&config),
+            Err(e) => {
+                (self.settings.cpu_clock, self.settings.light_sleep) = previous;
+                Err(e)
+            }
+        }
+    }
+
+    /// Configure CPU frequency & light sleep from `Settings`. The device still runs at its default clock if this fails.
+    fn apply_power(platform: &dyn Platform, settings: &Settings) {
+        let result = settings
+            .power()
+            .map_err(OsError::from)
+            .and_then(|config| platform.configure_power(&config).map(|_| config));
+
+        match result {
+            Ok(config) => log::info!(
+                target: LOG_TGT,
+                "CPU clock {}-{} MHz, light sleep {}",
+                config.min_mhz,
+                config.max_mhz,
+                if config.light_sleep { "on" } else { "off" }
+            ),
+            Err(e) => log::error!(
+                target: LOG_TGT,
+                "Failed to configure power management: {}",
+                crash::describe(&e)
+            ),
+        }
+    }
+
+    /// Console log levels, by target.
+    pub fn log_levels(&self) -> &Filter {
+        &self.settings.logging.levels
+    }
+
+    /// Set the console log level of a target & the targets below it, or the default level if no target is given.
+    pub fn set_log_level(
+        &mut self,
+        target: Option<&str>,
+        level: LevelFilter,
+    ) -> Result<(), OsError> {
+        let mut levels = self.settings.logging.levels.clone();
+        match target {
+            Some(target) => levels.set(target, level),
+            None => levels.set_default(level),
+        }
+        self.set_log_levels(levels)
+    }
+
+    /// Return a target to the default console log level.
+    pub fn reset_log_level(&mut self, target: &str) -> Result<(), OsError> {
+        let mut levels = self.settings.logging.levels.clone();
+        levels.reset(target);
+        self.set_log_levels(levels)
+    }
+
+    /// Replace the console log levels. Levels are saved to `Settings`, so apply from boot after a restart.
+    pub fn set_log_levels(&mut self, levels: Filter) -> Result<(), OsError> {
+        let value = SettingValue::Str(levels.to_string());
+        let previous = std::mem::replace(&mut self.settings.logging.levels, levels);
+        if let Err(e) = self.settings.write_setting(KEY_LOG_LEVELS, value) {
+            self.settings.logging.levels = previous;
+            return Err(e);
+        }
+
+        if let Some(logger) = logging::logger() {
+            logger.set_console_filter(self.settings.logging.levels.clone());
+        }
+        Ok(())
+    }
+
+    /// Replace the levels of records streamed to the network, saving them to `Settings`.
+    pub fn set_remote_log_levels(&mut self, levels: Filter) -> Result<(), OsError> {
+        let value = SettingValue::Str(levels.to_string());
+        let previous = std::mem::replace(&mut self.settings.logging.remote_filter, levels);
+        if let Err(e) = self.settings.write_setting(KEY_LOG_REMOTE_LVL, value) {
+            self.settings.logging.remote_filter = previous;
+            return Err(e);
+        }
+
+        if let Some(logger) = logging::logger() {
+            logger.set_remote_filter(self.settings.logging.remote_filter.clone());
+        }
+        Ok(())
+    }
+
+    /// Stream logs to the network sink configured in `Settings`. If there is none, records stop being held for the
+    /// network.
+    ///
+    /// Requires the logger to be installed with `logging::init`. Streaming to the Inu network requires the Inu service
+    /// to be started first. Call once.
+    pub fn start_remote_log(&self) -> Result<(), OsError> {
+        let logger =
+            logging::logger().ok_or_else(|| OsError::Generic("Logger not installed".into()))?;
+        let config = &self.settings.logging;
+
+        let mut sink: Box<dyn Sink> = match &config.remote {
+            None => {
+                logger.disable_remote();
+                return Ok(());
+            }
+            Some(RemoteSink::Syslog { host, port }) => {
+                Box::new(SyslogSink::new(host, *port, &self.settings.device_id))
+            }
+            Some(RemoteSink::Inu) => {
+                Box::new(InuSink::new(self.inu().cloned().ok_or_else(|| {
+                    OsError::Generic("Inu service not started".into())
+                })?))
+            }
+        };
+
+        logger.set_remote_filter(config.remote_filter.clone());
+        let connectivity = self.connectivity.clone();
+        self.add_service(
+            ServiceSpec::new("log", Self::options(3, None, 4096)).with_dependency("net"),
+            Box::new(move || {
+                health::watch()?;
+                logger.remote().run(sink.as_mut(), &connectivity)
+            }),
+        )?;
+
+        log::info!(target: LOG_TGT, "Streaming logs to {}", config.remote.as_ref().unwrap());
+        Ok(())
+    }
+
+    /// The Inu protocol service, once started.
+    pub fn inu(&self) -> Option<&InuService> {
+        self.inu.get()
+    }
+
+    /// Firmware update service, if the platform supports it.
+    pub fn ota(&self) -> Option<&Arc<Ota>> {
+        self.ota.as_ref()
+    }
+
+    /// Download & install a firmware image, then restart into it.
+    ///
+    /// The image must be of the given edition & no older than the running build or `Settings::min_build`, which is
+    /// raised to the build of the image once it has booted & been confirmed. The image must be signed by the release
+    /// key. Only returns if the update fails, in which case the running image is left in place.
+    pub fn update_firmware(
+        &mut self,
+        request: &UpdateRequest,
+        edition: &str,
+        build: u32,
+        release_key: &ReleaseKey,
+    ) -> Result<(), OsError> {
+        let ota = self
+            .ota
+            .as_ref()
+            .ok_or_else(|| OsError::Generic("Firmware updates unavailable".into()))?;
+
+        let policy = UpdatePolicy {
+            edition,
+            // A device flashed over USB may run a build newer than any update confirmed
+            min_build: self.settings.min_build.max(build),
+            release_key,
+        };
+
+        policy.check(&request.manifest)?;
+
+        // Recorded before the image is activated, so that nothing can fail once it has been
+        let mut flash = Flash::new(self.platform.as_ref(), OTA_PARTITION, OTA_NAMESPACE)?;
+        flash.write(KEY_PENDING_BUILD, request.manifest.build)?;
+
+        Self::set_state(KernelState::Updating);
+        if let Err(e) = ota.update(self.platform.as_ref(), request, &policy) {
+            Self::set_state(KernelState::Running);
+            if let Err(e) = flash.remove(KEY_PENDING_BUILD) {
+                log::error!(target: LOG_TGT, "Failed to clear pending build: {:?}", e);
+            }
+            return Err(e);
+        }
+
+        self.restart();
     }
 
     /// Display welcome info to the device logger.
@@ -135,40 +695,311 @@ impl Kernel {
         log::info!(target: LOG_TGT, " * Access Point:   {}", self.get_settings().wifi.access_point);
     }
 
+    /// Record an unrecoverable error in the crash log, then halt the device.
+    pub fn fatal(&self, error: OsError) -> ! {
+        log::error!(target: LOG_TGT, "Fatal error: {}", crash::describe(&error));
+
+        if let Some(log) = &self.crash_log {
+            if let Err(e) = log.record_error(&error) {
+                log::error!(target: LOG_TGT, "Failed to record fatal error: {:?}", e);
+            }
+        }
+
+        Self::death_loop();
+    }
+
     /// Call this when you encounter an unrecoverable error. This will halt the device.
     /// It is better to call this than to panic, a panic will typically end up in a restart-loop.
     pub fn death_loop() -> ! {
+        Self::set_state(KernelState::Halted);
         log::error!(target: LOG_TGT, "Death loop commenced");
         loop {
             std::thread::sleep(Duration::from_secs(1));
         }
     }
 
+    /// Register a long-running service & start it once its dependencies have started. The service is supervised,
+    /// restarted according to its [`RestartPolicy`] when it exits. A panic is fatal, restarting the device.
+    ///
+    /// [`RestartPolicy`]: crate::supervisor::RestartPolicy
+    pub fn add_service(&self, spec: ServiceSpec, run: ServiceFn) -> Result<(), OsError> {
+        self.supervisor.register(spec, run)?;
+        self.supervisor.start()
+    }
+
+    /// Status of every service, including those of the OS.
+    pub fn services(&self) -> Vec<ServiceStatus> {
+        self.supervisor.status()
+    }
+
+    /// Heap usage & the stack and watchdog health of every thread spawned by the kernel.
+    pub fn health(&self) -> HealthReport {
+        self.health.report()
+    }
+
+    fn options(priority: u8, core: Option<Core>, stack_size: usize) -> ThreadOptions {
+        ThreadOptions {
+            priority,
+            core,
+            stack_size,
+        }
+    }
+
     /// Creates a new thread (FreeRTOS task) with given priority, core & stack size.
+    ///
+    /// The thread's stack is monitored while it runs. It can subscribe to the task watchdog with `health::watch`.
     pub fn new_thread<T>(
+        &self,
         priority: u8,
-        core: Option<cpu::Core>,
+        core: Option<Core>,
         stack_size: usize,
         f: T,
     ) -> Result<JoinHandle<()>, OsError>
     where
         T: FnOnce() + Send + 'static,
     {
-        // Set the default thread configuration
-        ThreadSpawnConfiguration {
-            stack_size,
-            priority,
-            pin_to_core: core,
-            ..Default::default()
+        self.health.spawn(
+            "thread",
+            Self::options(priority, core, stack_size),
+            Box::new(f),
+        )
+    }
+}
+
+#[cfg(all(test, feature = "host"))]
+mod tests {
+    use super::*;
+    use crate::error::{OtaError, SleepError};
+    use crate::hal::host::{HostPlatform, SimOta};
+    use crate::hal::{PowerConfig, ResetReason, Storage};
+    use crate::ota::Manifest;
+    use crate::supervisor::ServiceState;
+    use ed25519_dalek::SigningKey;
+    use sha2::{Digest, Sha256};
+    use std::panic::{self, AssertUnwindSafe};
+    use std::time::Instant;
+
+    fn wait_for(kernel: &Kernel, online: bool) -> bool {
+        let start = Instant::now();
+        while start.elapsed() < Duration::from_secs(5) {
+            if kernel.is_online() == online {
+                return true;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        false
+    }
+
+    fn provisioned() -> HostPlatform {
+        let platform = HostPlatform::new();
+        let mut cfg = platform.storage_sim("cfg", "settings");
+        cfg.set_str("device_id", "inu.test").unwrap();
+        cfg.set_str("wifi_ap", "inu-test").unwrap();
+        cfg.set_str("wifi_pw", "password").unwrap();
+        platform
+    }
+
+    #[test]
+    fn kernel_boots_and_tracks_wifi() {
+        let platform = provisioned();
+        let wifi = platform.wifi_sim();
+
+        let kernel = Kernel::with_platform(Arc::new(platform));
+        assert_eq!(kernel.get_settings().wifi.access_point, "inu-test");
+        assert!(wait_for(&kernel, true));
+        assert_eq!(wifi.configuration().unwrap().ssid.as_str(), "inu-test");
+
+        let services = kernel.services();
+        assert_eq!(services[0].name, "net");
+        assert_eq!(services[0].state, ServiceState::Running);
+        let health = kernel.health();
+        let net = health.tasks.iter().find(|t| t.name == "net").unwrap();
+        assert_eq!(net.options.stack_size, 2048);
+        assert!(net.since_feed.is_some());
+
+        let events = kernel.subscribe_connectivity();
+        wifi.set_in_range(false);
+        assert!(wait_for(&kernel, false));
+        let event = events.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(event.is_offline());
+
+        wifi.set_in_range(true);
+        assert!(wait_for(&kernel, true));
+    }
+
+    #[test]
+    fn power_settings_are_applied() {
+        let platform = Arc::new(provisioned());
+        let mut kernel = Kernel::with_platform(platform.clone());
+        let config = |max_mhz, light_sleep| PowerConfig {
+            max_mhz,
+            min_mhz: 80,
+            light_sleep,
+        };
+        assert_eq!(platform.power_config(), Some(config(160, false)));
+
+        assert!(matches!(
+            kernel.set_power(200, true),
+            Err(OsError::Settings(SettingsError::Invalid {
+                key: "clock",
+                ..
+            }))
+        ));
+        assert_eq!(kernel.get_settings().cpu_clock, 160);
+        assert_eq!(platform.power_config(), Some(config(160, false)));
+
+        kernel.set_power(240, true).unwrap();
+        assert_eq!(platform.power_config(), Some(config(240, true)));
+        let saved = Settings::new(platform.as_ref()).unwrap();
+        assert_eq!((saved.cpu_clock, saved.light_sleep), (240, true));
+    }
+
+    #[test]
+    fn wake_cause_and_state_are_kept_across_deep_sleep() {
+        let asleep = Arc::new(provisioned());
+        let kernel = Kernel::with_platform(asleep.clone());
+        assert_eq!(kernel.wake_cause(), None);
+        kernel.rtc_memory().save(&7u32).unwrap();
+        assert!(matches!(
+            kernel.deep_sleep(&[]),
+            Err(OsError::Sleep(SleepError::NoWakeSource))
+        ));
+
+        let woken = provisioned()
+            .with_wake_cause(WakeCause::Timer)
+            .with_rtc_of(&asleep);
+        let kernel = Kernel::with_platform(Arc::new(woken));
+        assert_eq!(kernel.wake_cause(), Some(&WakeCause::Timer));
+        assert_eq!(kernel.rtc_memory().load::<u32>(), Some(7));
+        assert!(!kernel.last_boot().unwrap().is_crash());
+    }
+
+    #[test]
+    fn failed_boots_are_counted_until_the_kernel_starts() {
+        let unprovisioned = Arc::new(HostPlatform::new());
+        assert!(matches!(
+            Kernel::try_with_platform(unprovisioned).err(),
+            Some(OsError::Settings(SettingsError::Missing(_)))
+        ));
+
+        let platform = Arc::new(provisioned());
+        let mut counter = BootCounter::new(platform.as_ref()).unwrap();
+        counter.begin().unwrap();
+        counter.begin().unwrap();
+
+        let kernel = Kernel::with_platform(platform.clone());
+        assert_eq!(kernel.boot_failures(), 2);
+        assert_eq!(counter.failures().unwrap(), 0);
+    }
+
+    #[test]
+    fn crash_is_reported_with_the_next_boot() {
+        let platform = provisioned();
+        let log = CrashLog::new(&platform).unwrap();
+        log.record_panic("boom", None).unwrap();
+
+        let kernel =
+            Kernel::with_platform(Arc::new(platform.with_reset_reason(ResetReason::Panic)));
+        let last_boot = kernel.last_boot().unwrap();
+        assert!(last_boot.is_crash());
+        assert_eq!(last_boot.panic.as_ref().unwrap().message, "boom");
+        assert_eq!(kernel.crash_log().unwrap().records().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn min_build_is_raised_once_the_update_is_confirmed() {
+        const URL: &str = "https://updates.example/inu.bin";
+        let image = vec![0xe9; 1024];
+        let platform = Arc::new(provisioned());
+        platform.http_sim().serve_file(URL, image.clone());
+        let sim = platform.ota_sim();
+        let signing = SigningKey::from_bytes(&[1; 32]);
+        let key = ReleaseKey::from(&signing);
+        let mut request = UpdateRequest {
+            url: URL.into(),
+            manifest: Manifest {
+                edition: "Ferric".into(),
+                build: 7,
+                size: image.len(),
+                sha256: Sha256::digest(&image).into(),
+                signature: None,
+            },
+        };
+        request.manifest.sign(&signing);
+
+        let pending = || {
+            Flash::new(platform.as_ref(), OTA_PARTITION, OTA_NAMESPACE)
+                .unwrap()
+                .contains(KEY_PENDING_BUILD)
+                .unwrap()
+        };
+        let mut kernel = Kernel::with_platform(platform.clone());
+        assert!(kernel.update_firmware(&request, "Other", 2, &key).is_err());
+        assert_eq!(Kernel::state(), KernelState::Running);
+
+        // Never below the running build, though the setting is lower
+        assert!(matches!(
+            kernel.update_firmware(&request, "Ferric", 9, &key),
+            Err(OsError::Ota(OtaError::Downgrade {
+                build: 7,
+                min_build: 9
+            }))
+        ));
+        assert_eq!(sim.boot_slot(), SimOta::FACTORY);
+        assert!(!pending());
+
+        // A failed download leaves no pending build to raise the minimum to
+        let mut corrupt = request.clone();
+        corrupt.manifest.sha256[0] ^= 1;
+        corrupt.manifest.sign(&signing);
+        assert!(matches!(
+            kernel.update_firmware(&corrupt, "Ferric", 2, &key),
+            Err(OsError::Ota(OtaError::HashMismatch))
+        ));
+        assert!(!pending());
+
+        let restarted = panic::catch_unwind(AssertUnwindSafe(|| {
+            let _ = kernel.update_firmware(&request, "Ferric", 2, &key);
+        }));
+        assert!(restarted.is_err());
+        assert_eq!(Kernel::state(), KernelState::Updating);
+        assert_eq!(sim.boot_slot(), SimOta::OTA_0);
+        assert!(pending());
+        assert_eq!(Settings::new(platform.as_ref()).unwrap().min_build, 0);
+
+        // Booting the new image, which is confirmed as the device is online
+        sim.restart();
+        assert!(wait_for(&kernel, true));
+        let ota = kernel.ota().unwrap().clone();
+        Kernel::check_boot(platform.clone(), ota.clone(), kernel.connectivity());
+
+        let start = Instant::now();
+        while Settings::new(platform.as_ref()).unwrap().min_build != 7 {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            std::thread::sleep(Duration::from_millis(10));
         }
-        .set()?;
+        assert_eq!(ota.running_slot().unwrap().state, ImageState::Valid);
+        assert!(!pending());
+    }
+
+    #[test]
+    fn log_levels_are_saved_to_settings() {
+        let platform = Arc::new(provisioned());
+        let mut kernel = Kernel::with_platform(platform.clone());
 
-        // Create the new thread
-        let thread = std::thread::Builder::new().spawn(f)?;
+        kernel
+            .set_log_level(Some("inu.net"), LevelFilter::Debug)
+            .unwrap();
+        kernel.set_log_level(None, LevelFilter::Warn).unwrap();
+        assert_eq!(
+            kernel.log_levels().level("inu.net.scan"),
+            LevelFilter::Debug
+        );
 
-        // ..and reset the default thread config
-        ThreadSpawnConfiguration::default().set()?;
+        let saved = Settings::new(platform.as_ref()).unwrap();
+        assert_eq!(saved.logging.levels.to_string(), "warn,inu.net=debug");
 
-        Ok(thread)
+        kernel.reset_log_level("inu.net").unwrap();
+        assert_eq!(kernel.log_levels().level("inu.net"), LevelFilter::Warn);
     }
 }
@@ -1,8 +1,19 @@
+pub mod boot;
+pub mod connectivity;
+pub mod crash;
 pub mod error;
 pub mod flash;
+pub mod hal;
+pub mod health;
+pub mod inu;
 pub mod kernel;
+pub mod logging;
 pub mod networking;
+pub mod ota;
 pub mod physical;
 pub mod pin_mgr;
+pub mod power;
+pub mod provisioning;
 pub mod settings;
+pub mod supervisor;
 pub mod types;
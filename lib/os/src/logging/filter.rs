@@ -0,0 +1,151 @@
+//! Per-target log level filter.
+//!
+//! A filter is written as a comma separated list of levels, eg. `warn,inu.net=debug,inu.proto=off`. A bare level sets
+//! the default, while `target=level` sets the level of a target & every target below it, so `inu.net` also covers
+//! `inu.net.scan`. The most specific target wins.
+
+use core::fmt;
+use core::str::FromStr;
+
+use log::{Level, LevelFilter};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Filter {
+    default: LevelFilter,
+    targets: Vec<(String, LevelFilter)>,
+}
+
+impl Filter {
+    /// A filter applying the same level to every target.
+    pub fn new(default: LevelFilter) -> Self {
+        Filter {
+            default,
+            targets: vec![],
+        }
+    }
+
+    /// The level of the given target.
+    pub fn level(&self, target: &str) -> LevelFilter {
+        self.targets
+            .iter()
+            .filter(|(t, _)| covers(t, target))
+            .max_by_key(|(t, _)| t.len())
+            .map_or(self.default, |(_, level)| *level)
+    }
+
+    pub fn enabled(&self, target: &str, level: Level) -> bool {
+        level <= self.level(target)
+    }
+
+    /// The most verbose level of any target.
+    pub fn max_level(&self) -> LevelFilter {
+        self.targets
+            .iter()
+            .map(|(_, level)| *level)
+            .fold(self.default, Ord::max)
+    }
+
+    pub fn default_level(&self) -> LevelFilter {
+        self.default
+    }
+
+    pub fn set_default(&mut self, level: LevelFilter) {
+        self.default = level;
+    }
+
+    /// Set the level of a target & every target below it.
+    pub fn set(&mut self, target: &str, level: LevelFilter) {
+        match self.targets.iter_mut().find(|(t, _)| t == target) {
+            Some((_, l)) => *l = level,
+            None => self.targets.push((target.to_string(), level)),
+        }
+    }
+
+    /// Return a target to the default level.
+    pub fn reset(&mut self, target: &str) {
+        self.targets.retain(|(t, _)| t != target);
+    }
+}
+
+impl Default for Filter {
+    fn default() -> Self {
+        Filter::new(LevelFilter::Info)
+    }
+}
+
+/// Whether a filter on `prefix` applies to `target`.
+fn covers(prefix: &str, target: &str) -> bool {
+    match target.strip_prefix(prefix) {
+        Some(rest) => rest.is_empty() || rest.starts_with('.') || rest.starts_with("::"),
+        None => false,
+    }
+}
+
+impl FromStr for Filter {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let mut filter = Filter::default();
+        let parse_level = |level: &str| {
+            LevelFilter::from_str(level.trim())
+                .map_err(|_| format!("'{}' is not a log level", level.trim()))
+        };
+
+        for directive in spec.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            match directive.split_once('=') {
+                Some((target, level)) => {
+                    let target = target.trim();
+                    if target.is_empty() || target.contains(char::is_whitespace) {
+                        return Err(format!("'{}' is not a log target", target));
+                    }
+                    filter.set(target, parse_level(level)?);
+                }
+                None => filter.default = parse_level(directive)?,
+            }
+        }
+
+        Ok(filter)
+    }
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.default.as_str().to_lowercase())?;
+        for (target, level) in &self.targets {
+            write!(f, ",{}={}", target, level.as_str().to_lowercase())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, feature = "host"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn most_specific_target_wins() {
+        let filter: Filter = "warn, inu.net=debug, inu.net.scan=off, inu=info"
+            .parse()
+            .unwrap();
+
+        assert_eq!(filter.level("other"), LevelFilter::Warn);
+        assert_eq!(filter.level("inu.kernel"), LevelFilter::Info);
+        assert_eq!(filter.level("inu.net"), LevelFilter::Debug);
+        assert_eq!(filter.level("inu.net.scan"), LevelFilter::Off);
+        assert_eq!(filter.level("inu.network"), LevelFilter::Info);
+        assert_eq!(filter.max_level(), LevelFilter::Debug);
+        assert!(filter.enabled("inu.net", Level::Debug));
+        assert!(!filter.enabled("inu.kernel", Level::Debug));
+
+        assert_eq!(
+            filter.to_string(),
+            "warn,inu.net=debug,inu.net.scan=off,inu=info"
+        );
+        assert_eq!(filter.to_string().parse::<Filter>().unwrap(), filter);
+        assert_eq!("".parse::<Filter>().unwrap(), Filter::default());
+
+        assert!("loud".parse::<Filter>().is_err());
+        assert!("inu.net=loud".parse::<Filter>().is_err());
+        assert!("=debug".parse::<Filter>().is_err());
+    }
+}
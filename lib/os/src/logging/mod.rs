@@ -0,0 +1,399 @@
+//! Device logging.
+//!
+//! `Logger` is installed as the global `log` logger with `init`. Records passing the console filter are passed to a
+//! console logger, typically `Console` on the serial port, and records passing the remote filter are also queued for a
+//! network `Sink`: a syslog server or the Inu network. Both filters can be changed at runtime. The queue holds records
+//! while the device is offline, dropping the oldest once full, and is drained by `RemoteLog::run` on its own thread.
+
+use std::collections::VecDeque;
+use std::io::Write;
+use std::sync::{Condvar, Mutex, OnceLock};
+use std::time::Duration;
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+use crate::connectivity::Connectivity;
+use crate::crash;
+use crate::error::OsError;
+use crate::hal::clock::{Clock, SystemClock};
+use crate::health;
+
+pub mod filter;
+pub mod sink;
+
+pub use filter::Filter;
+pub use sink::Sink;
+
+/// Records of the logger itself only go to the console, so that a failing sink can't feed itself.
+const LOG_TGT: &str = "inu.log";
+
+/// Most records held for the network sink.
+pub const REMOTE_QUEUE_LEN: usize = 64;
+
+/// How long the sender waits before retrying a failed sink.
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How often the sender checks the link while waiting for records.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+static LOGGER: OnceLock<Logger> = OnceLock::new();
+
+/// A log record queued for the network.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogLine {
+    pub level: Level,
+    pub target: String,
+    pub message: String,
+    /// Time since boot.
+    pub uptime: Duration,
+}
+
+/// Install the global logger, passing records to `console`. Until the filters are set, records at info level & above
+/// are logged.
+///
+/// The console logger is given every record that passes the console filter, so should not filter them itself.
+pub fn init(console: Box<dyn Log>) -> Result<&'static Logger, OsError> {
+    let mut installed = false;
+    let logger = LOGGER.get_or_init(|| {
+        installed = true;
+        Logger {
+            console,
+            console_filter: Mutex::new(Filter::default()),
+            remote: RemoteLog::new(),
+            clock: SystemClock::new(),
+        }
+    });
+
+    if !installed {
+        return Err(OsError::Generic("Logger already installed".into()));
+    }
+
+    log::set_logger(logger).map_err(|_| OsError::Generic("Logger already installed".into()))?;
+    logger.update_max_level();
+    Ok(logger)
+}
+
+/// The global logger, if installed.
+pub fn logger() -> Option<&'static Logger> {
+    LOGGER.get()
+}
+
+pub struct Logger {
+    console: Box<dyn Log>,
+    console_filter: Mutex<Filter>,
+    remote: RemoteLog,
+    clock: SystemClock,
+}
+
+impl Logger {
+    /// The filter deciding which records are logged to the console.
+    pub fn console_filter(&self) -> Filter {
+        self.console_filter.lock().unwrap().clone()
+    }
+
+    /// Replace the filter deciding which records are logged to the console.
+    pub fn set_console_filter(&self, filter: Filter) {
+        *self.console_filter.lock().unwrap() = filter;
+        self.update_max_level();
+    }
+
+    /// Queue of records for the network sink.
+    pub fn remote(&self) -> &RemoteLog {
+        &self.remote
+    }
+
+    /// Replace the filter deciding which records are sent to the network.
+    pub fn set_remote_filter(&self, filter: Filter) {
+        self.remote.buffer.lock().unwrap().filter = filter;
+        self.update_max_level();
+    }
+
+    /// Stop queueing records for the network, discarding any already queued.
+    pub fn disable_remote(&self) {
+        self.remote.disable();
+        self.update_max_level();
+    }
+
+    /// Let the `log` macros skip records that no logger wants.
+    fn update_max_level(&self) {
+        let console = self.console_filter.lock().unwrap().max_level();
+        log::set_max_level(console.max(self.remote.max_level()));
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        let (target, level) = (metadata.target(), metadata.level());
+        self.console_filter.lock().unwrap().enabled(target, level)
+            || self.remote.enabled(target, level)
+    }
+
+    fn log(&self, record: &Record) {
+        if self
+            .console_filter
+            .lock()
+            .unwrap()
+            .enabled(record.target(), record.level())
+        {
+            self.console.log(record);
+        }
+
+        if record.target() != LOG_TGT && self.remote.enabled(record.target(), record.level()) {
+            self.remote.push(LogLine {
+                level: record.level(),
+                target: record.target().to_string(),
+                message: record.args().to_string(),
+                uptime: self.clock.now(),
+            });
+        }
+    }
+
+    fn flush(&self) {
+        self.console.flush();
+    }
+}
+
+/// Logs to standard output in the ESP-IDF format, eg. `I (1234) inu.kernel: Restarting device..`, with the time in
+/// milliseconds since boot.
+pub struct Console {
+    clock: SystemClock,
+}
+
+impl Console {
+    pub fn new() -> Self {
+        Console {
+            clock: SystemClock::new(),
+        }
+    }
+}
+
+impl Default for Console {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Log for Console {
+    fn enabled(&self, _: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        let marker = match record.level() {
+            Level::Error => 'E',
+            Level::Warn => 'W',
+            Level::Info => 'I',
+            Level::Debug => 'D',
+            Level::Trace => 'V',
+        };
+
+        let _ = writeln!(
+            std::io::stdout().lock(),
+            "{} ({}) {}: {}",
+            marker,
+            self.clock.now().as_millis(),
+            record.target(),
+            record.args()
+        );
+    }
+
+    fn flush(&self) {
+        let _ = std::io::stdout().flush();
+    }
+}
+
+struct Buffer {
+    filter: Filter,
+    /// Records are queued from boot, until it is known whether a sink is configured.
+    enabled: bool,
+    lines: VecDeque<LogLine>,
+    /// Records dropped since the last was sent.
+    dropped: usize,
+}
+
+/// Records waiting to be sent to the network.
+pub struct RemoteLog {
+    buffer: Mutex<Buffer>,
+    ready: Condvar,
+}
+
+impl RemoteLog {
+    pub fn new() -> Self {
+        RemoteLog {
+            buffer: Mutex::new(Buffer {
+                filter: Filter::default(),
+                enabled: true,
+                lines: VecDeque::new(),
+                dropped: 0,
+            }),
+            ready: Condvar::new(),
+        }
+    }
+
+    pub fn enabled(&self, target: &str, level: Level) -> bool {
+        let buffer = self.buffer.lock().unwrap();
+        buffer.enabled && buffer.filter.enabled(target, level)
+    }
+
+    fn max_level(&self) -> LevelFilter {
+        let buffer = self.buffer.lock().unwrap();
+        if buffer.enabled {
+            buffer.filter.max_level()
+        } else {
+            LevelFilter::Off
+        }
+    }
+
+    /// The filter deciding which records are sent.
+    pub fn filter(&self) -> Filter {
+        self.buffer.lock().unwrap().filter.clone()
+    }
+
+    /// Queue a record, dropping the oldest if the queue is full.
+    pub fn push(&self, line: LogLine) {
+        let mut buffer = self.buffer.lock().unwrap();
+        if !buffer.enabled {
+            return;
+        }
+
+        if buffer.lines.len() >= REMOTE_QUEUE_LEN {
+            buffer.lines.pop_front();
+            buffer.dropped += 1;
+        }
+        buffer.lines.push_back(line);
+        self.ready.notify_all();
+    }
+
+    /// Number of records waiting to be sent.
+    pub fn pending(&self) -> usize {
+        self.buffer.lock().unwrap().lines.len()
+    }
+
+    fn disable(&self) {
+        let mut buffer = self.buffer.lock().unwrap();
+        buffer.enabled = false;
+        buffer.lines.clear();
+        buffer.dropped = 0;
+    }
+
+    /// Send every queued record to the sink, oldest first. A record that fails to send is kept at the front of the
+    /// queue for the next attempt.
+    pub fn flush(&self, sink: &mut dyn Sink) -> Result<(), OsError> {
+        loop {
+            let (line, dropped) = {
+                let mut buffer = self.buffer.lock().unwrap();
+                match buffer.lines.pop_front() {
+                    Some(line) => (line, buffer.dropped),
+                    None => return Ok(()),
+                }
+            };
+
+            let result = match dropped {
+                0 => Ok(()),
+                n => sink.send(&LogLine {
+                    level: Level::Warn,
+                    target: LOG_TGT.into(),
+                    message: format!("{} log records dropped while offline", n),
+                    uptime: line.uptime,
+                }),
+            }
+            .and_then(|_| sink.send(&line));
+
+            let mut buffer = self.buffer.lock().unwrap();
+            match result {
+                Ok(_) => buffer.dropped -= dropped,
+                Err(e) => {
+                    buffer.lines.push_front(line);
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    /// Send records to the sink whenever the device is online.
+    pub fn run(&self, sink: &mut dyn Sink, connectivity: &Connectivity) -> ! {
+        loop {
+            health::feed();
+            {
+                let buffer = self.buffer.lock().unwrap();
+                let _ = self
+                    .ready
+                    .wait_timeout_while(buffer, POLL_INTERVAL, |b| b.lines.is_empty());
+            }
+
+            if !connectivity.is_online() {
+                health::sleep(POLL_INTERVAL);
+                continue;
+            }
+
+            if let Err(e) = self.flush(sink) {
+                log::debug!(target: LOG_TGT, "Remote log interrupted: {}", crash::describe(&e));
+                health::sleep(RETRY_INTERVAL);
+            }
+        }
+    }
+}
+
+impl Default for RemoteLog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(all(test, feature = "host"))]
+mod tests {
+    use super::*;
+
+    /// Collects sent records, failing while offline.
+    #[derive(Default)]
+    struct TestSink {
+        online: bool,
+        sent: Vec<String>,
+    }
+
+    impl Sink for TestSink {
+        fn send(&mut self, line: &LogLine) -> Result<(), OsError> {
+            if !self.online {
+                return Err(crate::error::WifiError::Disconnected.into());
+            }
+            self.sent.push(line.message.clone());
+            Ok(())
+        }
+    }
+
+    fn line(message: String) -> LogLine {
+        LogLine {
+            level: Level::Info,
+            target: "inu.test".into(),
+            message,
+            uptime: Duration::ZERO,
+        }
+    }
+
+    #[test]
+    fn records_are_held_while_offline() {
+        let remote = RemoteLog::new();
+        let mut sink = TestSink::default();
+
+        remote.push(line("first".into()));
+        assert!(remote.flush(&mut sink).is_err());
+        assert_eq!(remote.pending(), 1);
+
+        for i in 0..REMOTE_QUEUE_LEN {
+            remote.push(line(format!("record {}", i)));
+        }
+        assert_eq!(remote.pending(), REMOTE_QUEUE_LEN);
+
+        sink.online = true;
+        remote.flush(&mut sink).unwrap();
+        assert_eq!(remote.pending(), 0);
+        assert_eq!(sink.sent.len(), REMOTE_QUEUE_LEN + 1);
+        assert_eq!(sink.sent[0], "1 log records dropped while offline");
+        assert_eq!(sink.sent[1], "record 0");
+
+        remote.disable();
+        remote.push(line("ignored".into()));
+        assert_eq!(remote.pending(), 0);
+        assert!(!remote.enabled("inu.test", Level::Error));
+    }
+}
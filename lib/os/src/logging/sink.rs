@@ -0,0 +1,151 @@
+//! Network destinations for log records.
+
+use std::net::{Ipv4Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+
+use log::Level;
+
+use crate::error::OsError;
+use crate::inu::protocol::{fit, LogEntry, Message};
+use crate::inu::InuService;
+use crate::logging::LogLine;
+
+/// Syslog facility the device logs as, `local0`.
+const FACILITY: u8 = 16;
+
+/// A destination for remote log records.
+pub trait Sink: Send {
+    fn send(&mut self, line: &LogLine) -> Result<(), OsError>;
+}
+
+/// Sends records to a syslog server over UDP, as RFC 5424 messages.
+pub struct SyslogSink {
+    host: String,
+    port: u16,
+    hostname: String,
+    /// Bound socket & resolved server address, dropped on failure so that the server is resolved again.
+    socket: Option<(UdpSocket, SocketAddr)>,
+}
+
+impl SyslogSink {
+    /// Log to the server at the given host & port, announcing the device by the given hostname.
+    pub fn new(host: &str, port: u16, hostname: &str) -> Self {
+        SyslogSink {
+            host: host.to_string(),
+            port,
+            hostname: hostname.to_string(),
+            socket: None,
+        }
+    }
+
+    fn connect(&self) -> Result<(UdpSocket, SocketAddr), OsError> {
+        let server = (self.host.as_str(), self.port)
+            .to_socket_addrs()?
+            .find(SocketAddr::is_ipv4)
+            .ok_or_else(|| {
+                OsError::Generic(format!("No address for syslog server '{}'", self.host))
+            })?;
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0))?;
+
+        Ok((socket, server))
+    }
+}
+
+impl Sink for SyslogSink {
+    fn send(&mut self, line: &LogLine) -> Result<(), OsError> {
+        let (socket, server) = match self.socket.take() {
+            Some(s) => s,
+            None => self.connect()?,
+        };
+
+        socket.send_to(format_syslog(line, &self.hostname).as_bytes(), server)?;
+        self.socket = Some((socket, server));
+        Ok(())
+    }
+}
+
+/// Format a record as an RFC 5424 syslog message.
+///
+/// The device has no wall clock, so the timestamp is nil & the uptime is given in hundredths of a second by the
+/// `meta` structured data element instead. The log target is the message ID.
+pub fn format_syslog(line: &LogLine, hostname: &str) -> String {
+    let severity = match line.level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    };
+
+    format!(
+        "<{}>1 - {} inu - {} [meta sysUpTime=\"{}\"] {}",
+        FACILITY * 8 + severity,
+        header_field(hostname, 255),
+        header_field(&line.target, 32),
+        line.uptime.as_millis() / 10,
+        line.message
+    )
+}
+
+/// A header field holds up to `max` printable ASCII characters, or `-` if empty.
+fn header_field(value: &str, max: usize) -> String {
+    let field: String = value
+        .chars()
+        .filter(|c| c.is_ascii_graphic())
+        .take(max)
+        .collect();
+
+    if field.is_empty() {
+        "-".into()
+    } else {
+        field
+    }
+}
+
+/// Publishes records to the Inu network.
+pub struct InuSink {
+    inu: InuService,
+}
+
+impl InuSink {
+    pub fn new(inu: InuService) -> Self {
+        InuSink { inu }
+    }
+}
+
+impl Sink for InuSink {
+    fn send(&mut self, line: &LogLine) -> Result<(), OsError> {
+        self.inu.send(&Message::Log(LogEntry {
+            device_id: self.inu.device_id().to_string(),
+            level: line.level,
+            target: fit(line.target.clone()),
+            message: fit(line.message.clone()),
+        }))
+    }
+}
+
+#[cfg(all(test, feature = "host"))]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn records_are_formatted_as_rfc5424() {
+        let line = LogLine {
+            level: Level::Warn,
+            target: "inu.net".into(),
+            message: "Link lost".into(),
+            uptime: Duration::from_millis(12_345),
+        };
+
+        assert_eq!(
+            format_syslog(&line, "inu.lights"),
+            "<132>1 - inu.lights inu - inu.net [meta sysUpTime=\"1234\"] Link lost"
+        );
+
+        let line = LogLine {
+            level: Level::Debug,
+            target: "".into(),
+            ..line
+        };
+        assert!(format_syslog(&line, "inu lights").starts_with("<135>1 - inulights inu - - "));
+    }
+}
@@ -1,68 +1,484 @@
-use esp_idf_hal::cpu;
-use esp_idf_svc::wifi::{BlockingWifi, EspWifi};
-use std::thread;
+use core::cmp::Reverse;
+use core::str::FromStr;
+use std::sync::mpsc::{Receiver, RecvTimeoutError};
+use std::sync::Arc;
 use std::time::Duration;
 
-use crate::error::OsError;
-use crate::types::{OnlineSemaphore, WifiState};
+use crate::connectivity::Connectivity;
+use crate::error::{OsError, WifiError};
+use crate::hal::clock::SystemClock;
+use crate::hal::wifi::{ClientConfiguration, ScanResult, WifiEvent};
+use crate::hal::{Clock, WifiDriver};
+use crate::health;
+use crate::settings::WifiNetwork;
+use crate::types::{DisconnectReason, LinkState, WifiState};
 
 const LOG_TGT: &str = "inu.net";
 
-pub struct Networking<'s> {
-    wifi: BlockingWifi<EspWifi<'s>>,
-    online: OnlineSemaphore,
+/// Delay before retrying after the first failed connection attempt. Doubles with each failure, up to the maximum.
+const RETRY_MIN: Duration = Duration::from_secs(1);
+const RETRY_MAX: Duration = Duration::from_secs(120);
+
+/// How often link statistics are refreshed while connected.
+const STATS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// 802.11 "unspecified reason", used when the link is found down without an event.
+const REASON_UNSPECIFIED: u16 = 1;
+
+pub struct Networking {
+    wifi: Box<dyn WifiDriver>,
+    networks: Vec<WifiNetwork>,
+    connectivity: Arc<Connectivity>,
+    events: Receiver<WifiEvent>,
+    clock: Arc<dyn Clock>,
+    backoff: Backoff,
+    /// When the next connection attempt is due. None to attempt immediately.
+    retry_at: Option<Duration>,
+    connected_at: Duration,
+    has_connected: bool,
 }
 
-impl<'s> Networking<'s> {
-    pub fn new(wifi: BlockingWifi<EspWifi<'s>>, online: OnlineSemaphore) -> Self {
-        Networking { wifi, online }
+impl Networking {
+    /// Networks are given in order of preference.
+    pub fn new(
+        mut wifi: Box<dyn WifiDriver>,
+        networks: Vec<WifiNetwork>,
+        connectivity: Arc<Connectivity>,
+    ) -> Result<Self, OsError> {
+        let events = wifi.subscribe()?;
+
+        Ok(Networking {
+            wifi,
+            networks,
+            connectivity,
+            events,
+            clock: Arc::new(SystemClock::new()),
+            backoff: Backoff::new(RETRY_MIN, RETRY_MAX, 1),
+            retry_at: None,
+            connected_at: Duration::ZERO,
+            has_connected: false,
+        })
+    }
+
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Seed the reconnect jitter, so that devices losing the same access point don't all retry in lockstep.
+    pub fn with_seed(mut self, seed: u32) -> Self {
+        self.backoff = Backoff::new(RETRY_MIN, RETRY_MAX, seed);
+        self
     }
 
     pub fn run(&mut self) -> ! {
-        log::info!(target: LOG_TGT, "Networking task started on core {}", cpu::core() as i32);
+        log::info!(target: LOG_TGT, "Networking task started");
 
         loop {
+            health::feed();
+            let wait = self.poll().min(health::FEED_INTERVAL);
+
+            match self.events.recv_timeout(wait) {
+                Ok(event) => self.handle_event(event),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => health::sleep(wait),
+            }
+        }
+    }
+
+    /// Advance the state machine: connect when an attempt is due, or refresh the link statistics while connected.
+    ///
+    /// Returns how long until the state machine next needs to be polled, unless an event arrives first.
+    pub fn poll(&mut self) -> Duration {
+        let now = self.clock.now();
+
+        if self.state().is_connected() {
+            // Events report a lost link, this catches any that went missing
             if !self.wifi.is_connected().unwrap_or(false) {
-                log::warn!(target: LOG_TGT, "WiFi down, connecting..");
-
-                match self.connect_wifi() {
-                    Ok(_) => {
-                        log::info!(target: LOG_TGT, "WiFi connected");
-                    }
-                    Err(e) => {
-                        self.set_state(WifiState::Disconnected);
-                        log::error!(target: LOG_TGT, "Failed to connect to WiFi: {:?}", e);
-                    }
-                }
+                self.link_lost(DisconnectReason::Link(REASON_UNSPECIFIED));
+                return Duration::ZERO;
+            }
+
+            let rssi = self.wifi.rssi().ok();
+            let uptime = now.saturating_sub(self.connected_at);
+            self.update(|s| {
+                s.rssi = rssi;
+                s.uptime = uptime;
+            });
+            return STATS_INTERVAL;
+        }
+
+        if let Some(at) = self.retry_at {
+            if now < at {
+                return at - now;
+            }
+        }
+
+        log::warn!(target: LOG_TGT, "WiFi down, connecting..");
+        // Joining may outlast the watchdog timeout
+        match health::unwatched(|| self.connect_wifi()) {
+            Ok(_) => {
+                log::info!(target: LOG_TGT, "WiFi connected");
+                let reconnected = self.has_connected;
+                let rssi = self.wifi.rssi().ok();
+                self.update(|s| {
+                    s.rssi = rssi;
+                    s.uptime = Duration::ZERO;
+                    s.reconnects += reconnected as u32;
+                });
+
+                self.has_connected = true;
+                self.connected_at = self.clock.now();
+                self.backoff.reset();
+                self.retry_at = None;
+                STATS_INTERVAL
+            }
+            Err(e) => {
+                let reason = match self.state().link {
+                    LinkState::AcquiringIp => DisconnectReason::NoIp,
+                    _ => DisconnectReason::JoinFailed,
+                };
+                let _ = self.wifi.disconnect();
+
+                let delay = self.backoff.next_delay();
+                log::error!(target: LOG_TGT, "Failed to connect to WiFi: {:?}, retrying in {:?}", e, delay);
+
+                self.update(|s| {
+                    s.link = LinkState::Disconnected;
+                    s.last_disconnect = Some(reason);
+                });
+                self.retry_at = Some(now + delay);
+                delay
             }
+        }
+    }
 
-            thread::sleep(Duration::from_millis(100));
+    /// React to a driver event.
+    pub fn handle_event(&mut self, event: WifiEvent) {
+        if let WifiEvent::Disconnected { reason } = event {
+            // Failed connection attempts report disconnects too, which may arrive late. Only a drop of an established
+            // link counts.
+            if self.state().is_connected() && !self.wifi.is_connected().unwrap_or(false) {
+                log::warn!(target: LOG_TGT, "WiFi link lost, reason {}", reason);
+                self.link_lost(DisconnectReason::Link(reason));
+            }
         }
     }
 
+    /// Reconnect straight away after losing an established link, backing off only if that fails.
+    fn link_lost(&mut self, reason: DisconnectReason) {
+        self.update(|s| {
+            s.link = LinkState::Disconnected;
+            s.rssi = None;
+            s.uptime = Duration::ZERO;
+            s.last_disconnect = Some(reason);
+        });
+        self.retry_at = None;
+    }
+
+    /// Join the best available known network, failing over to the next candidate if a connection attempt fails.
     fn connect_wifi(&mut self) -> Result<(), OsError> {
-        self.set_state(WifiState::Connecting);
+        self.set_link(LinkState::Connecting);
         self.wifi.start()?;
-        self.wifi.connect()?;
-        self.set_state(WifiState::AcquiringIp);
-        log::info!(target: LOG_TGT, "Connection established to AP");
-        self.wifi.wait_netif_up()?;
 
-        match self.wifi.wifi().sta_netif().get_ip_info() {
-            Ok(r) => {
-                self.set_state(WifiState::Connected(r));
-            }
-            Err(e) => {
-                log::error!(target: LOG_TGT, "Failed to get IP info: {:?}", e);
-                self.set_state(WifiState::Disconnected);
-                self.wifi.disconnect()?;
+        let scan = self.wifi.scan().unwrap_or_else(|e| {
+            log::warn!(target: LOG_TGT, "WiFi scan failed: {:?}", e);
+            vec![]
+        });
+
+        let mut result = Err(WifiError::NoAccessPoint.into());
+        for network in candidates(&self.networks, &scan) {
+            log::info!(target: LOG_TGT, "Connecting to '{}'..", network.ssid);
+
+            result = self.join(&network);
+            match &result {
+                Ok(_) => break,
+                Err(e) => log::warn!(target: LOG_TGT, "Failed to join '{}': {:?}", network.ssid, e),
             }
         }
+        result?;
+
+        self.set_link(LinkState::AcquiringIp);
+        log::info!(target: LOG_TGT, "Connection established to AP");
+        self.wifi.wait_netif_up()?;
+
+        let ip = self.wifi.ip_info()?;
+        self.set_link(LinkState::Connected(ip));
         Ok(())
     }
 
-    fn set_state(&mut self, state: WifiState) {
-        let mut online = self.online.lock().unwrap();
-        *online = state;
+    fn join(&mut self, network: &WifiNetwork) -> Result<(), OsError> {
+        self.wifi
+            .set_configuration(&client_configuration(network)?)?;
+        self.wifi.connect()
+    }
+
+    fn state(&self) -> WifiState {
+        self.connectivity.state()
+    }
+
+    fn update(&self, f: impl FnOnce(&mut WifiState)) {
+        self.connectivity.update(f);
+    }
+
+    fn set_link(&self, link: LinkState) {
+        self.update(|s| s.link = link);
+    }
+}
+
+/// Exponential backoff with jitter.
+///
+/// Each delay is drawn from the upper half of a ceiling that doubles with every attempt, so retries spread out
+/// without ever coming sooner than half the nominal delay.
+pub struct Backoff {
+    min: Duration,
+    max: Duration,
+    attempts: u32,
+    seed: u32,
+}
+
+impl Backoff {
+    pub fn new(min: Duration, max: Duration, seed: u32) -> Self {
+        Backoff {
+            min,
+            max,
+            attempts: 0,
+            // xorshift gets stuck on zero
+            seed: seed.max(1),
+        }
+    }
+
+    pub fn next_delay(&mut self) -> Duration {
+        let ceiling = self
+            .min
+            .saturating_mul(1 << self.attempts.min(16))
+            .min(self.max);
+        self.attempts = self.attempts.saturating_add(1);
+
+        let half = ceiling / 2;
+        half + half.mul_f64(self.random() as f64 / u32::MAX as f64)
+    }
+
+    pub fn reset(&mut self) {
+        self.attempts = 0;
+    }
+
+    fn random(&mut self) -> u32 {
+        self.seed ^= self.seed << 13;
+        self.seed ^= self.seed >> 17;
+        self.seed ^= self.seed << 5;
+        self.seed
+    }
+}
+
+/// Order the known networks for connection attempts.
+///
+/// Networks seen in the scan come first, strongest signal first, pinned to the access point that was seen. Networks
+/// not seen follow in order of preference, as they may be hidden or the scan may have missed them.
+pub fn candidates(networks: &[WifiNetwork], scan: &[ScanResult]) -> Vec<WifiNetwork> {
+    let mut seen: Vec<(i8, WifiNetwork)> = vec![];
+    let mut unseen: Vec<WifiNetwork> = vec![];
+
+    for network in networks {
+        let best = scan
+            .iter()
+            .filter(|ap| {
+                ap.ssid == network.ssid
+                    && network.bssid.map_or(true, |b| b == ap.bssid)
+                    && network.channel.map_or(true, |c| c == ap.channel)
+            })
+            .max_by_key(|ap| ap.rssi);
+
+        match best {
+            Some(ap) => seen.push((
+                ap.rssi,
+                WifiNetwork {
+                    bssid: Some(ap.bssid),
+                    channel: Some(ap.channel),
+                    ..network.clone()
+                },
+            )),
+            None => unseen.push(network.clone()),
+        }
+    }
+
+    // Stable sort keeps the order of preference for equal signal strength
+    seen.sort_by_key(|(rssi, _)| Reverse(*rssi));
+    seen.into_iter().map(|(_, n)| n).chain(unseen).collect()
+}
+
+fn client_configuration(network: &WifiNetwork) -> Result<ClientConfiguration, OsError> {
+    let ssid = heapless::String::<32>::from_str(&network.ssid)
+        .map_err(|_| WifiError::Unknown(format!("SSID '{}' is too long", network.ssid)))?;
+    let password = heapless::String::<64>::from_str(&network.password)
+        .map_err(|_| WifiError::Unknown(format!("Password for '{}' is too long", network.ssid)))?;
+
+    Ok(ClientConfiguration {
+        ssid,
+        password,
+        auth_method: network.auth_method,
+        bssid: network.bssid,
+        channel: network.channel,
+        ..Default::default()
+    })
+}
+
+#[cfg(all(test, feature = "host"))]
+mod tests {
+    use super::*;
+    use crate::hal::host::{ManualClock, SimWifi};
+    use crate::hal::wifi::AuthMethod;
+
+    fn network(ssid: &str) -> WifiNetwork {
+        WifiNetwork {
+            ssid: ssid.into(),
+            password: "password".into(),
+            auth_method: AuthMethod::WPA2Personal,
+            bssid: None,
+            channel: None,
+        }
+    }
+
+    fn ap(ssid: &str, bssid: u8, channel: u8, rssi: i8) -> ScanResult {
+        ScanResult {
+            ssid: ssid.into(),
+            bssid: [0, 0, 0, 0, 0, bssid],
+            channel,
+            rssi,
+            auth_method: Some(AuthMethod::WPA2Personal),
+        }
+    }
+
+    #[test]
+    fn candidates_prefer_the_strongest_visible_network() {
+        let mut pinned = network("mesh");
+        pinned.bssid = Some([0, 0, 0, 0, 0, 3]);
+        let networks = [
+            network("home"),
+            network("office"),
+            pinned,
+            network("hidden"),
+        ];
+        let scan = [
+            ap("home", 1, 6, -80),
+            ap("office", 2, 11, -50),
+            ap("office", 4, 1, -40),
+            ap("mesh", 3, 1, -90),
+            ap("mesh", 5, 1, -30),
+        ];
+
+        let order: Vec<_> = candidates(&networks, &scan)
+            .into_iter()
+            .map(|n| (n.ssid, n.bssid.map(|b| b[5]), n.channel))
+            .collect();
+
+        assert_eq!(
+            order,
+            vec![
+                ("office".to_string(), Some(4), Some(1)),
+                ("home".to_string(), Some(1), Some(6)),
+                ("mesh".to_string(), Some(3), Some(1)),
+                ("hidden".to_string(), None, None),
+            ]
+        );
+    }
+
+    #[test]
+    fn connection_fails_over_to_the_next_network() {
+        let wifi = SimWifi::new();
+        wifi.set_access_points(vec![ap("home", 1, 6, -40), ap("office", 2, 11, -50)]);
+
+        // The strongest network can't be configured, so the next is tried
+        let mut broken = network("home");
+        broken.password = "x".repeat(70);
+
+        let online = Arc::new(Connectivity::new());
+        let mut nw = Networking::new(
+            Box::new(wifi.clone()),
+            vec![broken, network("office")],
+            online.clone(),
+        )
+        .unwrap();
+
+        nw.connect_wifi().unwrap();
+        assert!(online.is_online());
+        assert_eq!(wifi.configuration().unwrap().ssid.as_str(), "office");
+
+        // Nothing known in range
+        wifi.set_access_points(vec![ap("other", 9, 1, -20)]);
+        assert!(nw.connect_wifi().is_err());
+    }
+
+    #[test]
+    fn backoff_grows_with_jitter_and_resets() {
+        let secs = Duration::from_secs;
+        let mut backoff = Backoff::new(secs(1), secs(8), 42);
+
+        let delays: Vec<_> = (0..6).map(|_| backoff.next_delay()).collect();
+        for (delay, ceiling) in delays.iter().zip([1, 2, 4, 8, 8, 8]) {
+            assert!(*delay >= secs(ceiling) / 2 && *delay <= secs(ceiling));
+        }
+
+        // Jitter differs between devices
+        let mut other = Backoff::new(secs(1), secs(8), 7);
+        assert_ne!(
+            delays,
+            (0..6).map(|_| other.next_delay()).collect::<Vec<_>>()
+        );
+
+        backoff.reset();
+        assert!(backoff.next_delay() <= secs(1));
+    }
+
+    #[test]
+    fn dropped_links_are_reconnected_with_backoff() {
+        let wifi = SimWifi::new();
+        let clock = Arc::new(ManualClock::new());
+        let online = Arc::new(Connectivity::new());
+        let mut nw = Networking::new(
+            Box::new(wifi.clone()),
+            vec![network("home")],
+            online.clone(),
+        )
+        .unwrap()
+        .with_clock(clock.clone());
+        let state = || online.state();
+        let drain = |nw: &mut Networking| {
+            while let Ok(event) = nw.events.try_recv() {
+                nw.handle_event(event);
+            }
+        };
+
+        assert_eq!(nw.poll(), STATS_INTERVAL);
+        assert!(state().is_connected());
+        assert_eq!(state().rssi, Some(-50));
+
+        clock.advance(Duration::from_secs(10));
+        nw.poll();
+        assert_eq!(state().uptime, Duration::from_secs(10));
+
+        wifi.set_in_range(false);
+        drain(&mut nw);
+        assert_eq!(state().link, LinkState::Disconnected);
+        assert_eq!(state().last_disconnect, Some(DisconnectReason::Link(200)));
+
+        // First retry is immediate, then each failure waits longer
+        let first = nw.poll();
+        assert_eq!(state().last_disconnect, Some(DisconnectReason::JoinFailed));
+        assert!(first >= RETRY_MIN / 2 && first <= RETRY_MIN);
+        assert!(nw.poll() <= first);
+        clock.advance(first);
+        let second = nw.poll();
+        assert!(second >= RETRY_MIN && second <= RETRY_MIN * 2);
+
+        wifi.set_in_range(true);
+        clock.advance(second);
+        assert_eq!(nw.poll(), STATS_INTERVAL);
+
+        // Disconnects from the failed attempts are stale by now
+        drain(&mut nw);
+        assert!(state().is_connected());
+        assert_eq!(state().reconnects, 1);
+        assert_eq!(state().uptime, Duration::ZERO);
     }
 }
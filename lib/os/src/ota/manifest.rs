@@ -0,0 +1,196 @@
+//! Firmware image manifest.
+//!
+//! Every release image is published with a JSON manifest describing it:
+//!
+//! ```json
+//! {"edition": "Ferric", "build": 3, "size": 1048576, "sha256": "<64 hex>", "signature": "<128 hex>"}
+//! ```
+//!
+//! The signature is an Ed25519 signature by the release key over `signed_payload`, which binds the edition, build &
+//! size to the image hash. A manifest can't be altered to pass an old image off as a new build.
+
+use ed25519_dalek::{Signature, Signer, SigningKey, VerifyingKey};
+use serde::{Deserialize, Serialize};
+
+use crate::error::{OsError, OtaError};
+
+/// Prefix of the signed payload, so that a release signature can't be mistaken for anything else.
+const SIGNING_CONTEXT: &[u8] = b"inu-firmware-v1";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Manifest {
+    pub edition: String,
+    pub build: u32,
+    /// Size of the image in bytes.
+    pub size: usize,
+    pub sha256: [u8; 32],
+    pub signature: Option<[u8; 64]>,
+}
+
+/// Wire form of the manifest, with hex encoded binary fields.
+#[derive(Serialize, Deserialize)]
+struct ManifestJson {
+    edition: String,
+    build: u32,
+    size: usize,
+    sha256: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    signature: Option<String>,
+}
+
+impl Manifest {
+    pub fn parse(json: &str) -> Result<Self, OsError> {
+        let m: ManifestJson = serde_json::from_str(json)?;
+        let invalid = |field| OsError::Parse(format!("Manifest {} is not valid hex", field));
+
+        Ok(Manifest {
+            edition: m.edition,
+            build: m.build,
+            size: m.size,
+            sha256: from_hex(&m.sha256).ok_or_else(|| invalid("sha256"))?,
+            signature: match m.signature {
+                Some(s) => Some(from_hex(&s).ok_or_else(|| invalid("signature"))?),
+                None => None,
+            },
+        })
+    }
+
+    pub fn to_json(&self) -> String {
+        let m = ManifestJson {
+            edition: self.edition.clone(),
+            build: self.build,
+            size: self.size,
+            sha256: to_hex(&self.sha256),
+            signature: self.signature.as_ref().map(|s| to_hex(s)),
+        };
+
+        serde_json::to_string(&m).expect("manifest is always serialisable")
+    }
+
+    /// The bytes covered by the signature: the signing context, the edition prefixed by its `u32` length, the `u32`
+    /// build, the `u64` size and the image hash. Integers are big-endian.
+    pub fn signed_payload(&self) -> Vec<u8> {
+        let edition = self.edition.as_bytes();
+        let mut payload = Vec::with_capacity(SIGNING_CONTEXT.len() + edition.len() + 48);
+
+        payload.extend_from_slice(SIGNING_CONTEXT);
+        payload.extend_from_slice(&(edition.len() as u32).to_be_bytes());
+        payload.extend_from_slice(edition);
+        payload.extend_from_slice(&self.build.to_be_bytes());
+        payload.extend_from_slice(&(self.size as u64).to_be_bytes());
+        payload.extend_from_slice(&self.sha256);
+        payload
+    }
+
+    /// Sign the manifest with a release signing key.
+    pub fn sign(&mut self, key: &SigningKey) {
+        self.signature = Some(key.sign(&self.signed_payload()).to_bytes());
+    }
+}
+
+/// Public half of the release key, used to verify manifests.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReleaseKey(VerifyingKey);
+
+impl ReleaseKey {
+    pub fn from_bytes(bytes: &[u8; 32]) -> Result<Self, OtaError> {
+        VerifyingKey::from_bytes(bytes)
+            .map(ReleaseKey)
+            .map_err(|_| OtaError::BadReleaseKey)
+    }
+
+    /// Parse a key from 64 hex characters.
+    pub fn from_hex(hex: &str) -> Result<Self, OtaError> {
+        Self::from_bytes(&from_hex(hex).ok_or(OtaError::BadReleaseKey)?)
+    }
+
+    /// Check that the manifest was signed by this key.
+    pub fn verify(&self, manifest: &Manifest) -> Result<(), OtaError> {
+        let signature = manifest.signature.ok_or(OtaError::SignatureRequired)?;
+
+        self.0
+            .verify_strict(
+                &manifest.signed_payload(),
+                &Signature::from_bytes(&signature),
+            )
+            .map_err(|_| OtaError::BadSignature)
+    }
+}
+
+impl From<&SigningKey> for ReleaseKey {
+    fn from(key: &SigningKey) -> Self {
+        ReleaseKey(key.verifying_key())
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex<const N: usize>(hex: &str) -> Option<[u8; N]> {
+    if hex.len() != N * 2 || !hex.is_ascii() {
+        return None;
+    }
+
+    let mut bytes = [0u8; N];
+    for (i, b) in bytes.iter_mut().enumerate() {
+        *b = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(bytes)
+}
+
+#[cfg(all(test, feature = "host"))]
+mod tests {
+    use super::*;
+
+    fn manifest() -> Manifest {
+        Manifest {
+            edition: "Ferric".into(),
+            build: 7,
+            size: 4096,
+            sha256: [0xab; 32],
+            signature: None,
+        }
+    }
+
+    #[test]
+    fn signed_manifests_verify_with_the_release_key() {
+        let signing = SigningKey::from_bytes(&[7; 32]);
+        let key = ReleaseKey::from(&signing);
+        let other = ReleaseKey::from(&SigningKey::from_bytes(&[8; 32]));
+
+        let mut m = manifest();
+        assert_eq!(key.verify(&m), Err(OtaError::SignatureRequired));
+
+        m.sign(&signing);
+        assert_eq!(key.verify(&m), Ok(()));
+        assert_eq!(other.verify(&m), Err(OtaError::BadSignature));
+
+        // The signature covers every field
+        let mut tampered = m.clone();
+        tampered.build = 8;
+        assert_eq!(key.verify(&tampered), Err(OtaError::BadSignature));
+        let mut tampered = m.clone();
+        tampered.sha256[31] ^= 1;
+        assert_eq!(key.verify(&tampered), Err(OtaError::BadSignature));
+
+        // Keys & manifests survive a round trip through their text forms
+        let key_hex = to_hex(signing.verifying_key().as_bytes());
+        assert_eq!(ReleaseKey::from_hex(&key_hex).unwrap(), key);
+        assert_eq!(Manifest::parse(&m.to_json()).unwrap(), m);
+    }
+
+    #[test]
+    fn malformed_manifests_are_rejected() {
+        assert!(Manifest::parse("{}").is_err());
+        assert!(Manifest::parse(
+            r#"{"edition": "Ferric", "build": 1, "size": 1, "sha256": "abcd"}"#
+        )
+        .is_err());
+
+        let m = Manifest::parse(&manifest().to_json()).unwrap();
+        assert_eq!(m.signature, None);
+
+        assert_eq!(ReleaseKey::from_hex("zz"), Err(OtaError::BadReleaseKey));
+    }
+}
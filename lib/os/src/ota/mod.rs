@@ -0,0 +1,436 @@
+//! Over-the-air firmware updates.
+//!
+//! An update is described by a `Manifest`, which is checked against the `UpdatePolicy` (edition, minimum build &
+//! release key signature) before anything is downloaded. The image is then streamed from an HTTPS server into the
+//! inactive app slot, checked against the size & SHA-256 in the manifest and selected as the boot image. The new image
+//! boots pending verification: once it has proven healthy it is marked valid, otherwise the bootloader rolls back to
+//! the previous image. This requires `CONFIG_BOOTLOADER_APP_ROLLBACK_ENABLE`.
+//!
+//! The partition table has a factory slot & a single OTA slot, `ota_0`. Updates are installed from the factory image;
+//! an image running from `ota_0` can't overwrite itself, so reports `OtaError::NoUpdateSlot`.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use sha2::{Digest, Sha256};
+
+use crate::connectivity::Connectivity;
+use crate::error::{OsError, OtaError};
+use crate::hal::ota::{ImageState, Slot};
+use crate::hal::{Download, OtaDriver, Platform};
+
+pub mod manifest;
+
+pub use manifest::{Manifest, ReleaseKey};
+
+const LOG_TGT: &str = "inu.ota";
+
+/// How long a new image has to come online before it is rolled back.
+pub const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Size of each chunk read from the server & written to flash.
+const CHUNK_LEN: usize = 4096;
+
+/// A firmware image to install.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UpdateRequest {
+    pub url: String,
+    pub manifest: Manifest,
+}
+
+/// The images a device accepts.
+#[derive(Debug, Clone, Copy)]
+pub struct UpdatePolicy<'a> {
+    /// Edition of the running firmware. Images of another edition are rejected.
+    pub edition: &'a str,
+    /// Anti-rollback: images with an older build are rejected.
+    pub min_build: u32,
+    /// Images must be signed by this key.
+    pub release_key: &'a ReleaseKey,
+}
+
+impl UpdatePolicy<'_> {
+    pub fn check(&self, manifest: &Manifest) -> Result<(), OtaError> {
+        if manifest.edition != self.edition {
+            return Err(OtaError::WrongEdition(manifest.edition.clone()));
+        }
+
+        self.release_key.verify(manifest)?;
+
+        if manifest.build < self.min_build {
+            return Err(OtaError::Downgrade {
+                build: manifest.build,
+                min_build: self.min_build,
+            });
+        }
+
+        Ok(())
+    }
+}
+
+/// Outcome of the health check of a newly booted image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BootCheck {
+    /// The running image was already confirmed, or is the factory image.
+    NotPending,
+    Confirmed,
+    RolledBack,
+}
+
+pub struct Ota {
+    driver: Mutex<Box<dyn OtaDriver>>,
+}
+
+impl Ota {
+    pub fn new(driver: Box<dyn OtaDriver>) -> Self {
+        Ota {
+            driver: Mutex::new(driver),
+        }
+    }
+
+    /// The slot the running image was booted from.
+    pub fn running_slot(&self) -> Result<Slot, OsError> {
+        self.driver.lock().unwrap().running_slot()
+    }
+
+    /// Download & install an image, booting it on the next restart. The image must be served over HTTPS.
+    pub fn update(
+        &self,
+        platform: &dyn Platform,
+        request: &UpdateRequest,
+        policy: &UpdatePolicy,
+    ) -> Result<(), OsError> {
+        if !request
+            .url
+            .get(..8)
+            .is_some_and(|scheme| scheme.eq_ignore_ascii_case("https://"))
+        {
+            return Err(OtaError::InsecureUrl(request.url.clone()).into());
+        }
+
+        // Reject the manifest before spending time on the download
+        policy.check(&request.manifest)?;
+
+        log::info!(target: LOG_TGT, "Downloading build {} from {}", request.manifest.build, request.url);
+        let mut download = platform.download(&request.url)?;
+        self.install(&request.manifest, policy, download.as_mut())
+    }
+
+    /// Write an image to the update slot, booting it on the next restart.
+    ///
+    /// Nothing is activated unless the manifest is accepted by the policy & the image matches it. A failed install
+    /// leaves the running image & boot selection untouched.
+    pub fn install(
+        &self,
+        manifest: &Manifest,
+        policy: &UpdatePolicy,
+        source: &mut dyn Download,
+    ) -> Result<(), OsError> {
+        policy.check(manifest)?;
+
+        if let Some(len) = source.content_length() {
+            if len != manifest.size {
+                return Err(size_mismatch(manifest.size, len));
+            }
+        }
+
+        let mut driver = self.driver.lock().unwrap();
+        let running = driver.running_slot()?;
+        let target = driver.update_slot()?;
+        if target.label == running.label {
+            return Err(OtaError::NoUpdateSlot.into());
+        }
+
+        log::info!(target: LOG_TGT, "Writing {} byte image to '{}'", manifest.size, target.label);
+        let mut update = driver.begin()?;
+        let mut hasher = Sha256::new();
+        let mut buffer = vec![0u8; CHUNK_LEN];
+        let mut written = 0;
+
+        loop {
+            let len = source.read(&mut buffer)?;
+            if len == 0 {
+                break;
+            }
+
+            written += len;
+            if written > manifest.size {
+                return Err(size_mismatch(manifest.size, written));
+            }
+
+            hasher.update(&buffer[..len]);
+            update.write(&buffer[..len])?;
+        }
+
+        if written != manifest.size {
+            return Err(size_mismatch(manifest.size, written));
+        }
+
+        let sha256: [u8; 32] = hasher.finalize().into();
+        if sha256 != manifest.sha256 {
+            return Err(OtaError::HashMismatch.into());
+        }
+
+        update.complete()?;
+        log::info!(target: LOG_TGT, "Image verified, '{}' boots on restart", target.label);
+        Ok(())
+    }
+
+    /// Confirm a newly booted image if it is healthy, rolling it back otherwise.
+    ///
+    /// On the device a rollback restarts into the previous image, so this only returns `RolledBack` in simulation.
+    pub fn verify_boot(&self, healthy: bool) -> Result<BootCheck, OsError> {
+        let mut driver = self.driver.lock().unwrap();
+        let running = driver.running_slot()?;
+
+        if running.state != ImageState::PendingVerify {
+            return Ok(BootCheck::NotPending);
+        }
+
+        if healthy {
+            driver.mark_valid()?;
+            log::info!(target: LOG_TGT, "Image in '{}' confirmed", running.label);
+            Ok(BootCheck::Confirmed)
+        } else {
+            log::error!(target: LOG_TGT, "Image in '{}' failed its health check, rolling back", running.label);
+            driver.rollback()?;
+            Ok(BootCheck::RolledBack)
+        }
+    }
+
+    /// Health check for a newly booted image: it must bring the network up within `timeout`, otherwise it could
+    /// never receive a fix.
+    pub fn check_boot(
+        &self,
+        connectivity: &Connectivity,
+        timeout: Duration,
+    ) -> Result<BootCheck, OsError> {
+        if self.running_slot()?.state != ImageState::PendingVerify {
+            return Ok(BootCheck::NotPending);
+        }
+
+        log::info!(target: LOG_TGT, "New image pending verification, waiting to come online");
+        let events = connectivity.subscribe();
+        let start = Instant::now();
+
+        while !connectivity.is_online() {
+            match timeout.checked_sub(start.elapsed()) {
+                Some(remaining) => {
+                    let _ = events.recv_timeout(remaining);
+                }
+                None => break,
+            }
+        }
+
+        self.verify_boot(connectivity.is_online())
+    }
+}
+
+fn size_mismatch(expected: usize, actual: usize) -> OsError {
+    OtaError::SizeMismatch { expected, actual }.into()
+}
+
+#[cfg(all(test, feature = "host"))]
+mod tests {
+    use super::*;
+    use crate::hal::host::{HostPlatform, SimOta};
+    use crate::hal::wifi::IpInfo;
+    use crate::types::LinkState;
+    use ed25519_dalek::SigningKey;
+    use embedded_svc::ipv4::{Mask, Subnet};
+    use std::net::Ipv4Addr;
+
+    const URL: &str = "https://updates.example/inu.bin";
+
+    fn signing() -> SigningKey {
+        SigningKey::from_bytes(&[1; 32])
+    }
+
+    /// Accepts any Ferric image signed by the key.
+    fn open(key: &ReleaseKey) -> UpdatePolicy<'_> {
+        UpdatePolicy {
+            edition: "Ferric",
+            min_build: 0,
+            release_key: key,
+        }
+    }
+
+    fn request(image: &[u8]) -> UpdateRequest {
+        let mut manifest = Manifest {
+            edition: "Ferric".into(),
+            build: 5,
+            size: image.len(),
+            sha256: Sha256::digest(image).into(),
+            signature: None,
+        };
+        manifest.sign(&signing());
+
+        UpdateRequest {
+            url: URL.into(),
+            manifest,
+        }
+    }
+
+    fn setup(image: &[u8]) -> (HostPlatform, Ota, SimOta) {
+        let platform = HostPlatform::new();
+        platform.http_sim().serve_file(URL, image);
+        let ota = Ota::new(platform.ota().unwrap());
+        let sim = platform.ota_sim();
+        (platform, ota, sim)
+    }
+
+    #[test]
+    fn verified_image_is_installed_and_confirmed() {
+        let image = vec![0xe9; 10_000];
+        let (platform, ota, sim) = setup(&image);
+        let key = ReleaseKey::from(&signing());
+
+        ota.update(&platform, &request(&image), &open(&key))
+            .unwrap();
+        assert_eq!(sim.image(), image);
+        assert_eq!(sim.boot_slot(), SimOta::OTA_0);
+
+        sim.restart();
+        assert_eq!(ota.running_slot().unwrap().state, ImageState::PendingVerify);
+        // Running from the only OTA slot, there is nowhere to write another update
+        assert!(matches!(
+            ota.update(&platform, &request(&image), &open(&key)),
+            Err(OsError::Ota(OtaError::NoUpdateSlot))
+        ));
+
+        assert_eq!(ota.verify_boot(true).unwrap(), BootCheck::Confirmed);
+        assert_eq!(ota.verify_boot(true).unwrap(), BootCheck::NotPending);
+        sim.restart();
+        assert_eq!(ota.running_slot().unwrap().state, ImageState::Valid);
+    }
+
+    #[test]
+    fn mismatched_images_are_not_activated() {
+        let image = b"inu firmware".to_vec();
+        let (platform, ota, sim) = setup(&image);
+        let key = ReleaseKey::from(&signing());
+
+        let mut wrong_size = request(&image);
+        wrong_size.manifest.size += 1;
+        wrong_size.manifest.sign(&signing());
+        assert!(matches!(
+            ota.update(&platform, &wrong_size, &open(&key)),
+            Err(OsError::Ota(OtaError::SizeMismatch { .. }))
+        ));
+
+        let mut wrong_hash = request(&image);
+        wrong_hash.manifest.sha256[0] ^= 1;
+        wrong_hash.manifest.sign(&signing());
+        assert!(matches!(
+            ota.update(&platform, &wrong_hash, &open(&key)),
+            Err(OsError::Ota(OtaError::HashMismatch))
+        ));
+
+        assert_eq!(sim.boot_slot(), SimOta::FACTORY);
+    }
+
+    #[test]
+    fn policy_rejects_untrusted_images() {
+        let image = vec![0x55; 64];
+        let (platform, ota, sim) = setup(&image);
+        let signing = signing();
+        let key = ReleaseKey::from(&signing);
+        let policy = UpdatePolicy {
+            min_build: 5,
+            ..open(&key)
+        };
+        let rejected = |request: &UpdateRequest| match ota.update(&platform, request, &policy) {
+            Err(OsError::Ota(e)) => e,
+            r => panic!("expected rejection, got {:?}", r),
+        };
+
+        let mut unsigned = request(&image);
+        unsigned.manifest.signature = None;
+        assert_eq!(rejected(&unsigned), OtaError::SignatureRequired);
+        assert_eq!(
+            open(&key).check(&unsigned.manifest),
+            Err(OtaError::SignatureRequired)
+        );
+
+        let mut forged = request(&image);
+        forged.manifest.sign(&SigningKey::from_bytes(&[2; 32]));
+        assert_eq!(rejected(&forged), OtaError::BadSignature);
+
+        let mut other_edition = request(&image);
+        other_edition.manifest.edition = "Carbon".into();
+        other_edition.manifest.sign(&signing);
+        assert_eq!(
+            rejected(&other_edition),
+            OtaError::WrongEdition("Carbon".into())
+        );
+
+        let mut old = request(&image);
+        old.manifest.build = 4;
+        old.manifest.sign(&signing);
+        assert_eq!(
+            rejected(&old),
+            OtaError::Downgrade {
+                build: 4,
+                min_build: 5
+            }
+        );
+        assert_eq!(sim.boot_slot(), SimOta::FACTORY);
+
+        // Even signed, images are only downloaded over HTTPS
+        let mut insecure = request(&image);
+        insecure.url = "http://updates.example/inu.bin".into();
+        platform.http_sim().serve_file(&insecure.url, image.clone());
+        assert!(matches!(
+            ota.update(&platform, &insecure, &policy),
+            Err(OsError::Ota(OtaError::InsecureUrl(_)))
+        ));
+
+        ota.update(&platform, &request(&image), &policy).unwrap();
+        assert_eq!(sim.boot_slot(), SimOta::OTA_0);
+    }
+
+    #[test]
+    fn unhealthy_image_is_rolled_back() {
+        let image = vec![1, 2, 3];
+        let (platform, ota, sim) = setup(&image);
+        let key = ReleaseKey::from(&signing());
+        ota.update(&platform, &request(&image), &open(&key))
+            .unwrap();
+        sim.restart();
+
+        let connectivity = Connectivity::new();
+        assert_eq!(
+            ota.check_boot(&connectivity, Duration::from_millis(20))
+                .unwrap(),
+            BootCheck::RolledBack
+        );
+        assert_eq!(ota.running_slot().unwrap().label, SimOta::FACTORY);
+        assert_eq!(
+            ota.check_boot(&connectivity, Duration::ZERO).unwrap(),
+            BootCheck::NotPending
+        );
+
+        // A new image that comes online is kept
+        ota.update(&platform, &request(&image), &open(&key))
+            .unwrap();
+        sim.restart();
+        connectivity.update(|s| s.link = LinkState::Connected(ip()));
+        assert_eq!(
+            ota.check_boot(&connectivity, Duration::from_secs(5))
+                .unwrap(),
+            BootCheck::Confirmed
+        );
+    }
+
+    fn ip() -> IpInfo {
+        IpInfo {
+            ip: Ipv4Addr::new(10, 0, 0, 2),
+            subnet: Subnet {
+                gateway: Ipv4Addr::new(10, 0, 0, 1),
+                mask: Mask(24),
+            },
+            dns: None,
+            secondary_dns: None,
+        }
+    }
+}
@@ -1,4 +1,4 @@
-/// esp32s3
+//! esp32s3
 
 /// The partition table itself
 pub const PARTITION_OFFSET: u32 = 0x8000;
@@ -13,3 +13,9 @@ pub const TICKS_PER_SECOND: u64 = 16_000_000;
 
 /// Max supported pins on the chipset
 pub const MAX_PINS: u8 = 49;
+
+/// Highest RTC GPIO. Only RTC GPIOs, 0 to this, can wake the device from deep sleep.
+pub const MAX_RTC_PIN: u8 = 21;
+
+/// Touch pads, on the GPIO of the same number
+pub const TOUCH_PADS: core::ops::RangeInclusive<u8> = 1..=14;
@@ -1,14 +1,19 @@
 use std::cell::RefCell;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
 
 use crate::error::PinError;
+use crate::hal::gpio::{InputPin, Level, OutputPin, Pull};
+use crate::hal::Gpio;
 use crate::physical::hardware;
-use esp_idf_svc::hal::gpio::{AnyIOPin, Input, Level, Output, PinDriver, Pull};
 
-pub type GpioInput<'a> = PinDriver<'a, AnyIOPin, Input>;
-pub type GpioOutput<'a> = PinDriver<'a, AnyIOPin, Output>;
+#[cfg(feature = "esp32s3")]
+use esp_idf_svc::hal::gpio::AnyIOPin;
+
+pub type GpioInput<'a> = Box<dyn InputPin + 'a>;
+pub type GpioOutput<'a> = Box<dyn OutputPin + 'a>;
 
 pub struct PinManager {
+    gpio: Arc<dyn Gpio>,
     pin_state: Mutex<RefCell<[bool; hardware::MAX_PINS as usize]>>,
 }
 
@@ -17,61 +22,117 @@ impl PinManager {
     ///
     /// # Safety
     /// Singleton. Create only once.
-    pub unsafe fn new() -> Self {
+    pub unsafe fn new(gpio: Arc<dyn Gpio>) -> Self {
         Self {
+            gpio,
             pin_state: Mutex::new(RefCell::new([false; hardware::MAX_PINS as usize])),
         }
     }
 
-    /// Get a pin from the pin manager.
+    /// Claim a pin from the pin manager.
     ///
-    /// This takes the pin, once taken, the pin cannot be retaken.
-    pub fn get_pin(&self, pin: u8) -> Result<AnyIOPin, PinError> {
-        if pin > hardware::MAX_PINS {
+    /// Once claimed, the pin cannot be claimed again.
+    pub fn claim(&self, pin: u8) -> Result<(), PinError> {
+        if pin >= hardware::MAX_PINS {
             return Err(PinError::InvalidPin(pin));
         }
 
-        let lock = self.pin_state.lock();
-        if lock.is_err() {
-            return Err(PinError::Generic {
-                pin,
-                error: format!("Pin mutex poisoned: {:?}", lock.unwrap_err()),
-            });
-        }
-
-        let mg = lock.unwrap();
+        let mg = self.pin_state.lock().map_err(|e| PinError::Generic {
+            pin,
+            error: format!("Pin mutex poisoned: {:?}", e),
+        })?;
         let mut ps = mg.borrow_mut();
         if ps[pin as usize] {
             return Err(PinError::PinInUse(pin));
         }
         ps[pin as usize] = true;
 
+        Ok(())
+    }
+
+    /// Return a pin to the manager, after its driver failed to be created.
+    fn release(&self, pin: u8) {
+        if let Ok(mg) = self.pin_state.lock() {
+            mg.borrow_mut()[pin as usize] = false;
+        }
+    }
+
+    /// Get a pin from the pin manager.
+    ///
+    /// This takes the pin, once taken, the pin cannot be retaken.
+    #[cfg(feature = "esp32s3")]
+    pub fn get_pin(&self, pin: u8) -> Result<AnyIOPin, PinError> {
+        self.claim(pin)?;
         Ok(unsafe { AnyIOPin::new(pin as i32) })
     }
 
     /// Get a pin and designate it as an input.
-    pub fn get_input(&self, pin: u8, pull: Pull) -> Result<GpioInput, PinError> {
-        let p = self.get_pin(pin)?;
-        let mut input = PinDriver::input(p).unwrap();
+    ///
+    /// Pins are never returned to the manager, so the driver may be moved to another thread. A pin whose driver fails to
+    /// be created is released, to be claimed again.
+    pub fn get_input(&self, pin: u8, pull: Pull) -> Result<GpioInput<'static>, PinError> {
+        self.claim(pin)?;
+        self.gpio
+            .input(pin, pull)
+            .inspect_err(|_| self.release(pin))
+    }
 
-        input.set_pull(pull).map_err(|e| PinError::Generic {
-            pin,
-            error: format!("Failed to set pin pull mode: {:?}", e),
-        })?;
+    /// Get a pin and designate it as an output. As with inputs, the pin is released if its driver fails.
+    pub fn get_output(&self, pin: u8, level: Level) -> Result<GpioOutput<'static>, PinError> {
+        self.claim(pin)?;
+        self.gpio
+            .output(pin, level)
+            .inspect_err(|_| self.release(pin))
+    }
+}
+
+#[cfg(all(test, feature = "host"))]
+mod tests {
+    use super::*;
+    use crate::hal::host::SimGpio;
 
-        Ok(input)
+    #[test]
+    fn pins_can_only_be_taken_once() {
+        let pm = unsafe { PinManager::new(Arc::new(SimGpio::new())) };
+
+        assert!(pm.get_input(9, Pull::Down).is_ok());
+        assert!(matches!(
+            pm.get_output(9, Level::Low),
+            Err(PinError::PinInUse(9))
+        ));
+        assert!(matches!(
+            pm.claim(hardware::MAX_PINS),
+            Err(PinError::InvalidPin(_))
+        ));
     }
 
-    /// Get a pin and designate it as an output.
-    pub fn get_output(&self, pin: u8, level: Level) -> Result<GpioOutput, PinError> {
-        let p = self.get_pin(pin)?;
-        let mut output = PinDriver::output(p).unwrap();
+    #[test]
+    fn pins_are_released_when_the_driver_fails() {
+        let gpio = Arc::new(SimGpio::new());
+        let pm = unsafe { PinManager::new(gpio.clone()) };
+        gpio.fail(5);
+        gpio.fail(6);
 
-        output.set_level(level).map_err(|e| PinError::Generic {
-            pin,
-            error: format!("Failed to set pin level: {:?}", e),
-        })?;
+        assert!(matches!(
+            pm.get_input(5, Pull::Up),
+            Err(PinError::Generic { pin: 5, .. })
+        ));
+        assert!(matches!(
+            pm.get_output(6, Level::High),
+            Err(PinError::Generic { pin: 6, .. })
+        ));
+        assert!(pm.claim(5).is_ok());
+        assert!(pm.claim(6).is_ok());
+    }
+
+    #[test]
+    fn outputs_drive_the_pin() {
+        let gpio = Arc::new(SimGpio::new());
+        let pm = unsafe { PinManager::new(gpio.clone()) };
 
-        Ok(output)
+        let mut out = pm.get_output(4, Level::High).unwrap();
+        assert_eq!(gpio.level(4), Level::High);
+        out.set_level(Level::Low).unwrap();
+        assert_eq!(gpio.level(4), Level::Low);
     }
 }
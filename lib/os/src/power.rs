@@ -0,0 +1,179 @@
+//! Deep sleep & state kept across it.
+//!
+//! A battery-powered device sleeps between events, waking on a GPIO level, a timer or a touch pad. On waking it boots
+//! afresh, so anything it needs to remember is saved to RTC memory with `RtcMemory` before sleeping.
+
+use std::sync::Arc;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::error::{OsError, SleepError};
+use crate::flash::{frame, unframe, HEADER_LEN};
+use crate::hal::{Platform, WakeLevel, WakeSource, RTC_MEMORY_LEN};
+use crate::physical::hardware;
+
+/// Validate wake sources, merging GPIO sources into one as the device arms a single set of wake pins, all waking at the
+/// same level.
+pub fn wake_sources(sources: &[WakeSource]) -> Result<Vec<WakeSource>, SleepError> {
+    let mut merged: Vec<WakeSource> = vec![];
+    let mut wake_pins: Option<(Vec<u8>, WakeLevel)> = None;
+
+    for source in sources {
+        match source {
+            WakeSource::Gpio { pins, level } => {
+                if let Some(pin) = pins.iter().find(|p| **p > hardware::MAX_RTC_PIN) {
+                    return Err(SleepError::NotRtcPin(*pin));
+                }
+
+                match wake_pins.as_mut() {
+                    Some((_, l)) if l != level => return Err(SleepError::ConflictingSources),
+                    Some((all, _)) => {
+                        for pin in pins {
+                            if !all.contains(pin) {
+                                all.push(*pin);
+                            }
+                        }
+                    }
+                    None => wake_pins = Some((pins.clone(), *level)),
+                }
+            }
+            WakeSource::Touch { pad, .. } => {
+                if !hardware::TOUCH_PADS.contains(pad) {
+                    return Err(SleepError::NotTouchPad(*pad));
+                }
+                if merged.iter().any(|s| matches!(s, WakeSource::Touch { .. })) {
+                    return Err(SleepError::ConflictingSources);
+                }
+                merged.push(source.clone());
+            }
+            WakeSource::Timer(_) => merged.push(source.clone()),
+        }
+    }
+
+    if let Some((pins, level)) = wake_pins.filter(|(pins, _)| !pins.is_empty()) {
+        merged.push(WakeSource::Gpio { pins, level });
+    }
+
+    match merged.is_empty() {
+        true => Err(SleepError::NoWakeSource),
+        false => Ok(merged),
+    }
+}
+
+/// Small state kept in RTC memory across deep sleep, lost on reset or power loss.
+#[derive(Clone)]
+pub struct RtcMemory {
+    platform: Arc<dyn Platform>,
+}
+
+impl RtcMemory {
+    /// Largest serialised state that fits.
+    pub const CAPACITY: usize = RTC_MEMORY_LEN - HEADER_LEN;
+
+    pub fn new(platform: Arc<dyn Platform>) -> Self {
+        RtcMemory { platform }
+    }
+
+    /// Replace the saved state.
+    pub fn save<T: Serialize>(&self, state: &T) -> Result<(), OsError> {
+        let payload = serde_json::to_vec(state).map_err(|e| OsError::Parse(e.to_string()))?;
+        if payload.len() > Self::CAPACITY {
+            return Err(SleepError::StateTooLarge {
+                len: payload.len(),
+                max: Self::CAPACITY,
+            }
+            .into());
+        }
+
+        let mut memory = [0; RTC_MEMORY_LEN];
+        let framed = frame(&payload);
+        memory[..framed.len()].copy_from_slice(&framed);
+        self.platform.write_rtc(&memory);
+        Ok(())
+    }
+
+    /// The saved state, if there is any of this type.
+    pub fn load<T: DeserializeOwned>(&self) -> Option<T> {
+        let memory = self.platform.read_rtc();
+        let len = u32::from_le_bytes([memory[0], memory[1], memory[2], memory[3]]) as usize;
+        if len > Self::CAPACITY {
+            return None;
+        }
+
+        let payload = unframe(&memory[..HEADER_LEN + len]).ok()?;
+        serde_json::from_slice(payload).ok()
+    }
+
+    pub fn clear(&self) {
+        self.platform.write_rtc(&[0; RTC_MEMORY_LEN]);
+    }
+}
+
+#[cfg(all(test, feature = "host"))]
+mod tests {
+    use super::*;
+    use crate::hal::host::HostPlatform;
+    use std::time::Duration;
+
+    #[test]
+    fn wake_sources_are_validated_and_merged() {
+        let gpio = |pins: &[u8], level| WakeSource::Gpio {
+            pins: pins.to_vec(),
+            level,
+        };
+        let touch = |pad| WakeSource::Touch {
+            pad,
+            threshold: 1000,
+        };
+        let timer = WakeSource::Timer(Duration::from_secs(60));
+
+        assert_eq!(
+            wake_sources(&[
+                gpio(&[4], WakeLevel::AnyHigh),
+                timer.clone(),
+                gpio(&[5, 4], WakeLevel::AnyHigh)
+            ]),
+            Ok(vec![timer.clone(), gpio(&[4, 5], WakeLevel::AnyHigh)])
+        );
+        assert_eq!(
+            wake_sources(&[gpio(&[2], WakeLevel::AnyLow), gpio(&[3], WakeLevel::AnyLow)]),
+            Ok(vec![gpio(&[2, 3], WakeLevel::AnyLow)])
+        );
+
+        assert_eq!(wake_sources(&[]), Err(SleepError::NoWakeSource));
+        assert_eq!(
+            wake_sources(&[gpio(&[38], WakeLevel::AnyHigh)]),
+            Err(SleepError::NotRtcPin(38))
+        );
+        assert_eq!(
+            wake_sources(&[
+                gpio(&[4], WakeLevel::AnyHigh),
+                gpio(&[5], WakeLevel::AnyLow)
+            ]),
+            Err(SleepError::ConflictingSources)
+        );
+        assert_eq!(wake_sources(&[touch(0)]), Err(SleepError::NotTouchPad(0)));
+        assert_eq!(
+            wake_sources(&[touch(1), touch(2)]),
+            Err(SleepError::ConflictingSources)
+        );
+    }
+
+    #[test]
+    fn state_is_kept_in_rtc_memory() {
+        let rtc = RtcMemory::new(Arc::new(HostPlatform::new()));
+        assert_eq!(rtc.load::<u32>(), None);
+
+        rtc.save(&(42u32, "armed".to_string())).unwrap();
+        assert_eq!(rtc.load(), Some((42u32, "armed".to_string())));
+        assert_eq!(rtc.load::<bool>(), None);
+
+        assert!(matches!(
+            rtc.save(&"x".repeat(RtcMemory::CAPACITY)),
+            Err(OsError::Sleep(SleepError::StateTooLarge { .. }))
+        ));
+        rtc.clear();
+        assert_eq!(rtc.load::<(u32, String)>(), None);
+    }
+}
@@ -0,0 +1,420 @@
+//! Provisioning of a device that has no WiFi settings.
+//!
+//! The device hosts an open access point serving a configuration page. Every web request is answered with that page
+//! and every DNS query resolves to the device, so phones & laptops joining the network present it as a captive portal.
+//! Once valid settings have been submitted they are written through `Settings` and the device should be restarted.
+
+use std::io::ErrorKind;
+use std::net::{Ipv4Addr, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::error::{OsError, SettingsError};
+use crate::hal::http::{Method, Request, Response};
+use crate::hal::wifi::AuthMethod;
+use crate::hal::{Platform, ThreadOptions, WifiDriver};
+use crate::settings::{power, Settings};
+
+const LOG_TGT: &str = "inu.provision";
+
+/// SSID of the open network hosted while provisioning.
+pub const PORTAL_SSID: &str = "inu-setup";
+
+const HTTP_PORT: u16 = 80;
+const DNS_PORT: u16 = 53;
+
+/// Host the provisioning portal until settings have been saved.
+pub fn run(platform: &dyn Platform, wifi: &mut dyn WifiDriver) -> Result<(), OsError> {
+    let portal = Arc::new(Portal::new(Settings::unprovisioned(platform)?));
+
+    let ip = wifi.start_access_point(PORTAL_SSID)?;
+    log::info!(target: LOG_TGT, "Provisioning portal on '{}' at http://{}/", PORTAL_SSID, ip);
+
+    let handler = portal.clone();
+    let _server = platform.http_server(HTTP_PORT, Arc::new(move |req| handler.handle(req)))?;
+
+    let dns = portal.clone();
+    platform.spawn(
+        ThreadOptions {
+            priority: 5,
+            core: None,
+            stack_size: 4096,
+        },
+        Box::new(move || {
+            if let Err(e) = serve_dns(ip, &dns) {
+                log::warn!(target: LOG_TGT, "Captive DNS unavailable: {:?}", e);
+            }
+        }),
+    )?;
+
+    while !portal.is_saved() {
+        thread::sleep(Duration::from_millis(100));
+    }
+
+    // Give the confirmation page time to reach the client
+    thread::sleep(Duration::from_secs(1));
+    Ok(())
+}
+
+/// Resolve every name to the device until the portal is done.
+fn serve_dns(ip: Ipv4Addr, portal: &Portal) -> Result<(), OsError> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, DNS_PORT))?;
+    socket.set_read_timeout(Some(Duration::from_secs(1)))?;
+
+    let mut buffer = [0u8; 512];
+    while !portal.is_saved() {
+        match socket.recv_from(&mut buffer) {
+            Ok((len, peer)) => {
+                if let Some(answer) = dns_answer(&buffer[..len], ip) {
+                    socket.send_to(&answer, peer)?;
+                }
+            }
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    Ok(())
+}
+
+/// Answer a DNS query with the given address, whatever the name. Queries for anything other than an A record get an
+/// empty answer, so that clients fall back to IPv4.
+pub fn dns_answer(query: &[u8], ip: Ipv4Addr) -> Option<Vec<u8>> {
+    const HEADER_LEN: usize = 12;
+    const TYPE_A: u16 = 1;
+    const TYPE_ANY: u16 = 255;
+
+    // Must be a standard query with at least one question
+    if query.len() < HEADER_LEN || query[2] & 0xf8 != 0 || query[4..6] == [0, 0] {
+        return None;
+    }
+
+    // Only the first question is answered
+    let mut end = HEADER_LEN;
+    loop {
+        let len = *query.get(end)? as usize;
+        if len & 0xc0 != 0 {
+            return None;
+        }
+        end += 1 + len;
+        if len == 0 {
+            break;
+        }
+    }
+    let qtype = u16::from_be_bytes([*query.get(end)?, *query.get(end + 1)?]);
+    end += 4;
+    if end > query.len() {
+        return None;
+    }
+
+    let answered = qtype == TYPE_A || qtype == TYPE_ANY;
+    let mut answer = Vec::with_capacity(end + 16);
+    answer.extend_from_slice(&query[..2]);
+    // Authoritative response, echoing the recursion desired flag
+    answer.extend_from_slice(&[0x84 | (query[2] & 0x01), 0x00]);
+    answer.extend_from_slice(&[0, 1, 0, answered as u8, 0, 0, 0, 0]);
+    answer.extend_from_slice(&query[HEADER_LEN..end]);
+
+    if answered {
+        // Name points back at the question, class IN, TTL 60s
+        answer.extend_from_slice(&[0xc0, 0x0c, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4]);
+        answer.extend_from_slice(&ip.octets());
+    }
+
+    Some(answer)
+}
+
+/// Handles requests to the configuration page.
+pub struct Portal {
+    settings: Mutex<Settings>,
+    saved: AtomicBool,
+}
+
+impl Portal {
+    pub fn new(settings: Settings) -> Self {
+        Portal {
+            settings: Mutex::new(settings),
+            saved: AtomicBool::new(false),
+        }
+    }
+
+    /// True once valid settings have been written.
+    pub fn is_saved(&self) -> bool {
+        self.saved.load(Ordering::SeqCst)
+    }
+
+    /// Any GET shows the configuration page, any POST submits it.
+    pub fn handle(&self, request: &Request) -> Response {
+        let mut settings = self.settings.lock().unwrap();
+
+        if self.is_saved() {
+            return Response::html(200, SAVED_PAGE);
+        }
+
+        match request.method {
+            Method::Get => config_page(&settings, 200, None),
+            Method::Post => self.save(&mut settings, &parse_form(&request.body)),
+        }
+    }
+
+    fn save(&self, settings: &mut Settings, form: &[(String, String)]) -> Response {
+        let field = |name: &str| {
+            form.iter()
+                .find(|(k, _)| k == name)
+                .map(|(_, v)| v.trim().to_string())
+                .unwrap_or_default()
+        };
+
+        settings.device_id = field("device_id");
+        settings.wifi.access_point = field("ssid");
+        settings.wifi.password = field("password");
+        // The form has no auth field, an empty password being an open network
+        settings.wifi.auth_method = match settings.wifi.password.is_empty() {
+            true => AuthMethod::None,
+            false => AuthMethod::WPA2Personal,
+        };
+        settings.cpu_clock = match field("clock").parse() {
+            Ok(clock) => clock,
+            Err(_) => return config_page(settings, 400, Some("clock must be a number".into())),
+        };
+
+        match settings.write_settings() {
+            Ok(_) => {
+                log::info!(target: LOG_TGT, "Settings saved for '{}'", settings.device_id);
+                self.saved.store(true, Ordering::SeqCst);
+                Response::html(200, SAVED_PAGE)
+            }
+            Err(e) => {
+                log::warn!(target: LOG_TGT, "Rejected settings: {:?}", e);
+                config_page(settings, 400, Some(describe(&e)))
+            }
+        }
+    }
+}
+
+fn describe(e: &OsError) -> String {
+    match e {
+        OsError::Settings(SettingsError::Invalid { key, reason }) => format!("{}: {}", key, reason),
+        OsError::Settings(SettingsError::Missing(key)) => format!("{} is required", key),
+        e => format!("could not save settings ({:?})", e),
+    }
+}
+
+const SAVED_PAGE: &str = "<!DOCTYPE html><html><head><title>Inu Setup</title></head>\
+    <body><h1>Inu Setup</h1><p>Settings saved, the device is restarting.</p></body></html>";
+
+fn config_page(settings: &Settings, status: u16, error: Option<String>) -> Response {
+    let clocks: String = power::CPU_CLOCKS
+        .iter()
+        .map(|c| {
+            let selected = if *c == settings.cpu_clock {
+                " selected"
+            } else {
+                ""
+            };
+            format!("<option value=\"{0}\"{1}>{0} MHz</option>", c, selected)
+        })
+        .collect();
+
+    let error = error
+        .map(|e| format!("<p style=\"color:red\">{}</p>", escape(&e)))
+        .unwrap_or_default();
+
+    Response::html(
+        status,
+        format!(
+            "<!DOCTYPE html><html><head><title>Inu Setup</title>\
+            <meta name=\"viewport\" content=\"width=device-width, initial-scale=1\"></head>\
+            <body><h1>Inu Setup</h1>{}<form method=\"post\" action=\"/\">\
+            <p><label>Device ID<br><input name=\"device_id\" value=\"{}\"></label></p>\
+            <p><label>WiFi SSID<br><input name=\"ssid\" value=\"{}\"></label></p>\
+            <p><label>WiFi password (empty for an open network)<br><input name=\"password\" type=\"password\"></label></p>\
+            <p><label>CPU clock<br><select name=\"clock\">{}</select></label></p>\
+            <p><button type=\"submit\">Save</button></p></form></body></html>",
+            error,
+            escape(&settings.device_id),
+            escape(&settings.wifi.access_point),
+            clocks,
+        ),
+    )
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Decode an `application/x-www-form-urlencoded` body.
+pub fn parse_form(body: &[u8]) -> Vec<(String, String)> {
+    String::from_utf8_lossy(body)
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (k, v) = pair.split_once('=').unwrap_or((pair, ""));
+            (url_decode(k), url_decode(v))
+        })
+        .collect()
+}
+
+fn url_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' if i + 2 < bytes.len() => match (hex(bytes[i + 1]), hex(bytes[i + 2])) {
+                (Some(high), Some(low)) => {
+                    decoded.push(high << 4 | low);
+                    i += 2;
+                }
+                _ => decoded.push(b'%'),
+            },
+            b => decoded.push(b),
+        }
+        i += 1;
+    }
+
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+fn hex(digit: u8) -> Option<u8> {
+    (digit as char).to_digit(16).map(|d| d as u8)
+}
+
+#[cfg(all(test, feature = "host"))]
+mod tests {
+    use super::*;
+    use crate::hal::host::HostPlatform;
+    use crate::hal::http::MAX_BODY_LEN;
+    use std::time::Instant;
+
+    fn post(body: &str) -> Request {
+        Request {
+            method: Method::Post,
+            uri: "/".into(),
+            body: body.as_bytes().to_vec(),
+        }
+    }
+
+    #[test]
+    fn forms_are_decoded() {
+        assert_eq!(
+            parse_form(b"ssid=My+Net%21&password=p%26ss%3D1&bad=%zz&empty"),
+            vec![
+                ("ssid".to_string(), "My Net!".to_string()),
+                ("password".to_string(), "p&ss=1".to_string()),
+                ("bad".to_string(), "%zz".to_string()),
+                ("empty".to_string(), String::new()),
+            ]
+        );
+    }
+
+    #[test]
+    fn dns_queries_resolve_to_the_device() {
+        // Query for "inu.local", type A, class IN, recursion desired
+        let mut query = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+        query.extend_from_slice(b"\x03inu\x05local\x00\x00\x01\x00\x01");
+        let ip = Ipv4Addr::new(192, 168, 71, 1);
+
+        let answer = dns_answer(&query, ip).unwrap();
+        assert_eq!(&answer[..4], &[0x12, 0x34, 0x85, 0x00]);
+        assert_eq!(&answer[4..12], &[0, 1, 0, 1, 0, 0, 0, 0]);
+        assert_eq!(&answer[12..query.len()], &query[12..]);
+        assert_eq!(&answer[answer.len() - 4..], &[192, 168, 71, 1]);
+
+        // AAAA gets an empty answer, responses & truncated queries are ignored
+        let last = query.len() - 3;
+        query[last] = 28;
+        assert_eq!(&dns_answer(&query, ip).unwrap()[6..8], &[0, 0]);
+        query[2] = 0x81;
+        assert!(dns_answer(&query, ip).is_none());
+        assert!(dns_answer(&query[..14], ip).is_none());
+    }
+
+    #[test]
+    fn portal_writes_settings_then_finishes() {
+        let platform = Arc::new(HostPlatform::new());
+        let http = platform.http_sim();
+        let wifi = platform.wifi_sim();
+
+        let p = platform.clone();
+        let handle = thread::spawn(move || {
+            let mut wifi = p.wifi().unwrap();
+            run(p.as_ref(), wifi.as_mut())
+        });
+
+        let start = Instant::now();
+        let get = Request {
+            method: Method::Get,
+            uri: "/generate_204".into(),
+            body: vec![],
+        };
+        let page = loop {
+            if let Some(r) = http.request(HTTP_PORT, &get) {
+                break r;
+            }
+            assert!(start.elapsed() < Duration::from_secs(5));
+            thread::sleep(Duration::from_millis(10));
+        };
+        assert_eq!(page.status, 200);
+        assert!(String::from_utf8(page.body).unwrap().contains("<form"));
+        assert_eq!(wifi.hosted_access_point().as_deref(), Some(PORTAL_SSID));
+
+        // An oversized form is refused rather than saved truncated
+        let long = format!(
+            "device_id=inu.test&ssid=home&password=password&clock=160&pad={}",
+            "x".repeat(MAX_BODY_LEN)
+        );
+        let refused = http.request(HTTP_PORT, &post(&long)).unwrap();
+        assert_eq!(refused.status, 413);
+        assert!(Settings::new(platform.as_ref()).is_err());
+
+        let rejected = http
+            .request(
+                HTTP_PORT,
+                &post("device_id=inu.test&ssid=home&password=short&clock=160"),
+            )
+            .unwrap();
+        assert_eq!(rejected.status, 400);
+        assert!(String::from_utf8(rejected.body)
+            .unwrap()
+            .contains("wifi_pw"));
+        assert!(Settings::new(platform.as_ref()).is_err());
+
+        let saved = http
+            .request(
+                HTTP_PORT,
+                &post("device_id=inu.test&ssid=home&password=password&clock=240"),
+            )
+            .unwrap();
+        assert_eq!(saved.status, 200);
+        handle.join().unwrap().unwrap();
+
+        // The server has stopped & the device can now boot normally
+        assert!(http.request(HTTP_PORT, &get).is_none());
+        let settings = Settings::new(platform.as_ref()).unwrap();
+        assert_eq!(settings.device_id, "inu.test");
+        assert_eq!(settings.wifi.access_point, "home");
+        assert_eq!(settings.wifi.auth_method, AuthMethod::WPA2Personal);
+        assert_eq!(settings.cpu_clock, 240);
+    }
+
+    #[test]
+    fn empty_password_saves_an_open_network() {
+        let platform = HostPlatform::new();
+        let portal = Portal::new(Settings::unprovisioned(&platform).unwrap());
+
+        let saved = portal.handle(&post("device_id=inu.test&ssid=cafe&password=&clock=160"));
+        assert_eq!(saved.status, 200);
+
+        let settings = Settings::new(&platform).unwrap();
+        assert_eq!(settings.wifi.password, "");
+        assert_eq!(settings.wifi.auth_method, AuthMethod::None);
+    }
+}
@@ -0,0 +1,84 @@
+//! Logging settings.
+
+use core::fmt;
+use core::str::FromStr;
+
+pub use crate::logging::Filter;
+
+/// Default port of a syslog server.
+pub const SYSLOG_PORT: u16 = 514;
+
+/// Where log records are streamed to.
+///
+/// Stored as `syslog://host[:port]` for a syslog server, or `inu` for the Inu network.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RemoteSink {
+    Syslog { host: String, port: u16 },
+    Inu,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Logging {
+    /// Records logged to the serial console.
+    pub levels: Filter,
+    /// Stream log records to the network, as well as the serial console.
+    pub remote: Option<RemoteSink>,
+    /// Records streamed to the network.
+    pub remote_filter: Filter,
+}
+
+impl FromStr for RemoteSink {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "inu" {
+            return Ok(RemoteSink::Inu);
+        }
+
+        let address = s
+            .strip_prefix("syslog://")
+            .ok_or("must be 'inu' or a 'syslog://' address")?;
+        let (host, port) = match address.rsplit_once(':') {
+            Some((host, port)) => (
+                host,
+                port.parse()
+                    .map_err(|_| format!("'{}' is not a valid port", port))?,
+            ),
+            None => (address, SYSLOG_PORT),
+        };
+
+        if host.is_empty() || host.contains(|c: char| c.is_whitespace() || c == '/') {
+            return Err(format!("'{}' is not a valid host", host));
+        }
+
+        Ok(RemoteSink::Syslog {
+            host: host.to_string(),
+            port,
+        })
+    }
+}
+
+impl fmt::Display for RemoteSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RemoteSink::Syslog { host, port } => write!(f, "syslog://{}:{}", host, port),
+            RemoteSink::Inu => f.write_str("inu"),
+        }
+    }
+}
+
+/// Parse the `log_remote` setting, where empty disables remote logging.
+pub fn parse_remote(s: &str) -> Result<Option<RemoteSink>, String> {
+    match s {
+        "" => Ok(None),
+        s => s.parse().map(Some),
+    }
+}
+
+pub fn format_remote(remote: &Option<RemoteSink>) -> String {
+    remote.as_ref().map(|r| r.to_string()).unwrap_or_default()
+}
+
+pub fn parse_filter(s: &str) -> Result<Filter, String> {
+    s.parse()
+}
@@ -0,0 +1,420 @@
+use crate::error::{OsError, SettingsError};
+use crate::flash::{Flash, Recovery};
+use crate::hal::{Platform, PowerConfig};
+
+pub mod logging;
+pub mod power;
+pub mod schema;
+pub mod wifi;
+
+pub use logging::{Logging, RemoteSink};
+pub use schema::SettingValue;
+use schema::{
+    KEY_CLOCK, KEY_DEVICE_ID, KEY_LIGHT_SLEEP, KEY_LOG_LEVELS, KEY_LOG_REMOTE, KEY_LOG_REMOTE_LVL,
+    KEY_MIN_BUILD, KEY_TRIGGER_CODE, KEY_WIFI_AP, KEY_WIFI_AUTH, KEY_WIFI_BSSID, KEY_WIFI_CHANNEL,
+    KEY_WIFI_NETS, KEY_WIFI_PW,
+};
+pub use wifi::{WiFi, WifiNetwork};
+
+const SETTINGS_PARTITION: &str = "cfg";
+const SETTINGS_NAMESPACE: &str = "settings";
+const SETTINGS_BACKUP_NAMESPACE: &str = "settings.bak";
+
+pub struct Settings {
+    flash: Flash,
+    pub device_id: String,
+    /// CPU clock in MHz, one of `power::CPU_CLOCKS`.
+    pub cpu_clock: u16,
+    /// Light sleep when idle.
+    pub light_sleep: bool,
+    pub wifi: WiFi,
+    /// Inu trigger code published by the device's inputs.
+    pub trigger_code: u16,
+    /// Firmware updates older than this build are rejected. Raised each time an updated image is confirmed.
+    pub min_build: u32,
+    pub logging: Logging,
+}
+
+impl Settings {
+    /// Create a new settings object or an error
+    pub fn new(platform: &dyn Platform) -> Result<Self, OsError> {
+        Self::with_flash(Self::open(platform)?)
+    }
+
+    /// Open the settings of a device that may not have been provisioned yet.
+    ///
+    /// Settings that can be read are kept, anything else is left empty or at its default, ready to be completed and
+    /// written with `write_settings`.
+    pub fn unprovisioned(platform: &dyn Platform) -> Result<Self, OsError> {
+        Self::unprovisioned_with_flash(Self::open(platform)?)
+    }
+
+    /// Create a settings object backed by the given flash store
+    ///
+    /// Any pending schema migrations are applied before the settings are read.
+    pub fn with_flash(mut flash: Flash) -> Result<Self, OsError> {
+        schema::migrate(&mut flash, schema::MIGRATIONS, schema::SCHEMA_VERSION)?;
+
+        let mut s = Self::blank(flash);
+        s.read_settings()?;
+        Ok(s)
+    }
+
+    /// As `unprovisioned`, backed by the given flash store.
+    pub fn unprovisioned_with_flash(mut flash: Flash) -> Result<Self, OsError> {
+        schema::migrate(&mut flash, schema::MIGRATIONS, schema::SCHEMA_VERSION)?;
+
+        let mut s = Self::blank(flash);
+        if let Err(e) = s.read_settings() {
+            log::info!(target: schema::LOG_TGT, "Settings incomplete: {:?}", e);
+        }
+        Ok(s)
+    }
+
+    fn open(platform: &dyn Platform) -> Result<Flash, OsError> {
+        Ok(
+            Flash::new(platform, SETTINGS_PARTITION, SETTINGS_NAMESPACE)?
+                .with_backup(platform.storage(SETTINGS_PARTITION, SETTINGS_BACKUP_NAMESPACE)?)
+                .with_recovery(|_, _| Recovery::PreviousCopy),
+        )
+    }
+
+    fn blank(flash: Flash) -> Self {
+        Settings {
+            flash,
+            device_id: String::new(),
+            cpu_clock: 0,
+            light_sleep: false,
+            wifi: WiFi::default(),
+            trigger_code: 0,
+            min_build: 0,
+            logging: Logging::default(),
+        }
+    }
+}
+
+impl Settings {
+    /// Read application settings from the NVS partition.
+    ///
+    /// Fails if a required setting is missing or invalid. Settings with a default are read first, so that those are
+    /// still available if a required setting is not.
+    pub fn read_settings(&mut self) -> Result<(), SettingsError> {
+        self.cpu_clock = self.load_u16(KEY_CLOCK)?;
+        self.trigger_code = self.load_u16(KEY_TRIGGER_CODE)?;
+        self.min_build = self.load_u32(KEY_MIN_BUILD)?;
+
+        // Values have been validated, parsing can't fail
+        let invalid = |key: &'static str| move |reason| SettingsError::Invalid { key, reason };
+        self.light_sleep = power::parse_light_sleep(self.load_u16(KEY_LIGHT_SLEEP)?)
+            .map_err(invalid(KEY_LIGHT_SLEEP))?;
+        self.wifi.auth_method =
+            wifi::parse_auth(&self.load_str(KEY_WIFI_AUTH)?).map_err(invalid(KEY_WIFI_AUTH))?;
+        self.wifi.bssid =
+            wifi::parse_bssid(&self.load_str(KEY_WIFI_BSSID)?).map_err(invalid(KEY_WIFI_BSSID))?;
+        self.wifi.channel = wifi::parse_channel(self.load_u16(KEY_WIFI_CHANNEL)?)
+            .map_err(invalid(KEY_WIFI_CHANNEL))?;
+        self.wifi.fallback =
+            wifi::parse_networks(&self.load_str(KEY_WIFI_NETS)?).map_err(invalid(KEY_WIFI_NETS))?;
+        self.logging.levels = logging::parse_filter(&self.load_str(KEY_LOG_LEVELS)?)
+            .map_err(invalid(KEY_LOG_LEVELS))?;
+        self.logging.remote = logging::parse_remote(&self.load_str(KEY_LOG_REMOTE)?)
+            .map_err(invalid(KEY_LOG_REMOTE))?;
+        self.logging.remote_filter = logging::parse_filter(&self.load_str(KEY_LOG_REMOTE_LVL)?)
+            .map_err(invalid(KEY_LOG_REMOTE_LVL))?;
+
+        self.device_id = self.load_str(KEY_DEVICE_ID)?;
+        self.wifi.access_point = self.load_str(KEY_WIFI_AP)?;
+        self.wifi.password = self.load_str(KEY_WIFI_PW)?;
+
+        Ok(())
+    }
+
+    /// Write application settings to the NVS partition.
+    ///
+    /// All values are validated before anything is written.
+    pub fn write_settings(&mut self) -> Result<(), OsError> {
+        let values = [
+            (KEY_DEVICE_ID, SettingValue::Str(self.device_id.clone())),
+            (KEY_CLOCK, SettingValue::U16(self.cpu_clock)),
+            (KEY_LIGHT_SLEEP, SettingValue::U16(self.light_sleep as u16)),
+            (
+                KEY_WIFI_AP,
+                SettingValue::Str(self.wifi.access_point.clone()),
+            ),
+            (KEY_WIFI_PW, SettingValue::Str(self.wifi.password.clone())),
+            (
+                KEY_WIFI_AUTH,
+                SettingValue::Str(wifi::format_auth(self.wifi.auth_method)),
+            ),
+            (
+                KEY_WIFI_BSSID,
+                SettingValue::Str(wifi::format_bssid(self.wifi.bssid)),
+            ),
+            (
+                KEY_WIFI_CHANNEL,
+                SettingValue::U16(self.wifi.channel.unwrap_or(0) as u16),
+            ),
+            (
+                KEY_WIFI_NETS,
+                SettingValue::Str(wifi::format_networks(&self.wifi.fallback)),
+            ),
+            (KEY_TRIGGER_CODE, SettingValue::U16(self.trigger_code)),
+            (KEY_MIN_BUILD, SettingValue::U32(self.min_build)),
+            (
+                KEY_LOG_LEVELS,
+                SettingValue::Str(self.logging.levels.to_string()),
+            ),
+            (
+                KEY_LOG_REMOTE,
+                SettingValue::Str(logging::format_remote(&self.logging.remote)),
+            ),
+            (
+                KEY_LOG_REMOTE_LVL,
+                SettingValue::Str(self.logging.remote_filter.to_string()),
+            ),
+        ];
+
+        for (key, value) in values.iter() {
+            Self::def(key).validate(value)?;
+        }
+
+        for (key, value) in values.iter() {
+            Self::def(key).store(&mut self.flash, value)?;
+        }
+
+        Ok(())
+    }
+
+    /// Validate & write a single setting to the NVS partition, leaving the others untouched.
+    ///
+    /// This doesn't change the setting's field, which the caller is expected to keep in step with the stored value.
+    pub fn write_setting(&mut self, key: &'static str, value: SettingValue) -> Result<(), OsError> {
+        Self::def(key).store(&mut self.flash, &value)?;
+        Ok(())
+    }
+
+    /// CPU frequency & sleep configuration for the `cpu_clock` & `light_sleep` settings.
+    pub fn power(&self) -> Result<PowerConfig, SettingsError> {
+        power::power_config(self.cpu_clock, self.light_sleep).map_err(|reason| {
+            SettingsError::Invalid {
+                key: KEY_CLOCK,
+                reason,
+            }
+        })
+    }
+
+    fn def(key: &str) -> &'static schema::SettingDef {
+        schema::find(key).expect("setting missing from schema")
+    }
+
+    fn load_str(&self, key: &str) -> Result<String, SettingsError> {
+        match Self::def(key).load(&self.flash)? {
+            SettingValue::Str(s) => Ok(s),
+            v => unreachable!("{} loaded as {:?}", key, v),
+        }
+    }
+
+    fn load_u16(&self, key: &str) -> Result<u16, SettingsError> {
+        match Self::def(key).load(&self.flash)? {
+            SettingValue::U16(v) => Ok(v),
+            v => unreachable!("{} loaded as {:?}", key, v),
+        }
+    }
+
+    fn load_u32(&self, key: &str) -> Result<u32, SettingsError> {
+        match Self::def(key).load(&self.flash)? {
+            SettingValue::U32(v) => Ok(v),
+            v => unreachable!("{} loaded as {:?}", key, v),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "host"))]
+mod tests {
+    use super::schema::{migrate, Migration, VERSION_KEY};
+    use super::*;
+    use crate::error::FlashError;
+    use crate::flash::{Readable, Writable};
+    use crate::hal::host::MemoryStorage;
+    use crate::hal::Storage;
+
+    /// A store provisioned by the config tool, holding native NVS values.
+    fn provisioned() -> MemoryStorage {
+        let mut store = MemoryStorage::new();
+        store.set_str("device_id", "inu.test").unwrap();
+        store.set_str("wifi_ap", "inu-ap").unwrap();
+        store.set_str("wifi_pw", "password").unwrap();
+        store
+    }
+
+    #[test]
+    fn legacy_values_are_migrated_and_defaults_apply() {
+        // As on NVS, a native value can't be overwritten by a blob, so migration must remove it first
+        let mut store = provisioned();
+        assert!(store.set_blob("device_id", b"inu.test").is_err());
+        let s = Settings::with_flash(Flash::with_storage(Box::new(store.clone()))).unwrap();
+
+        assert_eq!(s.device_id, "inu.test");
+        assert_eq!(s.cpu_clock, 160);
+        assert_eq!(s.trigger_code, 1);
+        assert_eq!(
+            store.get_u16(VERSION_KEY).unwrap(),
+            Some(schema::SCHEMA_VERSION)
+        );
+
+        // Values are now framed, so reading them natively fails
+        assert!(store.get_str("device_id").is_err());
+        let flash = Flash::with_storage(Box::new(store));
+        assert_eq!(
+            Readable::<String>::read(&flash, "wifi_pw").unwrap(),
+            "password"
+        );
+    }
+
+    #[test]
+    fn missing_and_invalid_settings_are_rejected() {
+        let mut store = provisioned();
+        store.remove("wifi_ap").unwrap();
+        assert!(matches!(
+            Settings::with_flash(Flash::with_storage(Box::new(store.clone()))),
+            Err(OsError::Settings(SettingsError::Missing("wifi_ap")))
+        ));
+
+        let mut flash = Flash::with_storage(Box::new(store));
+        flash.write("wifi_ap", "inu-ap").unwrap();
+        flash.write("device_id", "Bad ID!").unwrap();
+        assert!(matches!(
+            Settings::with_flash(flash),
+            Err(OsError::Settings(SettingsError::Invalid {
+                key: "device_id",
+                ..
+            }))
+        ));
+    }
+
+    #[test]
+    fn write_settings_validates_before_writing() {
+        let store = provisioned();
+        let mut s = Settings::with_flash(Flash::with_storage(Box::new(store.clone()))).unwrap();
+
+        let stored = Flash::with_storage(Box::new(store));
+
+        s.device_id = "inu.renamed".into();
+        s.min_build = 3;
+        s.write_settings().unwrap();
+        assert_eq!(
+            Readable::<String>::read(&stored, "device_id").unwrap(),
+            "inu.renamed"
+        );
+        assert_eq!(Readable::<u16>::read(&stored, "clock").unwrap(), 160);
+        assert_eq!(Readable::<u32>::read(&stored, "min_build").unwrap(), 3);
+
+        s.device_id = "inu.other".into();
+        s.wifi.password = "short".into();
+        assert!(s.write_settings().is_err());
+        assert_eq!(
+            Readable::<String>::read(&stored, "device_id").unwrap(),
+            "inu.renamed"
+        );
+    }
+
+    #[test]
+    fn single_settings_are_written_alone() {
+        let store = provisioned();
+        let mut s = Settings::with_flash(Flash::with_storage(Box::new(store.clone()))).unwrap();
+        let stored = Flash::with_storage(Box::new(store));
+
+        // An invalid field elsewhere doesn't block writing a valid setting
+        s.wifi.password = "short".into();
+        s.write_setting(KEY_CLOCK, SettingValue::U16(240)).unwrap();
+        assert_eq!(Readable::<u16>::read(&stored, "clock").unwrap(), 240);
+        assert_ne!(
+            Readable::<String>::read(&stored, "wifi_pw").unwrap(),
+            "short"
+        );
+
+        assert!(s.write_setting(KEY_CLOCK, SettingValue::U16(100)).is_err());
+        assert!(s
+            .write_setting(KEY_CLOCK, SettingValue::Str("240".into()))
+            .is_err());
+        assert_eq!(Readable::<u16>::read(&stored, "clock").unwrap(), 240);
+    }
+
+    #[test]
+    fn logging_settings_round_trip() {
+        let store = provisioned();
+        let mut s = Settings::with_flash(Flash::with_storage(Box::new(store.clone()))).unwrap();
+        assert_eq!(s.logging.remote, None);
+        assert_eq!(s.logging.remote_filter, logging::Filter::default());
+
+        s.logging.levels.set("inu.net", log::LevelFilter::Trace);
+        s.logging.remote = Some("syslog://logs.local".parse().unwrap());
+        s.logging.remote_filter = "warn,inu.net=debug".parse().unwrap();
+        s.write_settings().unwrap();
+
+        let s = Settings::with_flash(Flash::with_storage(Box::new(store.clone()))).unwrap();
+        assert_eq!(
+            s.logging.remote,
+            Some(RemoteSink::Syslog {
+                host: "logs.local".into(),
+                port: 514
+            })
+        );
+        assert_eq!(s.logging.remote_filter.to_string(), "warn,inu.net=debug");
+        assert_eq!(s.logging.levels.to_string(), "info,inu.net=trace");
+
+        // Unusable values fall back to the default, rather than blocking the boot
+        let mut flash = Flash::with_storage(Box::new(store));
+        flash.write("log_remote", "http://logs.local").unwrap();
+        assert_eq!(Settings::with_flash(flash).unwrap().logging.remote, None);
+    }
+
+    fn rename_ap(flash: &mut Flash) -> Result<(), FlashError> {
+        let ap: String = flash.read("ap")?;
+        flash.write("wifi_ap", ap)?;
+        flash.remove("ap")?;
+        Ok(())
+    }
+
+    fn fail(_: &mut Flash) -> Result<(), FlashError> {
+        Err(FlashError::IoFault)
+    }
+
+    #[test]
+    fn migrations_run_in_order_from_the_stored_version() {
+        let migrations = [
+            Migration {
+                version: 2,
+                description: "rename ap",
+                apply: rename_ap,
+            },
+            Migration {
+                version: 3,
+                description: "broken",
+                apply: fail,
+            },
+        ];
+
+        let mut store = MemoryStorage::new();
+        let mut flash = Flash::with_storage(Box::new(store.clone()));
+        flash.write("ap", "inu-ap").unwrap();
+
+        assert_eq!(migrate(&mut flash, &migrations, 2).unwrap(), 2);
+        assert_eq!(
+            Readable::<String>::read(&flash, "wifi_ap").unwrap(),
+            "inu-ap"
+        );
+        assert!(!flash.contains("ap").unwrap());
+
+        // Already at v2, only the failing step runs and the version stays put
+        assert!(matches!(
+            migrate(&mut flash, &migrations, 3),
+            Err(SettingsError::Migration { version: 2, .. })
+        ));
+        assert_eq!(store.get_u16(VERSION_KEY).unwrap(), Some(2));
+
+        // A store from newer firmware is left alone
+        store.set_u16(VERSION_KEY, 9).unwrap();
+        assert_eq!(migrate(&mut flash, &migrations, 3).unwrap(), 9);
+    }
+}
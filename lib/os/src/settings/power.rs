@@ -0,0 +1,38 @@
+//! Power management settings.
+
+use crate::hal::PowerConfig;
+
+/// CPU clock frequencies the ESP32-S3 supports, in MHz.
+pub const CPU_CLOCKS: [u16; 3] = [80, 160, 240];
+
+/// Clock the CPU scales down to when idle, in MHz. WiFi needs the 80 MHz APB clock.
+pub const MIN_CPU_CLOCK: u16 = 80;
+
+pub fn check_cpu_clock(mhz: u16) -> Result<(), String> {
+    match CPU_CLOCKS.contains(&mhz) {
+        true => Ok(()),
+        false => Err(format!(
+            "CPU clock of {} MHz is not supported, must be 80, 160 or 240",
+            mhz
+        )),
+    }
+}
+
+pub fn parse_light_sleep(value: u16) -> Result<bool, String> {
+    match value {
+        0 => Ok(false),
+        1 => Ok(true),
+        _ => Err(format!("light sleep must be 0 or 1, not {}", value)),
+    }
+}
+
+/// Run the CPU at the given clock, scaling down to `MIN_CPU_CLOCK` when idle, and light sleep when idle if enabled.
+pub fn power_config(cpu_clock: u16, light_sleep: bool) -> Result<PowerConfig, String> {
+    check_cpu_clock(cpu_clock)?;
+
+    Ok(PowerConfig {
+        max_mhz: cpu_clock,
+        min_mhz: MIN_CPU_CLOCK,
+        light_sleep,
+    })
+}
@@ -0,0 +1,382 @@
+//! Declarative settings schema & migrations.
+//!
+//! Every setting the firmware understands is declared in `SCHEMA` with its type, an optional default and an
+//! optional validator. The layout of the settings namespace is versioned; when a build changes the layout it bumps
+//! `SCHEMA_VERSION` and appends a `Migration` that converts the previous layout.
+
+use crate::error::{FlashError, SettingsError};
+use crate::flash::{Flash, Readable, Writable};
+use crate::settings::{logging, power, wifi};
+
+pub(super) const LOG_TGT: &str = "inu.settings";
+
+/// Current version of the settings layout.
+pub const SCHEMA_VERSION: u16 = 2;
+
+/// Version assumed for stores written before the schema was versioned.
+pub const LEGACY_VERSION: u16 = 1;
+
+/// Key the schema version is stored under. This is kept as a native u16, without a frame header, so that any
+/// firmware or tool can read it.
+pub const VERSION_KEY: &str = "schema_ver";
+
+pub const KEY_DEVICE_ID: &str = "device_id";
+pub const KEY_CLOCK: &str = "clock";
+pub const KEY_LIGHT_SLEEP: &str = "light_sleep";
+pub const KEY_WIFI_AP: &str = "wifi_ap";
+pub const KEY_WIFI_PW: &str = "wifi_pw";
+pub const KEY_WIFI_AUTH: &str = "wifi_auth";
+pub const KEY_WIFI_BSSID: &str = "wifi_bssid";
+pub const KEY_WIFI_CHANNEL: &str = "wifi_channel";
+pub const KEY_WIFI_NETS: &str = "wifi_nets";
+pub const KEY_TRIGGER_CODE: &str = "trigger_code";
+pub const KEY_MIN_BUILD: &str = "min_build";
+pub const KEY_LOG_LEVELS: &str = "log_levels";
+pub const KEY_LOG_REMOTE: &str = "log_remote";
+pub const KEY_LOG_REMOTE_LVL: &str = "log_remote_lvl";
+
+pub const SCHEMA: &[SettingDef] = &[
+    SettingDef::required(KEY_DEVICE_ID, SettingKind::Str).validated(validate_device_id),
+    SettingDef::u16(KEY_CLOCK, 160).validated(validate_cpu_clock),
+    SettingDef::u16(KEY_LIGHT_SLEEP, 0).validated(validate_light_sleep),
+    SettingDef::required(KEY_WIFI_AP, SettingKind::Str).validated(validate_ssid),
+    SettingDef::required(KEY_WIFI_PW, SettingKind::Str).validated(validate_password),
+    SettingDef::str(KEY_WIFI_AUTH, "wpa2personal").validated(validate_auth),
+    SettingDef::str(KEY_WIFI_BSSID, "").validated(validate_bssid),
+    SettingDef::u16(KEY_WIFI_CHANNEL, 0).validated(validate_channel),
+    SettingDef::str(KEY_WIFI_NETS, "[]").validated(validate_networks),
+    SettingDef::u16(KEY_TRIGGER_CODE, 1),
+    SettingDef::u32(KEY_MIN_BUILD, 0),
+    SettingDef::str(KEY_LOG_LEVELS, "info").validated(validate_log_filter),
+    SettingDef::str(KEY_LOG_REMOTE, "").validated(validate_log_remote),
+    SettingDef::str(KEY_LOG_REMOTE_LVL, "info").validated(validate_log_filter),
+];
+
+/// Ordered migration steps, each upgrading the store to `Migration::version`.
+pub const MIGRATIONS: &[Migration] = &[Migration {
+    version: 2,
+    description: "add integrity header to native NVS values",
+    apply: frame_legacy_values,
+}];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SettingKind {
+    Str,
+    U16,
+    U32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SettingValue {
+    Str(String),
+    U16(u16),
+    U32(u32),
+}
+
+impl SettingValue {
+    pub fn kind(&self) -> SettingKind {
+        match self {
+            SettingValue::Str(_) => SettingKind::Str,
+            SettingValue::U16(_) => SettingKind::U16,
+            SettingValue::U32(_) => SettingKind::U32,
+        }
+    }
+}
+
+/// Checks a value, returning a human readable reason if it is rejected.
+pub type Validator = fn(&SettingValue) -> Result<(), String>;
+
+#[derive(Debug, Clone, Copy)]
+enum Fallback {
+    Str(&'static str),
+    U16(u16),
+    U32(u32),
+}
+
+impl From<Fallback> for SettingValue {
+    fn from(f: Fallback) -> Self {
+        match f {
+            Fallback::Str(s) => SettingValue::Str(s.to_string()),
+            Fallback::U16(v) => SettingValue::U16(v),
+            Fallback::U32(v) => SettingValue::U32(v),
+        }
+    }
+}
+
+/// Declaration of a single setting.
+#[derive(Debug, Clone, Copy)]
+pub struct SettingDef {
+    pub key: &'static str,
+    pub kind: SettingKind,
+    default: Option<Fallback>,
+    validator: Option<Validator>,
+}
+
+impl SettingDef {
+    /// A setting that must be provisioned, there is no default.
+    pub const fn required(key: &'static str, kind: SettingKind) -> Self {
+        Self {
+            key,
+            kind,
+            default: None,
+            validator: None,
+        }
+    }
+
+    /// A string setting with a default.
+    pub const fn str(key: &'static str, default: &'static str) -> Self {
+        Self {
+            key,
+            kind: SettingKind::Str,
+            default: Some(Fallback::Str(default)),
+            validator: None,
+        }
+    }
+
+    /// A u16 setting with a default.
+    pub const fn u16(key: &'static str, default: u16) -> Self {
+        Self {
+            key,
+            kind: SettingKind::U16,
+            default: Some(Fallback::U16(default)),
+            validator: None,
+        }
+    }
+
+    /// A u32 setting with a default.
+    pub const fn u32(key: &'static str, default: u32) -> Self {
+        Self {
+            key,
+            kind: SettingKind::U32,
+            default: Some(Fallback::U32(default)),
+            validator: None,
+        }
+    }
+
+    pub const fn validated(mut self, validator: Validator) -> Self {
+        self.validator = Some(validator);
+        self
+    }
+
+    /// The default value, if the setting has one.
+    pub fn default_value(&self) -> Option<SettingValue> {
+        self.default.map(SettingValue::from)
+    }
+
+    /// Check a value against the type & validator of this setting.
+    pub fn validate(&self, value: &SettingValue) -> Result<(), SettingsError> {
+        if value.kind() != self.kind {
+            return Err(SettingsError::Invalid {
+                key: self.key,
+                reason: format!("expected {:?}, found {:?}", self.kind, value.kind()),
+            });
+        }
+
+        match self.validator {
+            Some(v) => v(value).map_err(|reason| SettingsError::Invalid {
+                key: self.key,
+                reason,
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Read & validate the setting from flash.
+    ///
+    /// Absent or invalid values fall back to the default, if there is one.
+    pub fn load(&self, flash: &Flash) -> Result<SettingValue, SettingsError> {
+        let stored = match self.kind {
+            SettingKind::Str => flash.read(self.key).map(SettingValue::Str),
+            SettingKind::U16 => flash.read(self.key).map(SettingValue::U16),
+            SettingKind::U32 => flash.read(self.key).map(SettingValue::U32),
+        };
+
+        let error = match stored {
+            Ok(value) => match self.validate(&value) {
+                Ok(_) => return Ok(value),
+                Err(e) => e,
+            },
+            Err(FlashError::NotFound) => SettingsError::Missing(self.key),
+            Err(e) => return Err(e.into()),
+        };
+
+        match self.default_value() {
+            Some(value) => {
+                log::warn!(target: LOG_TGT, "Setting '{}' unusable ({:?}), using default {:?}", self.key, error, value);
+                Ok(value)
+            }
+            None => Err(error),
+        }
+    }
+
+    /// Validate & write the setting to flash.
+    pub fn store(&self, flash: &mut Flash, value: &SettingValue) -> Result<(), SettingsError> {
+        self.validate(value)?;
+
+        match value {
+            SettingValue::Str(s) => flash.write(self.key, s.clone())?,
+            SettingValue::U16(v) => flash.write(self.key, *v)?,
+            SettingValue::U32(v) => flash.write(self.key, *v)?,
+        }
+
+        Ok(())
+    }
+}
+
+/// Find a setting declaration by key.
+pub fn find(key: &str) -> Option<&'static SettingDef> {
+    SCHEMA.iter().find(|d| d.key == key)
+}
+
+/// A step converting the settings store from the previous schema version.
+pub struct Migration {
+    /// Schema version the store is at once this step has been applied.
+    pub version: u16,
+    pub description: &'static str,
+    pub apply: fn(&mut Flash) -> Result<(), FlashError>,
+}
+
+/// Bring the store up to `target`, applying each pending migration in order.
+///
+/// The stored version is updated after every step, so an interrupted upgrade resumes where it left off. A store
+/// written by a newer firmware is left untouched. Returns the version the store is now at.
+pub fn migrate(
+    flash: &mut Flash,
+    migrations: &[Migration],
+    target: u16,
+) -> Result<u16, SettingsError> {
+    let stored = flash.raw().get_u16(VERSION_KEY)?;
+    let mut version = stored.unwrap_or(LEGACY_VERSION);
+
+    if version > target {
+        log::warn!(
+            target: LOG_TGT,
+            "Settings schema v{} is newer than this firmware (v{}), leaving it untouched",
+            version,
+            target
+        );
+        return Ok(version);
+    }
+
+    let from = version;
+    for m in migrations
+        .iter()
+        .filter(|m| m.version > from && m.version <= target)
+    {
+        log::info!(target: LOG_TGT, "Migrating settings to v{}: {}", m.version, m.description);
+        (m.apply)(flash).map_err(|error| SettingsError::Migration { version, error })?;
+        version = m.version;
+        flash.raw().set_u16(VERSION_KEY, version)?;
+    }
+
+    if stored != Some(target) {
+        version = target;
+        flash.raw().set_u16(VERSION_KEY, version)?;
+    }
+
+    Ok(version)
+}
+
+/// v2: settings written by v1 firmware or the config tool are native NVS strings & integers. Rewrite them as framed
+/// values. Keys that can't be read natively have already been converted.
+///
+/// NVS entries are keyed by name & type, so the native entry is removed before the blob is written in its place.
+fn frame_legacy_values(flash: &mut Flash) -> Result<(), FlashError> {
+    for def in SCHEMA {
+        match def.kind {
+            SettingKind::Str => {
+                if let Ok(Some(v)) = flash.raw().get_str(def.key) {
+                    flash.raw().remove(def.key)?;
+                    flash.write(def.key, v)?;
+                }
+            }
+            SettingKind::U16 => {
+                if let Ok(Some(v)) = flash.raw().get_u16(def.key) {
+                    flash.raw().remove(def.key)?;
+                    flash.write(def.key, v)?;
+                }
+            }
+            SettingKind::U32 => {
+                if let Ok(Some(v)) = flash.raw().get_u32(def.key) {
+                    flash.raw().remove(def.key)?;
+                    flash.write(def.key, v)?;
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn as_str(value: &SettingValue) -> &str {
+    match value {
+        SettingValue::Str(s) => s.as_str(),
+        _ => "",
+    }
+}
+
+fn validate_device_id(value: &SettingValue) -> Result<(), String> {
+    let id = as_str(value);
+
+    if id.len() < 3 {
+        return Err("must be at least 3 characters".into());
+    }
+
+    if !id
+        .chars()
+        .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-' || c == '.')
+    {
+        return Err("can only contain characters a-z, 0-9, hyphen (-), and period (.)".into());
+    }
+
+    Ok(())
+}
+
+fn validate_cpu_clock(value: &SettingValue) -> Result<(), String> {
+    match value {
+        SettingValue::U16(mhz) => power::check_cpu_clock(*mhz),
+        _ => Ok(()),
+    }
+}
+
+fn validate_light_sleep(value: &SettingValue) -> Result<(), String> {
+    match value {
+        SettingValue::U16(v) => power::parse_light_sleep(*v).map(|_| ()),
+        _ => Ok(()),
+    }
+}
+
+fn validate_ssid(value: &SettingValue) -> Result<(), String> {
+    wifi::check_ssid(as_str(value))
+}
+
+fn validate_password(value: &SettingValue) -> Result<(), String> {
+    wifi::check_password(as_str(value))
+}
+
+fn validate_auth(value: &SettingValue) -> Result<(), String> {
+    wifi::parse_auth(as_str(value)).map(|_| ())
+}
+
+fn validate_bssid(value: &SettingValue) -> Result<(), String> {
+    wifi::parse_bssid(as_str(value)).map(|_| ())
+}
+
+fn validate_channel(value: &SettingValue) -> Result<(), String> {
+    match value {
+        SettingValue::U16(c) => wifi::parse_channel(*c).map(|_| ()),
+        _ => Ok(()),
+    }
+}
+
+fn validate_networks(value: &SettingValue) -> Result<(), String> {
+    wifi::parse_networks(as_str(value)).map(|_| ())
+}
+
+fn validate_log_remote(value: &SettingValue) -> Result<(), String> {
+    logging::parse_remote(as_str(value)).map(|_| ())
+}
+
+fn validate_log_filter(value: &SettingValue) -> Result<(), String> {
+    logging::parse_filter(as_str(value)).map(|_| ())
+}
@@ -0,0 +1,166 @@
+//! WiFi network settings.
+
+use core::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::hal::wifi::AuthMethod;
+
+/// A known WiFi network the device may join.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WifiNetwork {
+    pub ssid: String,
+    pub password: String,
+    pub auth_method: AuthMethod,
+    /// Only join the access point with this BSSID, eg. a single node of a mesh.
+    pub bssid: Option<[u8; 6]>,
+    /// Channel the access point is on, skipping the scan of other channels.
+    pub channel: Option<u8>,
+}
+
+/// WiFi settings. The primary network is held in the top-level fields, followed by any fallback networks in order
+/// of preference.
+#[derive(Debug, Clone, Default)]
+pub struct WiFi {
+    pub access_point: String,
+    pub password: String,
+    pub auth_method: AuthMethod,
+    pub bssid: Option<[u8; 6]>,
+    pub channel: Option<u8>,
+    pub fallback: Vec<WifiNetwork>,
+}
+
+impl WiFi {
+    /// All known networks, primary first.
+    pub fn networks(&self) -> Vec<WifiNetwork> {
+        let mut networks = vec![WifiNetwork {
+            ssid: self.access_point.clone(),
+            password: self.password.clone(),
+            auth_method: self.auth_method,
+            bssid: self.bssid,
+            channel: self.channel,
+        }];
+        networks.extend(self.fallback.iter().cloned());
+        networks
+    }
+}
+
+/// JSON representation of a fallback network, as stored in the `wifi_nets` setting.
+#[derive(Serialize, Deserialize)]
+struct StoredNetwork {
+    ssid: String,
+    #[serde(default)]
+    password: String,
+    #[serde(default)]
+    auth: String,
+    #[serde(default)]
+    bssid: String,
+    #[serde(default)]
+    channel: u8,
+}
+
+/// Parse an auth method by name (eg. "wpa2personal", "wpa3personal", "none"). Empty selects the default.
+pub fn parse_auth(s: &str) -> Result<AuthMethod, String> {
+    if s.is_empty() {
+        return Ok(AuthMethod::default());
+    }
+
+    AuthMethod::from_str(s).map_err(|_| format!("unknown auth method '{}'", s))
+}
+
+pub fn format_auth(auth: AuthMethod) -> String {
+    auth.to_string()
+}
+
+/// Parse a BSSID in the form "aa:bb:cc:dd:ee:ff". Empty means no BSSID.
+pub fn parse_bssid(s: &str) -> Result<Option<[u8; 6]>, String> {
+    if s.is_empty() {
+        return Ok(None);
+    }
+
+    let parts: Vec<&str> = s.split(':').collect();
+    if parts.len() != 6 {
+        return Err(format!("'{}' is not a BSSID", s));
+    }
+
+    let mut bssid = [0u8; 6];
+    for (i, part) in parts.iter().enumerate() {
+        bssid[i] = u8::from_str_radix(part, 16).map_err(|_| format!("'{}' is not a BSSID", s))?;
+    }
+
+    Ok(Some(bssid))
+}
+
+pub fn format_bssid(bssid: Option<[u8; 6]>) -> String {
+    match bssid {
+        Some(b) => b
+            .iter()
+            .map(|o| format!("{:02x}", o))
+            .collect::<Vec<_>>()
+            .join(":"),
+        None => String::new(),
+    }
+}
+
+/// Channel 0 means any channel.
+pub fn parse_channel(channel: u16) -> Result<Option<u8>, String> {
+    match channel {
+        0 => Ok(None),
+        1..=14 => Ok(Some(channel as u8)),
+        _ => Err(format!("channel {} is not in 1-14", channel)),
+    }
+}
+
+pub fn check_ssid(ssid: &str) -> Result<(), String> {
+    match ssid.len() {
+        0 => Err("SSID cannot be empty".into()),
+        1..=32 => Ok(()),
+        _ => Err("SSID cannot exceed 32 characters".into()),
+    }
+}
+
+/// Open networks have no password, otherwise a WPA passphrase is 8-63 characters.
+pub fn check_password(password: &str) -> Result<(), String> {
+    match password.len() {
+        0 | 8..=63 => Ok(()),
+        _ => Err("password must be between 8 and 63 characters".into()),
+    }
+}
+
+/// Parse the JSON list of fallback networks.
+pub fn parse_networks(json: &str) -> Result<Vec<WifiNetwork>, String> {
+    let stored: Vec<StoredNetwork> =
+        serde_json::from_str(json).map_err(|e| format!("invalid network list: {}", e))?;
+
+    stored
+        .into_iter()
+        .map(|n| {
+            check_ssid(&n.ssid)?;
+            check_password(&n.password)?;
+
+            Ok(WifiNetwork {
+                auth_method: parse_auth(&n.auth)?,
+                bssid: parse_bssid(&n.bssid)?,
+                channel: parse_channel(n.channel as u16)?,
+                ssid: n.ssid,
+                password: n.password,
+            })
+        })
+        .collect()
+}
+
+/// Encode fallback networks as JSON.
+pub fn format_networks(networks: &[WifiNetwork]) -> String {
+    let stored: Vec<StoredNetwork> = networks
+        .iter()
+        .map(|n| StoredNetwork {
+            ssid: n.ssid.clone(),
+            password: n.password.clone(),
+            auth: format_auth(n.auth_method),
+            bssid: format_bssid(n.bssid),
+            channel: n.channel.unwrap_or(0),
+        })
+        .collect();
+
+    serde_json::to_string(&stored).unwrap_or_else(|_| "[]".into())
+}
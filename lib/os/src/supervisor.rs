@@ -0,0 +1,432 @@
+//! Service supervisor.
+//!
+//! Long-running components of the OS, such as networking & the Inu service, are registered with the supervisor as
+//! services. Each declares its name, scheduling options & the services it depends on, and is started after its
+//! dependencies. A service runs on its own thread under a wrapper that records its exit & status, restarting it on the
+//! same thread according to its `RestartPolicy`.
+//!
+//! Panics are not recovered: the firmware is built with `panic_abort`, so a panicking service takes the device down.
+//! The panic is recorded by the `crash` log's panic hook before the reset, and one before the kernel has started counts
+//! as a failed boot, handled by the boot `FailurePolicy`. Services report failures by returning an error instead.
+
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::crash;
+use crate::error::{OsError, ServiceError};
+use crate::hal::ThreadOptions;
+use crate::health::{self, Health};
+
+const LOG_TGT: &str = "inu.svc";
+
+/// The body of a service, run until it returns. It is called again to restart the service.
+pub type ServiceFn = Box<dyn FnMut() -> Result<(), OsError> + Send>;
+
+/// What to do when a service exits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RestartPolicy {
+    /// Leave the service stopped.
+    Never,
+    /// Restart the service after the back-off if it failed by returning an error, up to `max_restarts` times.
+    OnFailure {
+        max_restarts: Option<u32>,
+        backoff: Duration,
+    },
+    /// Restart the service after the back-off whenever it exits.
+    Always { backoff: Duration },
+}
+
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        RestartPolicy::OnFailure {
+            max_restarts: None,
+            backoff: Duration::from_secs(1),
+        }
+    }
+}
+
+impl RestartPolicy {
+    /// The back-off before restarting a service that has exited, or `None` to leave it stopped.
+    fn restart(&self, failed: bool, restarts: u32) -> Option<Duration> {
+        match *self {
+            RestartPolicy::Never => None,
+            RestartPolicy::OnFailure {
+                max_restarts,
+                backoff,
+            } => (failed && max_restarts.map_or(true, |max| restarts < max)).then_some(backoff),
+            RestartPolicy::Always { backoff } => Some(backoff),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ServiceState {
+    /// Registered, waiting to be started.
+    Pending,
+    Running,
+    /// Exited, waiting for the back-off before restarting.
+    Restarting,
+    /// Exited & won't be restarted.
+    Stopped,
+    /// Failed & won't be restarted.
+    Failed,
+}
+
+/// Declaration of a service.
+#[derive(Debug, Clone)]
+pub struct ServiceSpec {
+    pub name: String,
+    pub options: ThreadOptions,
+    /// Services that must be started before this one.
+    pub depends_on: Vec<String>,
+    pub restart: RestartPolicy,
+}
+
+impl ServiceSpec {
+    pub fn new(name: &str, options: ThreadOptions) -> Self {
+        ServiceSpec {
+            name: name.to_string(),
+            options,
+            depends_on: vec![],
+            restart: RestartPolicy::default(),
+        }
+    }
+
+    pub fn with_dependency(mut self, name: &str) -> Self {
+        self.depends_on.push(name.to_string());
+        self
+    }
+
+    pub fn with_restart(mut self, policy: RestartPolicy) -> Self {
+        self.restart = policy;
+        self
+    }
+}
+
+/// Status of a registered service.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ServiceStatus {
+    pub name: String,
+    pub state: ServiceState,
+    pub restarts: u32,
+    /// Reason for the most recent failure.
+    pub last_error: Option<String>,
+}
+
+struct Entry {
+    spec: ServiceSpec,
+    status: ServiceStatus,
+    /// Taken when the service is started.
+    run: Option<ServiceFn>,
+}
+
+/// Registry of services. Clones share the registry.
+#[derive(Clone)]
+pub struct Supervisor {
+    health: Arc<Health>,
+    services: Arc<Mutex<Vec<Entry>>>,
+}
+
+impl Supervisor {
+    /// A supervisor spawning services through the given registry, so that their health is tracked.
+    pub fn new(health: Arc<Health>) -> Self {
+        Supervisor {
+            health,
+            services: Arc::new(Mutex::new(vec![])),
+        }
+    }
+
+    /// Register a service, to be started by `start`.
+    pub fn register(&self, spec: ServiceSpec, run: ServiceFn) -> Result<(), OsError> {
+        let mut services = self.services.lock().unwrap();
+        if services.iter().any(|e| e.spec.name == spec.name) {
+            return Err(ServiceError::Duplicate(spec.name).into());
+        }
+
+        services.push(Entry {
+            status: ServiceStatus {
+                name: spec.name.clone(),
+                state: ServiceState::Pending,
+                restarts: 0,
+                last_error: None,
+            },
+            spec,
+            run: Some(run),
+        });
+        Ok(())
+    }
+
+    /// Start every pending service, each after the services it depends on.
+    ///
+    /// Nothing is started if a dependency is unknown or circular.
+    pub fn start(&self) -> Result<(), OsError> {
+        let order = start_order(&self.services.lock().unwrap())?;
+
+        for name in order {
+            self.launch(&name)?;
+        }
+        Ok(())
+    }
+
+    /// Status of every service, in the order registered.
+    pub fn status(&self) -> Vec<ServiceStatus> {
+        self.services
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|e| e.status.clone())
+            .collect()
+    }
+
+    /// Status of the named service.
+    pub fn service(&self, name: &str) -> Option<ServiceStatus> {
+        self.services
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|e| e.spec.name == name)
+            .map(|e| e.status.clone())
+    }
+
+    fn launch(&self, name: &str) -> Result<(), OsError> {
+        let (spec, run) = {
+            let mut services = self.services.lock().unwrap();
+            let entry = find(&mut services, name);
+            let run = entry.run.take().expect("pending service has a body");
+            entry.status.state = ServiceState::Running;
+            (entry.spec.clone(), run)
+        };
+
+        let services = self.services.clone();
+        let result = self.health.spawn(
+            name,
+            spec.options,
+            Box::new(move || supervise(services, spec, run)),
+        );
+
+        match result {
+            Ok(_) => {
+                log::info!(target: LOG_TGT, "Started service '{}'", name);
+                Ok(())
+            }
+            Err(e) => {
+                let mut services = self.services.lock().unwrap();
+                let status = &mut find(&mut services, name).status;
+                status.state = ServiceState::Failed;
+                status.last_error = Some(crash::describe(&e));
+                Err(e)
+            }
+        }
+    }
+}
+
+fn find<'a>(services: &'a mut [Entry], name: &str) -> &'a mut Entry {
+    services
+        .iter_mut()
+        .find(|e| e.spec.name == name)
+        .expect("service is registered")
+}
+
+/// Names of the pending services, ordered so that each follows its dependencies.
+fn start_order(services: &[Entry]) -> Result<Vec<String>, ServiceError> {
+    fn visit<'a>(
+        services: &'a [Entry],
+        entry: &'a Entry,
+        path: &mut Vec<&'a str>,
+        order: &mut Vec<String>,
+    ) -> Result<(), ServiceError> {
+        let name = entry.spec.name.as_str();
+        if entry.status.state != ServiceState::Pending || order.iter().any(|n| n == name) {
+            return Ok(());
+        }
+        if path.contains(&name) {
+            return Err(ServiceError::DependencyCycle(name.to_string()));
+        }
+
+        path.push(name);
+        for dependency in &entry.spec.depends_on {
+            let dep = services
+                .iter()
+                .find(|e| &e.spec.name == dependency)
+                .ok_or_else(|| ServiceError::UnknownDependency {
+                    service: name.to_string(),
+                    dependency: dependency.clone(),
+                })?;
+            visit(services, dep, path, order)?;
+        }
+        path.pop();
+
+        order.push(name.to_string());
+        Ok(())
+    }
+
+    let mut order = vec![];
+    for entry in services {
+        visit(services, entry, &mut vec![], &mut order)?;
+    }
+    Ok(order)
+}
+
+/// Run a service on the current thread, restarting it according to its policy until it stops.
+fn supervise(services: Arc<Mutex<Vec<Entry>>>, spec: ServiceSpec, mut run: ServiceFn) {
+    loop {
+        let error = run().err().map(|e| crash::describe(&e));
+
+        let backoff = {
+            let mut services = services.lock().unwrap();
+            let status = &mut find(&mut services, &spec.name).status;
+            let backoff = spec.restart.restart(error.is_some(), status.restarts);
+
+            status.state = match (&backoff, &error) {
+                (Some(_), _) => ServiceState::Restarting,
+                (None, Some(_)) => ServiceState::Failed,
+                (None, None) => ServiceState::Stopped,
+            };
+            if error.is_some() {
+                status.last_error = error.clone();
+            }
+            backoff
+        };
+
+        match (&error, backoff) {
+            (Some(e), _) => log::error!(target: LOG_TGT, "Service '{}' failed: {}", spec.name, e),
+            (None, _) => log::info!(target: LOG_TGT, "Service '{}' exited", spec.name),
+        }
+
+        let Some(backoff) = backoff else {
+            return;
+        };
+        health::sleep(backoff);
+
+        let mut services = services.lock().unwrap();
+        let status = &mut find(&mut services, &spec.name).status;
+        status.state = ServiceState::Running;
+        status.restarts += 1;
+        log::info!(target: LOG_TGT, "Restarting service '{}' ({})", spec.name, status.restarts);
+    }
+}
+
+#[cfg(all(test, feature = "host"))]
+mod tests {
+    use super::*;
+    use crate::error::SettingsError;
+    use crate::hal::host::HostPlatform;
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::thread;
+    use std::time::Instant;
+
+    const OPTIONS: ThreadOptions = ThreadOptions {
+        priority: 5,
+        core: None,
+        stack_size: 4096,
+    };
+
+    fn supervisor() -> Supervisor {
+        Supervisor::new(Health::new(Arc::new(HostPlatform::new())))
+    }
+
+    fn wait_for(supervisor: &Supervisor, name: &str, state: ServiceState) -> ServiceStatus {
+        let start = Instant::now();
+        loop {
+            let status = supervisor.service(name).unwrap();
+            if status.state == state || start.elapsed() > Duration::from_secs(5) {
+                return status;
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    fn idle() -> ServiceFn {
+        Box::new(|| Ok(()))
+    }
+
+    #[test]
+    fn services_start_after_their_dependencies() {
+        let svc = supervisor();
+        svc.register(
+            ServiceSpec::new("log", OPTIONS).with_dependency("inu"),
+            idle(),
+        )
+        .unwrap();
+        svc.register(
+            ServiceSpec::new("inu", OPTIONS).with_dependency("net"),
+            idle(),
+        )
+        .unwrap();
+        svc.register(ServiceSpec::new("net", OPTIONS), idle())
+            .unwrap();
+
+        let order = start_order(&svc.services.lock().unwrap()).unwrap();
+        assert_eq!(order, ["net", "inu", "log"]);
+
+        assert!(matches!(
+            svc.register(ServiceSpec::new("net", OPTIONS), idle()),
+            Err(OsError::Service(ServiceError::Duplicate(_)))
+        ));
+
+        let bad = supervisor();
+        bad.register(ServiceSpec::new("a", OPTIONS).with_dependency("b"), idle())
+            .unwrap();
+        bad.register(ServiceSpec::new("b", OPTIONS).with_dependency("a"), idle())
+            .unwrap();
+        assert!(matches!(
+            bad.start(),
+            Err(OsError::Service(ServiceError::DependencyCycle(_)))
+        ));
+        assert_eq!(bad.service("a").unwrap().state, ServiceState::Pending);
+
+        let missing = supervisor();
+        missing
+            .register(ServiceSpec::new("c", OPTIONS).with_dependency("d"), idle())
+            .unwrap();
+        assert!(matches!(
+            missing.start(),
+            Err(OsError::Service(ServiceError::UnknownDependency { .. }))
+        ));
+    }
+
+    #[test]
+    fn failed_services_are_restarted_per_policy() {
+        let svc = supervisor();
+        let runs = Arc::new(AtomicU32::new(0));
+
+        let count = runs.clone();
+        let flaky: ServiceFn = Box::new(move || match count.fetch_add(1, Ordering::SeqCst) {
+            0 => Err(OsError::Generic("no route".into())),
+            1 => Err(OsError::Generic("timed out".into())),
+            _ => Ok(()),
+        });
+        let policy = RestartPolicy::OnFailure {
+            max_restarts: Some(5),
+            backoff: Duration::from_millis(1),
+        };
+        svc.register(
+            ServiceSpec::new("flaky", OPTIONS).with_restart(policy),
+            flaky,
+        )
+        .unwrap();
+
+        let fatal: ServiceFn = Box::new(|| Err(SettingsError::Missing("wifi_ap").into()));
+        svc.register(
+            ServiceSpec::new("fatal", OPTIONS).with_restart(RestartPolicy::Never),
+            fatal,
+        )
+        .unwrap();
+        svc.start().unwrap();
+
+        let flaky = wait_for(&svc, "flaky", ServiceState::Stopped);
+        assert_eq!(flaky.state, ServiceState::Stopped);
+        assert_eq!(flaky.restarts, 2);
+        assert_eq!(flaky.last_error.as_deref(), Some("timed out"));
+        assert_eq!(runs.load(Ordering::SeqCst), 3);
+
+        let fatal = wait_for(&svc, "fatal", ServiceState::Failed);
+        assert_eq!(fatal.state, ServiceState::Failed);
+        assert_eq!(fatal.restarts, 0);
+        // The error is recorded with its source
+        assert_eq!(
+            fatal.last_error.as_deref(),
+            Some("settings error: setting 'wifi_ap' has not been provisioned")
+        );
+    }
+}
@@ -1,8 +1,10 @@
-use esp_idf_svc::ipv4::IpInfo;
-use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
+use crate::hal::wifi::IpInfo;
+
+/// Progress of the WiFi station link.
 #[derive(Debug, Default, Clone, Copy, PartialEq)]
-pub enum WifiState {
+pub enum LinkState {
     #[default]
     Disconnected,
     Connecting,
@@ -10,4 +12,32 @@ pub enum WifiState {
     Connected(IpInfo),
 }
 
-pub type OnlineSemaphore = Arc<Mutex<WifiState>>;
+/// Why the WiFi link last went down.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// Reason code reported by the driver, see `WifiEvent::Disconnected`.
+    Link(u16),
+    /// None of the known networks could be joined.
+    JoinFailed,
+    /// Joined a network, but no IP address was assigned.
+    NoIp,
+}
+
+/// WiFi station status.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct WifiState {
+    pub link: LinkState,
+    /// Signal strength of the access point in dBm, while connected.
+    pub rssi: Option<i8>,
+    /// How long the current connection has been up.
+    pub uptime: Duration,
+    pub last_disconnect: Option<DisconnectReason>,
+    /// Number of times the connection has been re-established since boot.
+    pub reconnects: u32,
+}
+
+impl WifiState {
+    pub fn is_connected(&self) -> bool {
+        matches!(self.link, LinkState::Connected(_))
+    }
+}
@@ -1,11 +1,10 @@
-use std::time::Duration;
-
 use embedded_svc::http::Headers;
 use embedded_svc::{http::client::Client as HttpClient, utils::io};
-use esp_idf_svc::hal::gpio::Pull;
 
 use inu_hardware::switch::{DelayOptions, InuSwitch};
+use inu_os::hal::gpio::Pull;
 use inu_os::kernel::Kernel;
+use inu_os::logging;
 
 mod release;
 
@@ -13,71 +12,96 @@ const LOG_TGT: &str = "inu";
 
 fn main() {
     esp_idf_svc::sys::link_patches();
-    esp_idf_svc::log::EspLogger::initialize_default();
+    logging::init(Box::new(logging::Console::new())).unwrap();
 
     let kernel = unsafe { Kernel::new() };
     kernel.log_info(release::EDITION, release::BUILD);
 
+    let inu = kernel
+        .start_inu(release::EDITION, release::BUILD)
+        .unwrap_or_else(|e| kernel.fatal(e));
+
+    if let Err(e) = kernel.start_remote_log() {
+        log::error!(target: LOG_TGT, "Failed to start remote logging: {}", e);
+    }
+
+    // Sample handler for triggers published by other devices
+    inu.on_trigger(kernel.get_settings().trigger_code, |t| {
+        log::info!(target: LOG_TGT, "Trigger {} from '{}'", t.code, t.device_id);
+    });
+
     // Sample code for GPIO input
     let input9 = kernel.pin_mgr.get_input(9, Pull::Down).unwrap();
-    let sw9 = InuSwitch::new(input9)
-        .with_callback(|state| {
-            log::info!("Switch 9 state: {:?}", state);
+    InuSwitch::new(input9)
+        .with_callback(|e| {
+            log::info!("Switch {} state: {:?}, after {:?}", e.id, e.level, e.held);
         })
-        .with_delay(DelayOptions::tnx_ms(10));
+        .with_trigger(inu.clone(), kernel.get_settings().trigger_code)
+        .with_delay(DelayOptions::tnx_ms(10))
+        .with_clock(kernel.platform().clock())
+        .listen(&kernel)
+        .unwrap_or_else(|e| kernel.fatal(e));
 
-    // Main loop
-    let mut test = false;
     log::info!(target: LOG_TGT, "-- {} online --", kernel.get_settings().device_id);
-    loop {
-        std::thread::sleep(Duration::from_millis(10));
-        sw9.poll();
-
-        if kernel.is_online() && !test {
-            log::info!(target: LOG_TGT, "Running test connection..");
-            test = true;
-
-            use esp_idf_svc::http::client::{
-                Configuration as HttpConfiguration, EspHttpConnection,
-            };
-
-            let config = &HttpConfiguration {
-                crt_bundle_attach: Some(esp_idf_svc::sys::esp_crt_bundle_attach),
-                ..Default::default()
-            };
-
-            let mut client = HttpClient::wrap(EspHttpConnection::new(config).unwrap());
-            let mut r = client
-                .get("https://example.com/")
-                .unwrap()
-                .submit()
-                .unwrap();
-            log::info!("HTTP response: {}", r.status());
-
-            if let Some(ct) = r.content_type() {
-                log::info!("Content-Type: {}", ct);
-            }
-
-            if let Some(cl) = r.content_len() {
-                log::info!("Content-Length: {}", cl);
-            }
-
-            let mut buf = [0u8; 1024];
-            let bytes_read = io::try_read_full(&mut r, &mut buf)
-                .map_err(|e| e.0)
-                .unwrap();
-            log::info!("Read {} bytes", bytes_read);
-            match std::str::from_utf8(&buf[0..bytes_read]) {
-                Ok(body_string) => log::info!(
-                    "Response body (truncated to {} bytes): {:?}",
-                    buf.len(),
-                    body_string
-                ),
-                Err(e) => log::error!("Error decoding response body: {}", e),
-            };
-
-            // Drain the remaining response bytes
-            while r.read(&mut buf).unwrap() > 0 {}
+
+    // Run a test connection now if online, then each time the device comes online. The link may have come up after
+    // subscribing, so an event for a link already seen online is skipped rather than testing it twice.
+    let connectivity = kernel.subscribe_connectivity();
+    let mut online = kernel.is_online();
+    if online {
+        test_connection();
+    }
+
+    // Main loop, blocked until the link changes
+    for event in connectivity.iter() {
+        if event.is_online() && !online {
+            test_connection();
         }
+        online = event.state.is_connected();
+    }
+}
+
+/// Fetch a page over HTTPS, logging the response.
+fn test_connection() {
+    log::info!(target: LOG_TGT, "Running test connection..");
+
+    use esp_idf_svc::http::client::{Configuration as HttpConfiguration, EspHttpConnection};
+
+    let config = &HttpConfiguration {
+        crt_bundle_attach: Some(esp_idf_svc::sys::esp_crt_bundle_attach),
+        ..Default::default()
+    };
+
+    let mut client = HttpClient::wrap(EspHttpConnection::new(config).unwrap());
+    let mut r = client
+        .get("https://example.com/")
+        .unwrap()
+        .submit()
+        .unwrap();
+    log::info!("HTTP response: {}", r.status());
+
+    if let Some(ct) = r.content_type() {
+        log::info!("Content-Type: {}", ct);
+    }
+
+    if let Some(cl) = r.content_len() {
+        log::info!("Content-Length: {}", cl);
     }
+
+    let mut buf = [0u8; 1024];
+    let bytes_read = io::try_read_full(&mut r, &mut buf)
+        .map_err(|e| e.0)
+        .unwrap();
+    log::info!("Read {} bytes", bytes_read);
+    match std::str::from_utf8(&buf[0..bytes_read]) {
+        Ok(body_string) => log::info!(
+            "Response body (truncated to {} bytes): {:?}",
+            buf.len(),
+            body_string
+        ),
+        Err(e) => log::error!("Error decoding response body: {}", e),
+    };
+
+    // Drain the remaining response bytes
+    while r.read(&mut buf).unwrap() > 0 {}
 }
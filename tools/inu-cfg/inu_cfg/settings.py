@@ -1,14 +1,28 @@
+import hashlib
+import struct
+
 from .validator import Validator
 
+# Must match `settings::schema::SCHEMA_VERSION` in the firmware
+SCHEMA_VERSION = 2
+
 CSV_DATA = """key,type,encoding,value
 settings,namespace,,
-clock,data,u16,{}
-device_id,data,string,"{}"
-wifi_ap,data,string,"{}"
-wifi_pw,data,string,"{}"
+schema_ver,data,u16,{}
+clock,data,hex2bin,{}
+device_id,data,hex2bin,{}
+wifi_ap,data,hex2bin,{}
+wifi_pw,data,hex2bin,{}
 """
 
 
+def frame(payload: bytes) -> str:
+    """
+    Prefix a value with the integrity header the firmware expects (u32 length & MD5 digest), hex encoded.
+    """
+    return (struct.pack("<I", len(payload)) + hashlib.md5(payload).digest() + payload).hex()
+
+
 class Settings:
     def __init__(self, clk, dvc_id, ssid, pw):
         self.clock = clk
@@ -23,9 +37,10 @@ class Settings:
     def write(self, filename):
         with open(filename, 'w') as file:
             file.write(CSV_DATA.format(
-                self.clock,
-                self.device_id,
-                self.ssid,
-                self.password
+                SCHEMA_VERSION,
+                frame(struct.pack("<H", self.clock)),
+                frame(self.device_id.encode()),
+                frame(self.ssid.encode()),
+                frame(self.password.encode())
             ))
         print("Table data writen to {}".format(filename))